@@ -1,4 +1,4 @@
-use super::{Geometry, HitResult};
+use super::{Geometry, HitResult, RayPacket, PACKET_SIZE};
 use crate::material::Material;
 use crate::math::*;
 use delegate::*;
@@ -21,7 +21,7 @@ impl<T: Geometry> GeometryList<T> {
 }
 
 impl<T: Geometry> Geometry for GeometryList<T> {
-    fn hit(&self, ray: &Ray, mut t_max: f32) -> Option<HitResult> {
+    fn hit(&self, ray: &Ray, mut t_max: f32) -> Option<HitResult<'_>> {
         let mut result = None;
 
         for geom in &self.0 {
@@ -44,6 +44,45 @@ impl<T: Geometry> Geometry for GeometryList<T> {
         false
     }
 
+    fn hit_packet(
+        &self,
+        rays: &RayPacket,
+        mut t_max: [f32; PACKET_SIZE],
+    ) -> [Option<HitResult<'_>>; PACKET_SIZE] {
+        let mut result: [Option<HitResult>; PACKET_SIZE] = Default::default();
+
+        for geom in &self.0 {
+            for (i, hit) in geom.hit_packet(rays, t_max).iter_mut().enumerate() {
+                if let Some(h) = hit.take() {
+                    t_max[i] = h.t;
+                    result[i] = Some(h);
+                }
+            }
+        }
+
+        result
+    }
+
+    fn is_hit_packet(
+        &self,
+        rays: &RayPacket,
+        mut t_max: [f32; PACKET_SIZE],
+    ) -> [bool; PACKET_SIZE] {
+        let mut result = [false; PACKET_SIZE];
+
+        for geom in &self.0 {
+            for (i, &hit) in geom.is_hit_packet(rays, t_max).iter().enumerate() {
+                if hit {
+                    // Rays that are already blocked need not be traced any further.
+                    t_max[i] = -1.0;
+                    result[i] = true;
+                }
+            }
+        }
+
+        result
+    }
+
     fn bounding_box(&self) -> AABB {
         let mut bbox = AABB::new();
 
@@ -70,7 +109,7 @@ impl<T: Geometry> BoundingBox<T> {
 }
 
 impl<T: Geometry> Geometry for BoundingBox<T> {
-    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult> {
+    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>> {
         if let Some((t_in, t_out)) = self.1.intersect_ray(ray) {
             if t_in <= t_max && t_out >= 0.0 {
                 return self.0.hit(ray, t_max);
@@ -101,7 +140,7 @@ struct ObjectImpl<G, M> {
 }
 
 impl<G: Geometry, M: Material> Geometry for ObjectImpl<G, M> {
-    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult> {
+    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>> {
         if let Some(mut h) = self.geometry.hit(ray, t_max) {
             h.material = &self.material;
             Some(h)
@@ -110,10 +149,25 @@ impl<G: Geometry, M: Material> Geometry for ObjectImpl<G, M> {
         }
     }
 
+    fn hit_packet(
+        &self,
+        rays: &RayPacket,
+        t_max: [f32; PACKET_SIZE],
+    ) -> [Option<HitResult<'_>>; PACKET_SIZE] {
+        let mut hits = self.geometry.hit_packet(rays, t_max);
+
+        for h in hits.iter_mut().flatten() {
+            h.material = &self.material;
+        }
+
+        hits
+    }
+
     delegate! {
         target self.geometry {
             fn bounding_box(&self) -> AABB;
             fn is_hit(&self, ray: &Ray, t_max: f32) -> bool;
+            fn is_hit_packet(&self, rays: &RayPacket, t_max: [f32; PACKET_SIZE]) -> [bool; PACKET_SIZE];
         }
     }
 }
@@ -143,6 +197,8 @@ impl Geometry for Object {
             fn bounding_box(&self) -> AABB;
             fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>>;
             fn is_hit(&self, ray: &Ray, t_max: f32) -> bool;
+            fn hit_packet(&self, rays: &RayPacket, t_max: [f32; PACKET_SIZE]) -> [Option<HitResult<'_>>; PACKET_SIZE];
+            fn is_hit_packet(&self, rays: &RayPacket, t_max: [f32; PACKET_SIZE]) -> [bool; PACKET_SIZE];
         }
     }
 }
@@ -1,208 +1,914 @@
-use crate::geom::{Geometry, HitResult};
+use super::storage::Storage;
+use super::wide::{self, WideNode, WidePacket, WideRay, EMPTY, WIDTH};
+use crate::geom::{Geometry, HitResult, RayPacket, PACKET_SIZE};
 use crate::math::*;
+use rayon::prelude::*;
 use std::f32;
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 pub struct AABBTree<T> {
-    objs: Box<[T]>,
-    nodes: Box<[(AABB, u32, u32)]>,
+    objs: Storage<T>,
+    nodes: Storage<WideNode>,
+    bbox: AABB,
+    stats: BuildStats,
+    order: Storage<u32>,
+    hit_cost: f32,
+    build_cost: f32,
 }
 
-pub fn partition_sah<T>(bbox: &mut [AABB], objs: &mut [T]) -> Option<usize>
+// Nodes and object order of a tree, kept from one frame of an animation to refit the tree to the
+// objects of the next frame.
+pub struct TreeLayout {
+    nodes: Storage<WideNode>,
+    bbox: AABB,
+    stats: BuildStats,
+    order: Storage<u32>,
+    hit_cost: f32,
+    build_cost: f32,
+}
+
+impl TreeLayout {
+    pub fn stats(&self) -> &BuildStats {
+        &self.stats
+    }
+
+    // Tree for new objects with this structure, for example the same objects at the next frame of
+    // an animation. The objects must correspond one-to-one to the objects the tree was built from,
+    // which rules out trees with spatial splits.
+    pub fn refit_with<T: Geometry>(&self, objs: Vec<T>) -> AABBTree<T> {
+        assert_eq!(objs.len(), self.stats.objects, "invalid number of objects");
+        assert_eq!(self.order.len(), objs.len(), "tree has spatial splits");
+
+        let mut tree = AABBTree {
+            objs: permute(objs, &self.order).into(),
+            nodes: self.nodes.clone(),
+            bbox: self.bbox,
+            stats: self.stats.clone(),
+            order: self.order.clone(),
+            hit_cost: self.hit_cost,
+            build_cost: self.build_cost,
+        };
+
+        tree.refit();
+        tree
+    }
+}
+
+// Geometry that can be bounded after clipping it to a slab along one axis, which is what
+// spatial splits need to divide a single object over both children.
+pub trait Clip: Geometry + Clone {
+    fn clip_bounds(&self, axis: usize, min: f32, max: f32) -> AABB;
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BuildStats {
+    pub objects: usize,
+    pub references: usize,
+    pub nodes: usize,
+    pub leaves: usize,
+    pub min_leaf: usize,
+    pub max_leaf: usize,
+    pub depth: usize,
+    pub spatial_splits: usize,
+    pub sah_cost: f32,
+    pub build_time: Duration,
+}
+
+impl fmt::Display for BuildStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} objs, {} references, {} nodes, {} leafs, min/max/avg leaf: {}/{}/{:.2}, \
+             depth {}, {} spatial splits, SAH cost {:.2}, built in {:.3} sec",
+            self.objects,
+            self.references,
+            self.nodes,
+            self.leaves,
+            self.min_leaf,
+            self.max_leaf,
+            self.references as f32 / self.leaves.max(1) as f32,
+            self.depth,
+            self.spatial_splits,
+            self.sah_cost,
+            self.build_time.as_secs_f64()
+        )
+    }
+}
+
+// Limits the depth of the binary hierarchy, which also bounds the size of the traversal stack.
+const MAX_DEPTH: usize = 64;
+const STACK_SIZE: usize = (WIDTH - 1) * MAX_DEPTH + 1;
+
+const NUM_BINS: usize = 16;
+
+// Nodes with more references than this are built in parallel.
+const PARALLEL_THRESHOLD: usize = 4096;
+
+// Spatial splits are only considered if the children of the best object split overlap by more
+// than this fraction of the surface area of the root.
+const MIN_OVERLAP: f32 = 1e-5;
+
+// Spatial splits may add at most this many extra references per object, which bounds the memory
+// use and build time for meshes with many long and thin triangles.
+const MAX_EXTRA_REFERENCES: f32 = 0.3;
+
+// Bounding box of (part of) an object during construction, spatial splits can divide one object
+// over several references.
+#[derive(Debug, Copy, Clone)]
+struct Reference {
+    bbox: AABB,
+    index: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Bin {
+    bbox: AABB,
+    enter: usize,
+    exit: usize,
+}
+
+type Bins = [[Bin; NUM_BINS]; 3];
+
+fn empty_bins() -> Bins {
+    let bin = Bin {
+        bbox: AABB::new(),
+        enter: 0,
+        exit: 0,
+    };
+
+    [[bin; NUM_BINS]; 3]
+}
+
+fn merge_bins(mut a: Bins, b: Bins) -> Bins {
+    for axis in 0..3 {
+        for (x, y) in a[axis].iter_mut().zip(&b[axis]) {
+            x.bbox = x.bbox.union(y.bbox);
+            x.enter += y.enter;
+            x.exit += y.exit;
+        }
+    }
+
+    a
+}
+
+// Folds the references into bins, in parallel if there are many references.
+fn bin_references<F>(refs: &[Reference], fun: F) -> Bins
 where
-    T: Geometry,
+    F: Fn(&mut Bins, &Reference) + Sync,
 {
-    let surface = |bbox: AABB| {
-        let delta = bbox.max - bbox.min;
-        delta[0] * delta[1] + delta[1] * delta[2] + delta[2] * delta[0]
+    let bin_chunk = |chunk: &[Reference]| {
+        let mut bins = empty_bins();
+        for r in chunk {
+            fun(&mut bins, r);
+        }
+        bins
     };
 
-    let mut centers = bbox
-        .iter()
-        .map(|b| (b.min + b.max) * 0.5)
-        .collect::<Vec<_>>();
+    if refs.len() > PARALLEL_THRESHOLD {
+        refs.par_chunks(PARALLEL_THRESHOLD)
+            .map(bin_chunk)
+            .reduce(empty_bins, merge_bins)
+    } else {
+        bin_chunk(refs)
+    }
+}
 
-    let mut best = (f32::INFINITY, 0, 0.0);
+// Sweeps over the bins and returns the cheapest split along this axis as (cost, index), the cost
+// is the surface area of each side multiplied by its number of references.
+fn sweep_bins(bins: &[Bin; NUM_BINS]) -> Option<(f32, usize)> {
+    let mut right_area = [0.0; NUM_BINS];
+    let mut bbox = AABB::new();
+    let mut count = 0;
 
-    for axis in 0..3 {
-        let (mut min, mut max) = (f32::INFINITY, -f32::INFINITY);
+    for i in (1..NUM_BINS).rev() {
+        bbox = bbox.union(bins[i].bbox);
+        count += bins[i].exit;
+        right_area[i] = iff!(count > 0, bbox.surface_area() * count as f32, f32::NAN);
+    }
+
+    let mut best = None;
+    let mut bbox = AABB::new();
+    let mut count = 0;
 
-        for c in &centers {
-            min = min!(min, c[axis]);
-            max = max!(max, c[axis]);
+    for i in 1..NUM_BINS {
+        bbox = bbox.union(bins[i - 1].bbox);
+        count += bins[i - 1].enter;
+
+        // Splits that leave one side empty are marked with NaN and never chosen.
+        let cost = bbox.surface_area() * count as f32 + right_area[i];
+        if count > 0 && best.is_none_or(|(c, _)| cost < c) {
+            best = Some((cost, i));
         }
+    }
 
-        const NUM_BUCKETS: usize = 16;
-        let mut bucket_sizes = [0; NUM_BUCKETS];
-        let mut bucket_bbox = [AABB::new(); NUM_BUCKETS];
+    best
+}
 
-        for (c, b) in centers.iter().zip(bbox.iter()) {
-            let ratio = (c[axis] - min) / (max - min);
-            let index = ((ratio * NUM_BUCKETS as f32).floor() as usize).min(NUM_BUCKETS - 1);
+enum Split {
+    Object {
+        axis: usize,
+        index: usize,
+        min: f32,
+        scale: f32,
+    },
+    Spatial {
+        axis: usize,
+        plane: f32,
+    },
+}
+
+// Output of the construction in the skip-pointer layout: every node is (bounding box, index
+// of the next node after its subtree, index of its first reference).
+#[derive(Default)]
+struct Output {
+    nodes: Vec<(AABB, u32, u32)>,
+    order: Vec<u32>,
+}
+
+impl Output {
+    fn append(&mut self, other: Output) {
+        let (n, m) = (self.nodes.len() as u32, self.order.len() as u32);
+        self.nodes
+            .extend(other.nodes.into_iter().map(|(b, i, j)| (b, i + n, j + m)));
+        self.order.extend(other.order);
+    }
+}
 
-            bucket_sizes[index] += 1;
-            bucket_bbox[index] = AABB::union(bucket_bbox[index], *b);
+// Clips an object to the slab between two planes along an axis.
+type ClipFn<T> = fn(&T, usize, f32, f32) -> AABB;
+
+// Top-down binned SAH construction. Large nodes are binned in parallel and both of their
+// subtrees are built in parallel.
+struct Builder<'a, T> {
+    objs: &'a [T],
+    hit_cost: f32,
+    clip: Option<ClipFn<T>>,
+    root_area: f32,
+    spatial_splits: AtomicUsize,
+}
+
+impl<'a, T: Geometry> Builder<'a, T> {
+    fn new(objs: &'a [T], hit_cost: f32, clip: Option<ClipFn<T>>) -> Self {
+        Self {
+            objs,
+            hit_cost,
+            clip,
+            root_area: 0.0,
+            spatial_splits: AtomicUsize::new(0),
         }
+    }
 
-        for i in 1..NUM_BUCKETS {
-            let lsize: usize = bucket_sizes[..i].iter().sum();
-            let rsize: usize = bucket_sizes[i..].iter().sum();
+    // Returns the hierarchy, its depth and the number of spatial splits.
+    fn run(mut self) -> (Output, usize, usize) {
+        let refs = self
+            .objs
+            .par_iter()
+            .enumerate()
+            .map(|(index, obj)| Reference {
+                bbox: obj.bounding_box(),
+                index: index as u32,
+            })
+            .collect::<Vec<_>>();
 
-            if lsize == 0 || rsize == 0 {
-                continue;
+        self.root_area = Self::bounds(&refs).0.surface_area();
+        let budget = (refs.len() as f32 * MAX_EXTRA_REFERENCES) as usize;
+
+        let mut out = Output::default();
+        let depth = self.build(refs, 0, budget, &mut out);
+
+        let m = out.nodes.len();
+        out.nodes
+            .push((AABB::new(), m as u32, out.order.len() as u32));
+
+        (out, depth, self.spatial_splits.into_inner())
+    }
+
+    // Bounds of the references and of their centers.
+    fn bounds(refs: &[Reference]) -> (AABB, AABB) {
+        let identity = || (AABB::new(), AABB::new());
+        let add =
+            |(a, b): (AABB, AABB), r: &Reference| (a.union(r.bbox), b.union_point(r.bbox.center()));
+        let merge = |(a, b): (AABB, AABB), (c, d): (AABB, AABB)| (a.union(c), b.union(d));
+
+        if refs.len() > PARALLEL_THRESHOLD {
+            refs.par_chunks(PARALLEL_THRESHOLD)
+                .map(|chunk| chunk.iter().fold(identity(), add))
+                .reduce(identity, merge)
+        } else {
+            refs.iter().fold(identity(), add)
+        }
+    }
+
+    // Builds the subtree for these references into `out`, returns the depth of its deepest leaf.
+    // Spatial splits within the subtree may add at most `budget` references.
+    fn build(&self, refs: Vec<Reference>, depth: usize, budget: usize, out: &mut Output) -> usize {
+        let (bbox, centers) = Self::bounds(&refs);
+        let index = out.nodes.len();
+        let begin = out.order.len() as u32;
+        out.nodes.push((bbox, index as u32 + 1, begin));
+
+        let split = if depth < MAX_DEPTH && refs.len() > 1 {
+            self.find_split(&refs, bbox, centers, budget)
+        } else {
+            None
+        };
+
+        let (left, right) = match split.and_then(|s| self.partition(&refs, s)) {
+            Some(halves) => halves,
+            None => {
+                out.order.extend(refs.iter().map(|r| r.index));
+                return depth;
             }
+        };
+
+        // The rest of the budget is divided between the children by their number of references,
+        // which keeps the result independent of the order in which the subtrees are built.
+        let total = left.len() + right.len();
+        let remaining = budget.saturating_sub(total.saturating_sub(refs.len()));
+        let left_budget = remaining * left.len() / total;
+        let right_budget = remaining - left_budget;
+
+        drop(refs);
+        let max_depth = if total > PARALLEL_THRESHOLD {
+            let mut other = Output::default();
+            let (a, b) = rayon::join(
+                || self.build(left, depth + 1, left_budget, out),
+                || self.build(right, depth + 1, right_budget, &mut other),
+            );
+
+            out.append(other);
+            max!(a, b)
+        } else {
+            let a = self.build(left, depth + 1, left_budget, out);
+            let b = self.build(right, depth + 1, right_budget, out);
+            max!(a, b)
+        };
+
+        out.nodes[index].1 = out.nodes.len() as u32;
+        max_depth
+    }
+
+    fn find_split(
+        &self,
+        refs: &[Reference],
+        bbox: AABB,
+        centers: AABB,
+        budget: usize,
+    ) -> Option<Split> {
+        let mut best = None;
+        let mut best_cost = f32::INFINITY;
 
-            let mut lbox = AABB::new();
-            let mut rbox = AABB::new();
+        // Object split: the references are binned by their centers.
+        let extent = centers.max - centers.min;
+        let scale = Vec3D::from_map(|i| NUM_BINS as f32 / extent[i]);
+        let bin = |r: &Reference, axis: usize| {
+            let x = (r.bbox.center()[axis] - centers.min[axis]) * scale[axis];
+            (x as usize).min(NUM_BINS - 1)
+        };
 
-            for &b in &bucket_bbox[..i] {
-                lbox = lbox.union(b);
+        let bins = bin_references(refs, |bins, r| {
+            for axis in 0..3 {
+                let b = &mut bins[axis][bin(r, axis)];
+                b.bbox = b.bbox.union(r.bbox);
+                b.enter += 1;
+                b.exit += 1;
             }
+        });
 
-            for &b in &bucket_bbox[i..] {
-                rbox = rbox.union(b);
+        for axis in 0..3 {
+            if extent[axis] <= 0.0 {
+                continue;
             }
 
-            let cost: f32 = lsize as f32 * surface(lbox) + rsize as f32 * surface(rbox);
+            if let Some((cost, index)) = sweep_bins(&bins[axis]) {
+                if cost < best_cost {
+                    let (min, scale) = (centers.min[axis], scale[axis]);
+                    best = Some(Split::Object {
+                        axis,
+                        index,
+                        min,
+                        scale,
+                    });
+                    best_cost = cost;
+                }
+            }
+        }
 
-            if cost < best.0 {
-                let mid = (i as f32 / NUM_BUCKETS as f32) * (max - min) + min;
-                best = (cost, axis, mid);
+        // Spatial split: the references are clipped to the bins they overlap. This is only worth
+        // it if the children of the object split overlap significantly.
+        if let Some(clip) = self.clip {
+            let overlap = match best {
+                Some(Split::Object { axis, index, .. }) => {
+                    let left = bins[axis][..index]
+                        .iter()
+                        .fold(AABB::new(), |a, b| a.union(b.bbox));
+                    let right = bins[axis][index..]
+                        .iter()
+                        .fold(AABB::new(), |a, b| a.union(b.bbox));
+                    let both = left.intersection(right);
+                    iff!(both.is_empty(), 0.0, both.surface_area())
+                }
+                _ => f32::INFINITY,
+            };
+
+            if budget > 0 && overlap > MIN_OVERLAP * self.root_area {
+                if let Some((cost, axis, plane)) = self.find_spatial_split(refs, bbox, clip, budget)
+                {
+                    if cost < best_cost {
+                        best = Some(Split::Spatial { axis, plane });
+                        best_cost = cost;
+                    }
+                }
             }
         }
+
+        // Compare against the cost of making this node a leaf.
+        let area = bbox.surface_area();
+        let ratio = iff!(area > 0.0, best_cost / area, 0.0);
+        let split_cost = 1.0 + self.hit_cost * ratio;
+        let leaf_cost = self.hit_cost * refs.len() as f32;
+
+        iff!(split_cost < leaf_cost, best, None)
     }
 
-    let n = objs.len();
-    let (mut i, mut j) = (0, n);
-    let (_, best_axis, best_mid) = best;
+    // Only considers splits which duplicate at most `budget` references.
+    fn find_spatial_split(
+        &self,
+        refs: &[Reference],
+        bbox: AABB,
+        clip: ClipFn<T>,
+        budget: usize,
+    ) -> Option<(f32, usize, f32)> {
+        let extent = bbox.max - bbox.min;
+        let width = extent / NUM_BINS as f32;
+        let bin = |x: f32, axis: usize| {
+            let i = (x - bbox.min[axis]) / width[axis];
+            (i as usize).min(NUM_BINS - 1)
+        };
 
-    loop {
-        while i < n && centers[i][best_axis] >= best_mid {
-            i += 1;
-        }
+        let bins = bin_references(refs, |bins, r| {
+            for axis in 0..3 {
+                if extent[axis] <= 0.0 {
+                    continue;
+                }
+
+                let (first, last) = (bin(r.bbox.min[axis], axis), bin(r.bbox.max[axis], axis));
+                bins[axis][first].enter += 1;
+                bins[axis][last].exit += 1;
+
+                for (i, slot) in bins[axis].iter_mut().enumerate().take(last + 1).skip(first) {
+                    let part = if first == last {
+                        r.bbox
+                    } else {
+                        let lo = bbox.min[axis] + width[axis] * i as f32;
+                        let hi = bbox.min[axis] + width[axis] * (i + 1) as f32;
+                        clip(&self.objs[r.index as usize], axis, lo, hi).intersection(r.bbox)
+                    };
+
+                    if !part.is_empty() {
+                        slot.bbox = slot.bbox.union(part);
+                    }
+                }
+            }
+        });
+
+        let mut best = None;
+        for axis in 0..3 {
+            if extent[axis] <= 0.0 {
+                continue;
+            }
+
+            if let Some((cost, index)) = sweep_bins(&bins[axis]) {
+                let left = bins[axis][..index].iter().map(|b| b.enter).sum::<usize>();
+                let right = bins[axis][index..].iter().map(|b| b.exit).sum::<usize>();
+
+                if left + right - refs.len() > budget {
+                    continue;
+                }
 
-        while j > 0 && centers[j - 1][best_axis] < best_mid {
-            j -= 1;
+                if best.is_none_or(|(c, _, _)| cost < c) {
+                    let plane = bbox.min[axis] + width[axis] * index as f32;
+                    best = Some((cost, axis, plane));
+                }
+            }
         }
 
-        if i + 1 < j {
-            bbox.swap(i, j - 1);
-            centers.swap(i, j - 1);
-            objs.swap(i, j - 1);
-        } else {
-            break;
+        best
+    }
+
+    fn partition(
+        &self,
+        refs: &[Reference],
+        split: Split,
+    ) -> Option<(Vec<Reference>, Vec<Reference>)> {
+        let mut left = vec![];
+        let mut right = vec![];
+
+        match split {
+            Split::Object {
+                axis,
+                index,
+                min,
+                scale,
+            } => {
+                for &r in refs {
+                    let x = (r.bbox.center()[axis] - min) * scale;
+                    if ((x as usize).min(NUM_BINS - 1)) < index {
+                        left.push(r);
+                    } else {
+                        right.push(r);
+                    }
+                }
+            }
+            Split::Spatial { axis, plane } => {
+                let clip = self.clip?;
+                self.spatial_splits.fetch_add(1, Ordering::Relaxed);
+
+                for &r in refs {
+                    if r.bbox.max[axis] <= plane {
+                        left.push(r);
+                    } else if r.bbox.min[axis] >= plane {
+                        right.push(r);
+                    } else {
+                        // Objects which straddle the plane are split into two references.
+                        let obj = &self.objs[r.index as usize];
+                        let lbox = clip(obj, axis, r.bbox.min[axis], plane).intersection(r.bbox);
+                        let rbox = clip(obj, axis, plane, r.bbox.max[axis]).intersection(r.bbox);
+
+                        if !lbox.is_empty() {
+                            left.push(Reference { bbox: lbox, ..r });
+                        }
+
+                        if !rbox.is_empty() {
+                            right.push(Reference { bbox: rbox, ..r });
+                        }
+                    }
+                }
+            }
         }
+
+        iff!(
+            left.is_empty() || right.is_empty(),
+            None,
+            Some((left, right))
+        )
     }
+}
+
+// Index of the only active ray of a packet, such rays are cheaper to trace on their own.
+fn single_lane(t_max: &[f32; PACKET_SIZE]) -> Option<usize> {
+    let mut lanes = t_max.iter().enumerate().filter(|&(_, &t)| t >= 0.0);
 
-    Some(i)
+    match (lanes.next(), lanes.next()) {
+        (Some((lane, _)), None) => Some(lane),
+        _ => None,
+    }
+}
+
+// Moves every object to the position of its reference, the objects must be referenced once.
+fn permute<T>(objs: Vec<T>, order: &[u32]) -> Vec<T> {
+    let mut objs = objs.into_iter().map(Some).collect::<Vec<_>>();
+
+    order
+        .iter()
+        .map(|&i| objs[i as usize].take().expect("object referenced twice"))
+        .collect()
 }
 
 impl<T: Geometry> AABBTree<T> {
-    fn construct_node<F>(
-        bbox: &mut [AABB],
-        objs: &mut [T],
-        begin: usize,
-        end: usize,
-        nodes: &mut Vec<(AABB, u32, u32)>,
-        partition: &F,
+    pub fn new(objs: Vec<T>, hit_cost: f32) -> Self {
+        let start = Instant::now();
+        let n = objs.len();
+        let (out, depth, _) = Builder::new(&objs, hit_cost, None).run();
+
+        // Without spatial splits, every object is referenced exactly once.
+        let objs = permute(objs, &out.order);
+
+        let stats = BuildStats {
+            objects: n,
+            depth,
+            ..BuildStats::default()
+        };
+
+        Self::from_output(objs, out, stats, hit_cost, start)
+    }
+
+    // Fills in the remaining statistics while collapsing the hierarchy.
+    fn from_output(
+        objs: Vec<T>,
+        out: Output,
+        stats: BuildStats,
         hit_cost: f32,
-    ) where
-        F: Fn(&mut [AABB], &mut [T]) -> Option<usize>,
-    {
-        let bb = bbox[begin..end]
-            .iter()
-            .fold(AABB::new(), |a, &b| a.union(b));
+        start: Instant,
+    ) -> Self {
+        let binary = &out.nodes;
+        let nodes = wide::collapse(binary);
+        let root_area = binary[0].0.surface_area();
+        let mut sah_cost = 0.0;
+        let mut leaves = vec![];
+
+        for i in 0..binary.len() - 1 {
+            let area = binary[i].0.surface_area();
+            let area = iff!(root_area > 0.0 && area.is_finite(), area / root_area, 1.0);
+
+            if binary[i].1 as usize == i + 1 {
+                let size = (binary[i + 1].2 - binary[i].2) as usize;
+                sah_cost += hit_cost * size as f32 * area;
+                leaves.push(size);
+            } else {
+                sah_cost += area;
+            }
+        }
+
+        let stats = BuildStats {
+            references: objs.len(),
+            nodes: nodes.len(),
+            leaves: leaves.len(),
+            min_leaf: leaves.iter().copied().min().unwrap_or(0),
+            max_leaf: leaves.iter().copied().max().unwrap_or(0),
+            sah_cost,
+            build_time: start.elapsed(),
+            ..stats
+        };
+
+        AABBTree {
+            objs: objs.into(),
+            build_cost: wide::sah_cost(&nodes, hit_cost),
+            nodes: nodes.into(),
+            bbox: binary[0].0,
+            stats,
+            order: out.order.into(),
+            hit_cost,
+        }
+    }
+
+    // Updates the bounds of the nodes bottom-up after the objects have moved, the structure of the
+    // tree stays the same. Returns the new cost ratio, the tree should be rebuilt once refitting
+    // has made it too expensive to traverse.
+    pub fn refit(&mut self) -> f32 {
+        self.refit_by(T::bounding_box)
+    }
+
+    // Structure of this tree without the objects, which is all that is needed to refit it to other
+    // objects later on.
+    pub fn layout(&self) -> TreeLayout {
+        TreeLayout {
+            nodes: self.nodes.clone(),
+            bbox: self.bbox,
+            stats: self.stats.clone(),
+            order: self.order.clone(),
+            hit_cost: self.hit_cost,
+            build_cost: self.build_cost,
+        }
+    }
+
+    // Builds a new tree over the same objects. Objects referenced from several leaves are only
+    // included once, the new tree does not use spatial splits.
+    pub fn rebuild(self) -> Self {
+        let mut objs = (0..self.stats.objects).map(|_| None).collect::<Vec<_>>();
 
-        let index = nodes.len();
-        nodes.push((AABB::new(), !0 as u32, !0 as u32));
+        for (obj, &i) in self.objs.into_vec().into_iter().zip(self.order.iter()) {
+            if objs[i as usize].is_none() {
+                objs[i as usize] = Some(obj);
+            }
+        }
+
+        let objs = objs.into_iter().flatten().collect();
+        AABBTree::new(objs, self.hit_cost)
+    }
+}
+
+impl<T> AABBTree<T> {
+    pub fn objects(&self) -> &[T] {
+        &self.objs
+    }
+
+    // Objects in the order in which the leaves reference them, call `refit` after moving them.
+    pub fn objects_mut(&mut self) -> &mut [T] {
+        self.objs.to_mut()
+    }
+
+    // Bounding box of the root, the same as `bounding_box` for trees over geometry.
+    pub fn bounds(&self) -> AABB {
+        self.bbox
+    }
+
+    pub fn stats(&self) -> &BuildStats {
+        &self.stats
+    }
 
-        if let Some(len) = partition(&mut bbox[begin..end], &mut objs[begin..end]) {
-            let mid = begin + len;
-            let lsize = mid - begin;
-            let rsize = end - mid;
+    // Index of every reference in the list of objects the tree was built from.
+    pub fn order(&self) -> &[u32] {
+        &self.order
+    }
 
-            if lsize > 0 && rsize > 0 {
-                let lsurface = bbox[begin..mid]
-                    .iter()
-                    .fold(AABB::new(), |a, &b| a.union(b))
-                    .surface_area();
+    pub(super) fn nodes(&self) -> &[WideNode] {
+        &self.nodes
+    }
 
-                let rsurface = bbox[begin..mid]
-                    .iter()
-                    .fold(AABB::new(), |a, &b| a.union(b))
-                    .surface_area();
+    // Reassembles a tree from the nodes of an earlier build, the objects must be in the order in
+    // which the leaves reference them. Returns `None` if the nodes do not fit the objects.
+    pub(super) fn from_parts(
+        objs: Storage<T>,
+        nodes: Storage<WideNode>,
+        order: Storage<u32>,
+        bbox: AABB,
+        stats: BuildStats,
+        hit_cost: f32,
+    ) -> Option<Self> {
+        // Every object is referenced at least once.
+        if order.len() != objs.len() || stats.objects > objs.len() {
+            return None;
+        }
 
-                let surface = bb.surface_area();
+        if order.iter().any(|&i| i as usize >= stats.objects) {
+            return None;
+        }
 
-                let split_cost = 1.0
-                    + hit_cost * (lsurface / surface) * (lsize as f32)
-                    + hit_cost * (rsurface / surface) * (rsize as f32);
-                let nosplit_cost = hit_cost * (lsize + rsize) as f32;
+        for (i, node) in nodes.iter().enumerate() {
+            for (&child, &count) in node.child.iter().zip(&node.count) {
+                let valid = if child == EMPTY {
+                    count == 0
+                } else if count > 0 {
+                    child as usize + count as usize <= objs.len()
+                } else {
+                    // Children are stored after their parent, which rules out cycles.
+                    child as usize > i && (child as usize) < nodes.len()
+                };
 
-                if split_cost < nosplit_cost {
-                    Self::construct_node(bbox, objs, begin, mid, nodes, partition, hit_cost);
-                    Self::construct_node(bbox, objs, mid, end, nodes, partition, hit_cost);
+                if !valid {
+                    return None;
                 }
             }
         }
 
-        nodes[index] = (bb, nodes.len() as u32, begin as u32);
+        if nodes.is_empty() {
+            return None;
+        }
+
+        Some(AABBTree {
+            objs,
+            build_cost: wide::sah_cost(&nodes, hit_cost),
+            nodes,
+            bbox,
+            stats,
+            order,
+            hit_cost,
+        })
     }
 
-    pub fn with_partition<F>(mut objs: Vec<T>, partition: &F, hit_cost: f32) -> Self
-    where
-        F: Fn(&mut [AABB], &mut [T]) -> Option<usize>,
-    {
-        let n = objs.len();
-        let mut nodes = Vec::new();
-        let mut bbox = objs
-            .iter()
-            .map(|obj| obj.bounding_box())
-            .collect::<Vec<_>>();
+    // `refit` with the bounds of every object given by `bounds`, for objects which are not
+    // geometry by themselves.
+    pub(super) fn refit_by<F: Fn(&T) -> AABB>(&mut self, bounds: F) -> f32 {
+        let mut nodes = vec![AABB::new(); self.nodes.len()];
+
+        // Children are stored after their parent, so the nodes are visited bottom-up.
+        for (i, node) in self.nodes.to_mut().iter_mut().enumerate().rev() {
+            let mut bbox = AABB::new();
 
-        Self::construct_node(&mut bbox, &mut objs, 0, n, &mut nodes, partition, hit_cost);
+            for slot in 0..WIDTH {
+                if node.child[slot] == EMPTY {
+                    continue;
+                }
 
-        let m = nodes.len();
-        nodes.push((AABB::new(), m as u32, n as u32));
+                let (child, count) = (node.child[slot] as usize, node.count[slot] as usize);
+                let b = if count > 0 {
+                    self.objs[child..child + count]
+                        .iter()
+                        .fold(AABB::new(), |b, obj| b.union(bounds(obj)))
+                } else {
+                    nodes[child]
+                };
 
+                node.set_bounds(slot, b);
+                bbox = bbox.union(b);
+            }
+
+            nodes[i] = bbox;
+        }
+
+        self.bbox = nodes[0];
+        self.cost_ratio()
+    }
+
+    // SAH cost of the tree relative to its cost right after it was built.
+    pub fn cost_ratio(&self) -> f32 {
+        wide::sah_cost(&self.nodes, self.hit_cost) / self.build_cost
+    }
+
+    // Tree with the same structure over other objects, `fun` maps every reference.
+    pub(super) fn map_objects<U, F: FnMut(T) -> U>(self, fun: F) -> AABBTree<U> {
         AABBTree {
-            objs: objs.into_boxed_slice(),
-            nodes: nodes.into_boxed_slice(),
+            objs: self
+                .objs
+                .into_vec()
+                .into_iter()
+                .map(fun)
+                .collect::<Vec<_>>()
+                .into(),
+            nodes: self.nodes,
+            bbox: self.bbox,
+            stats: self.stats,
+            order: self.order,
+            hit_cost: self.hit_cost,
+            build_cost: self.build_cost,
         }
     }
 
-    pub fn new(objs: Vec<T>, hit_cost: f32) -> Self {
-        let out = Self::with_partition(objs, &partition_sah, hit_cost);
-        out.print_stats();
-        out
+    // The queries of `Geometry`, with the objects intersected by `objs`.
+    #[inline(always)]
+    pub(super) fn hit_with<'a, I>(
+        &'a self,
+        objs: &'a I,
+        ray: &Ray,
+        t_max: f32,
+    ) -> Option<HitResult<'a>>
+    where
+        I: Intersect<T>,
+    {
+        self.traverse(ray, t_max, false, |obj, ray, t_max| {
+            if let Some(hit) = objs.hit(obj, ray, *t_max) {
+                *t_max = hit.t;
+                Some(hit)
+            } else {
+                None
+            }
+        })
     }
 
-    pub fn print_stats(&self) {
-        let n = self.nodes.len();
-        let mut max_leaf = 0;
-        let mut min_leaf = self.objs.len() as u32;
-        let mut num_leaf = 0;
+    #[inline(always)]
+    pub(super) fn is_hit_with<I: Intersect<T>>(&self, objs: &I, ray: &Ray, t_max: f32) -> bool {
+        self.traverse(ray, t_max, true, |obj, ray, t_max| {
+            if objs.is_hit(obj, ray, *t_max) {
+                Some(())
+            } else {
+                None
+            }
+        })
+        .is_some()
+    }
+
+    #[inline(always)]
+    pub(super) fn hit_packet_with<'a, I>(
+        &'a self,
+        objs: &'a I,
+        rays: &RayPacket,
+        t_max: [f32; PACKET_SIZE],
+    ) -> [Option<HitResult<'a>>; PACKET_SIZE]
+    where
+        I: Intersect<T>,
+    {
+        let mut hits: [Option<HitResult>; PACKET_SIZE] = Default::default();
 
-        for i in 0..n - 1 {
-            let size = self.nodes[i + 1].2 - self.nodes[i].2;
+        if let Some(lane) = single_lane(&t_max) {
+            hits[lane] = self.hit_with(objs, &rays[lane], t_max[lane]);
+            return hits;
+        }
 
-            if size > 0 {
-                min_leaf = min!(min_leaf, size);
-                max_leaf = max!(max_leaf, size);
-                num_leaf += 1;
+        self.traverse_packet(rays, t_max, false, |obj, active, t_max| {
+            for (lane, hit) in objs.hit_packet(obj, rays, active).iter_mut().enumerate() {
+                if let Some(hit) = hit.take() {
+                    t_max[lane] = hit.t;
+                    hits[lane] = Some(hit);
+                }
             }
+        });
+
+        hits
+    }
+
+    // Occluded rays are deactivated by setting their `t_max` to minus infinity.
+    #[inline(always)]
+    pub(super) fn is_hit_packet_with<I: Intersect<T>>(
+        &self,
+        objs: &I,
+        rays: &RayPacket,
+        t_max: [f32; PACKET_SIZE],
+    ) -> [bool; PACKET_SIZE] {
+        let mut hits = [false; PACKET_SIZE];
+
+        if let Some(lane) = single_lane(&t_max) {
+            hits[lane] = self.is_hit_with(objs, &rays[lane], t_max[lane]);
+            return hits;
         }
 
-        println!(
-            "BVH statistics: {} objs, {} nodes, {} leafs, min/max/avg leaf: {}/{}/{}",
-            self.objs.len(),
-            self.nodes.len(),
-            num_leaf,
-            min_leaf,
-            max_leaf,
-            (self.objs.len() as f32) / (num_leaf as f32)
-        );
+        self.traverse_packet(rays, t_max, true, |obj, active, t_max| {
+            for (lane, &hit) in objs.is_hit_packet(obj, rays, active).iter().enumerate() {
+                if hit {
+                    t_max[lane] = -f32::INFINITY;
+                    hits[lane] = true;
+                }
+            }
+        });
+
+        hits
     }
 
+    // Visits the children that are hit from front to back, so that closer hits shrink `t_max`
+    // before the farther children are tested.
     #[inline(always)]
     fn traverse<'a, F, R>(
         &'a self,
@@ -214,73 +920,478 @@ impl<T: Geometry> AABBTree<T> {
     where
         F: Fn(&'a T, &Ray, &mut f32) -> Option<R>,
     {
-        let mut i = 0;
-        let n = self.nodes.len() - 1;
+        let wide_ray = WideRay::new(ray);
         let mut result = None;
-        let ray_inv_dir = 1.0 / ray.dir;
-        let ray_neg_dir = [
-            ray_inv_dir[0].is_sign_negative(),
-            ray_inv_dir[1].is_sign_negative(),
-            ray_inv_dir[2].is_sign_negative(),
-        ];
-
-        while i < n {
-            let node = &self.nodes[i];
-
-            if let Some((t0, t1)) = node.0.fast_intersect_ray(ray.pos, ray_inv_dir, ray_neg_dir) {
-                if t0 <= t_max + 0.01 && t1 >= -0.01 {
-                    let begin = node.2 as usize;
-                    let end = self.nodes[i + 1].2 as usize;
-
-                    for obj in &self.objs[begin..end] {
-                        if let Some(r) = fun(obj, ray, &mut t_max) {
-                            result = Some(r);
-
-                            if exit_immediate {
-                                return result;
-                            }
+
+        // Entries are (child, count, distance) as stored in the nodes. Only the entries below `top`
+        // are initialized, clearing the entire stack for every ray is measurably slower.
+        let mut stack = [MaybeUninit::<(u32, u32, f32)>::uninit(); STACK_SIZE];
+        stack[0] = MaybeUninit::new((0, 0, -f32::INFINITY));
+        let mut top = 1;
+
+        while top > 0 {
+            top -= 1;
+            let (child, count, dist) = unsafe { stack[top].assume_init() };
+
+            if dist > t_max + 0.01 {
+                continue;
+            }
+
+            if count > 0 {
+                let begin = child as usize;
+                let end = begin + count as usize;
+
+                for obj in &self.objs[begin..end] {
+                    if let Some(r) = fun(obj, ray, &mut t_max) {
+                        result = Some(r);
+
+                        if exit_immediate {
+                            return result;
                         }
                     }
+                }
+
+                continue;
+            }
+
+            let node = &self.nodes[child as usize];
+            let (mut mask, t_near) = node.intersect(&wide_ray, t_max);
+            let base = top;
+
+            while mask != 0 {
+                let i = 31 - mask.leading_zeros() as usize;
+                mask &= !(1 << i);
+
+                if node.child[i] == EMPTY {
+                    continue;
+                }
+
+                // Insertion sort by decreasing distance, the nearest child is pushed last. Any hit
+                // suffices for occlusion queries, they visit the children in their stored order.
+                let entry = (node.child[i], node.count[i], t_near[i]);
+                let mut j = top;
+                while !exit_immediate
+                    && j > base
+                    && unsafe { stack[j - 1].assume_init().2 } < entry.2
+                {
+                    stack[j] = stack[j - 1];
+                    j -= 1;
+                }
+
+                stack[j] = MaybeUninit::new(entry);
+                top += 1;
+            }
+        }
+
+        result
+    }
+
+    // Packet version of `traverse`. Every entry on the stack carries the mask of the rays which hit
+    // the node and their entry distances. Rays are dropped from an entry once they have found a
+    // closer hit, the entry is skipped once no rays remain. Rays with a negative `t_max` are
+    // inactive, `fun` receives the `t_max` of every ray with the inactive ones set to -1.
+    #[inline(always)]
+    fn traverse_packet<'a, F>(
+        &'a self,
+        rays: &RayPacket,
+        mut t_max: [f32; PACKET_SIZE],
+        exit_immediate: bool,
+        mut fun: F,
+    ) where
+        F: FnMut(&'a T, [f32; PACKET_SIZE], &mut [f32; PACKET_SIZE]),
+    {
+        let packet = WidePacket::new(rays);
+        let mut mask = 0;
+
+        for (lane, &t) in t_max.iter().enumerate() {
+            mask |= ((t >= 0.0) as u32) << lane;
+        }
 
-                    i += 1;
+        // Entries are (child, count, mask, distance, distance per ray). The distance is the
+        // nearest entry distance of the rays in the mask.
+        type Entry = (u32, u32, u32, f32, [f32; PACKET_SIZE]);
+        let mut stack = [MaybeUninit::<Entry>::uninit(); STACK_SIZE];
+        stack[0] = MaybeUninit::new((0, 0, mask, -f32::INFINITY, [-f32::INFINITY; PACKET_SIZE]));
+        let mut top = iff!(mask != 0, 1, 0);
+
+        while top > 0 {
+            top -= 1;
+            let (child, count, mut mask, _, dist) = unsafe { stack[top].assume_init() };
+            let mut active = [-f32::INFINITY; PACKET_SIZE];
+
+            for lane in 0..PACKET_SIZE {
+                if mask & (1 << lane) != 0 && dist[lane] <= t_max[lane] + 0.01 {
+                    active[lane] = t_max[lane];
                 } else {
-                    i = node.1 as usize;
+                    mask &= !(1 << lane);
+                }
+            }
+
+            if mask == 0 {
+                continue;
+            }
+
+            if count > 0 {
+                let begin = child as usize;
+                let end = begin + count as usize;
+
+                for obj in &self.objs[begin..end] {
+                    for (lane, t) in active.iter_mut().enumerate() {
+                        *t = iff!(mask & (1 << lane) != 0, t_max[lane], -1.0);
+                    }
+
+                    fun(obj, active, &mut t_max);
+
+                    if exit_immediate && t_max.iter().all(|&t| t < 0.0) {
+                        return;
+                    }
+                }
+
+                continue;
+            }
+
+            let node = &self.nodes[child as usize];
+            let mut masks = [0; WIDTH];
+            let mut dists = [[0.0; PACKET_SIZE]; WIDTH];
+
+            // Once the rays diverge a single remaining ray is cheaper to test on its own.
+            if mask & (mask - 1) == 0 {
+                let lane = mask.trailing_zeros() as usize;
+                let (hits, t_near) = node.intersect(&packet.lane(lane), active[lane]);
+
+                for i in 0..WIDTH {
+                    masks[i] = ((hits >> i) & 1) << lane;
+                    dists[i][lane] = t_near[i];
                 }
             } else {
-                i = node.1 as usize;
+                (masks, dists) = node.intersect_packet(&packet, active);
+            }
+
+            let base = top;
+
+            for i in (0..WIDTH).rev() {
+                if masks[i] == 0 || node.child[i] == EMPTY {
+                    continue;
+                }
+
+                // Same order as `traverse`, using the nearest ray of the packet as distance.
+                let mut near = f32::INFINITY;
+                for (lane, &d) in dists[i].iter().enumerate() {
+                    if masks[i] & (1 << lane) != 0 {
+                        near = near.min(d);
+                    }
+                }
+
+                let entry = (node.child[i], node.count[i], masks[i], near, dists[i]);
+                let mut j = top;
+                while !exit_immediate
+                    && j > base
+                    && unsafe { stack[j - 1].assume_init().3 } < entry.3
+                {
+                    stack[j] = stack[j - 1];
+                    j -= 1;
+                }
+
+                stack[j] = MaybeUninit::new(entry);
+                top += 1;
             }
         }
+    }
+}
 
-        result
+impl<T: Clip> AABBTree<T> {
+    // Spatial split BVH (SBVH), which may reference objects from multiple leaves. This gives
+    // tighter nodes for long and thin objects at the cost of a slower build.
+    pub fn with_spatial_splits(objs: Vec<T>, hit_cost: f32) -> Self {
+        let start = Instant::now();
+        let (out, depth, spatial_splits) =
+            Builder::new(&objs, hit_cost, Some(T::clip_bounds)).run();
+
+        let refs = out
+            .order
+            .iter()
+            .map(|&i| objs[i as usize].clone())
+            .collect::<Vec<_>>();
+
+        let stats = BuildStats {
+            objects: objs.len(),
+            depth,
+            spatial_splits,
+            ..BuildStats::default()
+        };
+
+        Self::from_output(refs, out, stats, hit_cost, start)
+    }
+}
+
+// Intersects the objects of a tree, for objects which are not geometry by themselves. The faces of
+// a mesh, for example, only index the vertices stored in the mesh.
+pub(super) trait Intersect<T> {
+    fn hit<'a>(&'a self, obj: &'a T, ray: &Ray, t_max: f32) -> Option<HitResult<'a>>;
+    fn is_hit(&self, obj: &T, ray: &Ray, t_max: f32) -> bool;
+    fn hit_packet<'a>(
+        &'a self,
+        obj: &'a T,
+        rays: &RayPacket,
+        t_max: [f32; PACKET_SIZE],
+    ) -> [Option<HitResult<'a>>; PACKET_SIZE];
+    fn is_hit_packet(
+        &self,
+        obj: &T,
+        rays: &RayPacket,
+        t_max: [f32; PACKET_SIZE],
+    ) -> [bool; PACKET_SIZE];
+}
+
+// Objects which are geometry by themselves.
+struct Direct;
+
+impl<T: Geometry> Intersect<T> for Direct {
+    #[inline(always)]
+    fn hit<'a>(&'a self, obj: &'a T, ray: &Ray, t_max: f32) -> Option<HitResult<'a>> {
+        obj.hit(ray, t_max)
+    }
+
+    #[inline(always)]
+    fn is_hit(&self, obj: &T, ray: &Ray, t_max: f32) -> bool {
+        obj.is_hit(ray, t_max)
+    }
+
+    #[inline(always)]
+    fn hit_packet<'a>(
+        &'a self,
+        obj: &'a T,
+        rays: &RayPacket,
+        t_max: [f32; PACKET_SIZE],
+    ) -> [Option<HitResult<'a>>; PACKET_SIZE] {
+        obj.hit_packet(rays, t_max)
+    }
+
+    #[inline(always)]
+    fn is_hit_packet(
+        &self,
+        obj: &T,
+        rays: &RayPacket,
+        t_max: [f32; PACKET_SIZE],
+    ) -> [bool; PACKET_SIZE] {
+        obj.is_hit_packet(rays, t_max)
     }
 }
 
 impl<T: Geometry> Geometry for AABBTree<T> {
     #[inline(never)]
-    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult> {
-        self.traverse(ray, t_max, false, |obj, ray, t_max| {
-            if let Some(hit) = obj.hit(ray, *t_max) {
-                *t_max = hit.t;
-                Some(hit)
-            } else {
-                None
-            }
-        })
+    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>> {
+        self.hit_with(&Direct, ray, t_max)
     }
 
     #[inline(never)]
     fn is_hit(&self, ray: &Ray, t_max: f32) -> bool {
-        self.traverse(ray, t_max, true, |obj, ray, t_max| {
-            if obj.is_hit(ray, *t_max) {
-                Some(())
-            } else {
-                None
-            }
-        })
-        .is_some()
+        self.is_hit_with(&Direct, ray, t_max)
+    }
+
+    #[inline(never)]
+    fn hit_packet(
+        &self,
+        rays: &RayPacket,
+        t_max: [f32; PACKET_SIZE],
+    ) -> [Option<HitResult<'_>>; PACKET_SIZE] {
+        self.hit_packet_with(&Direct, rays, t_max)
+    }
+
+    #[inline(never)]
+    fn is_hit_packet(&self, rays: &RayPacket, t_max: [f32; PACKET_SIZE]) -> [bool; PACKET_SIZE] {
+        self.is_hit_packet_with(&Direct, rays, t_max)
     }
 
     fn bounding_box(&self) -> AABB {
-        self.nodes[0].0
+        self.bbox
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::Triangle;
+    use rand::prelude::*;
+
+    // Random triangles with their vertices on a grid, such that rays starting on the grid lie
+    // exactly in the planes of the bounding boxes. Triangles parallel to any axis are skipped,
+    // rays along the grid could lie in their plane, where the triangle test is not meaningful.
+    fn triangle_soup(rng: &mut SmallRng, n: usize) -> Vec<Triangle> {
+        let vertex = |rng: &mut SmallRng, center: Vec3D| {
+            let p = center + Vec3D::from_map(|_| rng.gen_range(-0.5, 0.5));
+            Vec3D::from_map(|axis| (p[axis] * 4.0).round() / 4.0)
+        };
+
+        let mut result = vec![];
+
+        while result.len() < n {
+            let center = Vec3D::from_map(|_| rng.gen_range(-2.0, 2.0));
+            let [a, b, c] = [(); 3].map(|_| vertex(rng, center));
+            let norm = Vec3D::cross(b - a, c - a);
+
+            if (0..3).all(|axis| norm[axis] != 0.0) {
+                result.push(Triangle::new(a, b, c));
+            }
+        }
+
+        result
+    }
+
+    // Rays in general position, along the axes, parallel to the planes of the grid and with NaN
+    // components.
+    fn random_ray(rng: &mut SmallRng) -> Ray {
+        let grid = |rng: &mut SmallRng| (rng.gen_range(-10, 10) as f32) / 4.0;
+        let pos = Vec3D::from_map(|_| grid(rng));
+        let mut dir = Vec3D::from_map(|_| rng.gen_range(-1.0, 1.0));
+
+        match rng.gen_range(0, 4) {
+            0 => return Ray::new(Vec3D::from_map(|_| rng.gen_range(-3.0, 3.0)), dir),
+            1 => {
+                let axis = rng.gen_range(0, 3);
+                dir = Vec3D::from_map(|i| iff!(i == axis, dir[i].signum(), 0.0));
+            }
+            2 => dir[rng.gen_range(0, 3)] = 0.0,
+            _ => dir[rng.gen_range(0, 3)] = f32::NAN,
+        }
+
+        // Negative zeros flip the sign of the infinite inverse direction.
+        for axis in 0..3 {
+            if dir[axis] == 0.0 && rng.gen() {
+                dir[axis] = -0.0;
+            }
+        }
+
+        Ray::new(pos, dir)
+    }
+
+    // Plain slab test, slabs that produce NaN do not cull anything.
+    fn scalar_intersect(bbox: &AABB, ray: &Ray, t_max: f32) -> bool {
+        let mut t0 = -0.01f32;
+        let mut t1 = t_max + 0.01;
+
+        for axis in 0..3 {
+            let (near, far) = match ray.inv_dir[axis].is_sign_negative() {
+                true => (bbox.max[axis], bbox.min[axis]),
+                false => (bbox.min[axis], bbox.max[axis]),
+            };
+
+            t0 = t0.max((near - ray.pos[axis]) * ray.inv_dir[axis]);
+            t1 = t1.min((far - ray.pos[axis]) * ray.inv_dir[axis]);
+        }
+
+        t0 <= t1
+    }
+
+    // Walks the binary hierarchy in its skip-pointer layout, returns the distance to the nearest
+    // hit.
+    fn scalar_hit(out: &Output, objs: &[Triangle], ray: &Ray) -> Option<f32> {
+        let mut t_max = 1e12;
+        let mut result = None;
+        let mut i = 0;
+
+        while i < out.nodes.len() - 1 {
+            let (bbox, skip, begin) = out.nodes[i];
+
+            if !scalar_intersect(&bbox, ray, t_max) {
+                i = skip as usize;
+                continue;
+            }
+
+            let end = out.nodes[i + 1].2;
+            for &index in &out.order[begin as usize..end as usize] {
+                if let Some(hit) = objs[index as usize].hit(ray, t_max) {
+                    t_max = hit.t;
+                    result = Some(hit.t);
+                }
+            }
+
+            i += 1;
+        }
+
+        result
+    }
+
+    fn assert_same_hit(hit: Option<HitResult<'_>>, expected: Option<f32>, ray: &Ray) {
+        match (hit.map(|h| h.t), expected) {
+            (Some(a), Some(b)) => assert!((a - b).abs() < 1e-5, "{:?}: {} != {}", ray, a, b),
+            (None, None) => {}
+            (a, b) => panic!("{:?}: {:?} != {:?}", ray, a, b),
+        }
+    }
+
+    #[test]
+    fn wide_traversal_matches_scalar() {
+        let mut rng = SmallRng::seed_from_u64(21);
+        let objs = triangle_soup(&mut rng, 2000);
+        let (out, _, _) = Builder::new(&objs, 1.0, None).run();
+        let tree = AABBTree::new(objs.clone(), 1.0);
+
+        for _ in 0..500 {
+            let rays: RayPacket = std::array::from_fn(|_| random_ray(&mut rng));
+            let expected = rays.map(|ray| scalar_hit(&out, &objs, &ray));
+            let hits = tree.hit_packet(&rays, [1e12; PACKET_SIZE]);
+            let occluded = tree.is_hit_packet(&rays, [1e12; PACKET_SIZE]);
+
+            for (lane, (ray, hit)) in rays.iter().zip(hits).enumerate() {
+                assert_same_hit(tree.hit(ray, 1e12), expected[lane], ray);
+                assert_same_hit(hit, expected[lane], ray);
+                assert_eq!(tree.is_hit(ray, 1e12), expected[lane].is_some());
+                assert_eq!(occluded[lane], expected[lane].is_some());
+            }
+        }
+    }
+
+    fn contains(outer: &AABB, inner: &AABB) -> bool {
+        (0..3).all(|axis| outer.min[axis] <= inner.min[axis] && inner.max[axis] <= outer.max[axis])
+    }
+
+    #[test]
+    fn refit_bounds_contain_moved_triangles() {
+        let mut rng = SmallRng::seed_from_u64(24);
+        let mut tree = AABBTree::new(triangle_soup(&mut rng, 2000), 1.0);
+
+        // Spread the triangles apart, so that they leave the bounds of their leaves.
+        for tri in tree.objects_mut() {
+            let offset = Vec3D::from_map(|_| rng.gen_range(-1.0, 1.0));
+            *tri = Triangle::new(
+                tri.a * 2.0 + offset,
+                tri.b * 2.0 + offset,
+                tri.c * 2.0 + offset,
+            );
+        }
+
+        let ratio = tree.refit();
+        assert_eq!(ratio, tree.cost_ratio());
+        assert!(
+            ratio > 1.0,
+            "cost ratio {} after spreading the triangles",
+            ratio
+        );
+
+        let nodes = tree.nodes();
+        for node in nodes {
+            for slot in 0..WIDTH {
+                if node.child[slot] == EMPTY {
+                    continue;
+                }
+
+                let bbox = node.bounds(slot);
+                assert!(contains(&tree.bounding_box(), &bbox));
+
+                let (child, count) = (node.child[slot] as usize, node.count[slot] as usize);
+                if count > 0 {
+                    for tri in &tree.objects()[child..child + count] {
+                        assert!(contains(&bbox, &tri.bounding_box()));
+                    }
+                } else {
+                    for inner in 0..WIDTH {
+                        if nodes[child].child[inner] != EMPTY {
+                            assert!(contains(&bbox, &nodes[child].bounds(inner)));
+                        }
+                    }
+                }
+            }
+        }
     }
 }
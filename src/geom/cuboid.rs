@@ -1,6 +1,7 @@
-use crate::geom::{Geometry, HitResult};
+use crate::geom::{Geometry, HitResult, Surface, SurfaceSample};
 use crate::material::DEFAULT_MATERIAL;
 use crate::math::*;
+use crate::texture::COLOR_WHITE;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct UnitCuboid;
@@ -16,7 +17,7 @@ fn ray_box_intersection(ray: &Ray, min: Vec3D, max: Vec3D) -> (f32, f32) {
 }
 
 impl Geometry for UnitCuboid {
-    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult> {
+    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>> {
         let (t0, t1) = ray_box_intersection(ray, -Vec3D::one(), Vec3D::one());
 
         let t = if t0 > t1 || t0 > t_max || t1 < 0.0 {
@@ -42,6 +43,7 @@ impl Geometry for UnitCuboid {
             pos: p,
             norm: n,
             uv: [u, v],
+            color: COLOR_WHITE,
             t,
             material: &DEFAULT_MATERIAL,
         })
@@ -79,10 +81,10 @@ impl Cuboid {
 }
 
 impl Geometry for Cuboid {
-    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult> {
+    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>> {
         let p = (ray.pos - self.center) * self.inv_extent;
         let d = ray.dir * self.inv_extent;
-        let new_ray = Ray::new(p, d);
+        let new_ray = Ray::with_time(p, d, ray.time);
 
         if let Some(mut h) = UnitCuboid.hit(&new_ray, t_max) {
             h.pos = h.pos * self.extent + self.center;
@@ -103,3 +105,47 @@ impl Geometry for Cuboid {
         AABB::from_min_max(self.center - self.extent, self.center + self.extent)
     }
 }
+
+fn sample_box(center: Vec3D, extent: Vec3D, [u, v]: [f32; 2]) -> SurfaceSample {
+    let e = extent;
+    let areas = [e[1] * e[2], e[0] * e[2], e[0] * e[1]];
+    let total = areas[0] + areas[1] + areas[2];
+
+    // Pick one of the six faces proportional to its area and reuse `u` within it.
+    let mut x = u * total * 2.0;
+    let mut face = 0;
+    while face < 5 && x >= areas[face / 2] {
+        x -= areas[face / 2];
+        face += 1;
+    }
+
+    let axis = face / 2;
+    let sign = iff!(face % 2 == 0, 1.0, -1.0);
+    let s = (x / areas[axis]).min(1.0) * 2.0 - 1.0;
+    let t = v * 2.0 - 1.0;
+
+    let mut local = Vec3D::zero();
+    let mut norm = Vec3D::zero();
+    local[axis] = sign;
+    local[(axis + 1) % 3] = s;
+    local[(axis + 2) % 3] = t;
+    norm[axis] = sign;
+
+    SurfaceSample {
+        pos: center + local * extent,
+        norm,
+        pdf: 1.0 / (8.0 * total),
+    }
+}
+
+impl Surface for UnitCuboid {
+    fn sample_surface(&self, u: [f32; 2], _: f32) -> SurfaceSample {
+        sample_box(Vec3D::zero(), Vec3D::one(), u)
+    }
+}
+
+impl Surface for Cuboid {
+    fn sample_surface(&self, u: [f32; 2], _: f32) -> SurfaceSample {
+        sample_box(self.center, self.extent, u)
+    }
+}
@@ -1,29 +1,125 @@
-use crate::geom::triangle::moller_trumbore;
-use crate::geom::{AABBTree, Geometry, HitResult, Triangle};
+use super::bvh::Intersect;
+use super::storage::{Plain, Storage};
+use crate::geom::triangle::{moller_trumbore, sample_triangle};
+use crate::geom::{
+    AABBTree, BuildStats, Clip, Geometry, HitResult, RayPacket, Surface, SurfaceSample, Triangle,
+    PACKET_SIZE,
+};
 use crate::material::DEFAULT_MATERIAL;
 use crate::math::*;
+use crate::texture::{Color, COLOR_WHITE};
 use crunchy::unroll;
-use delegate::*;
+use memmap::Mmap;
 use std::collections::HashMap;
-use std::mem::transmute;
+use std::io::{self, Write};
+use std::mem::{size_of, transmute};
 use std::sync::Arc;
+use std::time::Instant;
 
-struct MeshTriangle {
+// Vertex data of a mesh, either owned by the mesh or served from a mapped cache.
+struct MeshData {
+    positions: Storage<Vec3D>,
+    normals: Storage<Vec3D>,
+    uvs: Option<Storage<[f32; 2]>>,
+    colors: Option<Storage<Color>>,
+}
+
+// Face of a mesh while its hierarchy is built, the finished tree only keeps the indices.
+#[derive(Clone, Copy)]
+struct MeshTriangle<'a> {
     vertices: [u32; 3],
-    data: Arc<[Vec3D]>,
+    data: &'a MeshData,
 }
 
+// Optional per-vertex data, normals are computed from the faces if absent.
+#[derive(Debug, Clone, Default)]
+pub struct VertexAttributes {
+    pub normals: Option<Vec<Vec3D>>,
+    pub uvs: Option<Vec<[f32; 2]>>,
+    pub colors: Option<Vec<Color>>,
+}
+
+const HIT_COST: f32 = 0.1;
+
 pub struct Mesh {
-    tree: AABBTree<MeshTriangle>,
+    tree: AABBTree<[u32; 3]>,
+    cdf: Storage<f32>,
+    data: MeshData,
+}
+
+// Layout of the cache written by `Mesh::write_cache`. All values are stored in little-endian
+// order and every array starts at a multiple of `CACHE_ALIGN` bytes, so that the arrays of a
+// mapped cache can be used in place. The version must be bumped whenever the layout, the
+// construction of the stored hierarchy or the conversion of the vertex data on load changes.
+const CACHE_MAGIC: &[u8; 8] = b"RTMESH\0\0";
+const CACHE_VERSION: u32 = 5;
+const CACHE_ALIGN: usize = 16;
+const CACHE_HAS_UVS: u32 = 1;
+const CACHE_HAS_COLORS: u32 = 2;
+
+fn smooth_normals(vertices: &[Vec3D], faces: &[[u32; 3]]) -> Vec<Vec3D> {
+    let n = vertices.len();
+    let mut normals = vec![Vec3D::zero(); n];
+
+    for &[i, j, k] in faces {
+        let [a, b, c] = [
+            vertices[i as usize],
+            vertices[j as usize],
+            vertices[k as usize],
+        ];
+
+        let e1 = b - a;
+        let e2 = c - a;
+        let normal = Vec3D::cross(e1, e2);
+
+        unsafe {
+            *normals.get_unchecked_mut(i as usize) += normal;
+            *normals.get_unchecked_mut(j as usize) += normal;
+            *normals.get_unchecked_mut(k as usize) += normal;
+        }
+    }
+
+    for normal in &mut normals {
+        *normal = normal.normalize();
+    }
+
+    normals
 }
 
 impl Mesh {
     pub fn new(vertices: Vec<Vec3D>, normals: Vec<Vec3D>, faces: Vec<[u32; 3]>) -> Self {
-        let n = vertices.len();
+        let attributes = VertexAttributes {
+            normals: Some(normals),
+            ..VertexAttributes::default()
+        };
 
-        if normals.len() != n {
-            panic!("invalid number of normals");
-        }
+        Self::with_attributes(vertices, attributes, faces)
+    }
+
+    pub fn with_attributes(
+        vertices: Vec<Vec3D>,
+        attributes: VertexAttributes,
+        faces: Vec<[u32; 3]>,
+    ) -> Self {
+        Self::build(vertices, attributes, faces, false)
+    }
+
+    // Builds the hierarchy with spatial splits, see `AABBTree::with_spatial_splits`.
+    pub fn with_spatial_splits(
+        vertices: Vec<Vec3D>,
+        attributes: VertexAttributes,
+        faces: Vec<[u32; 3]>,
+    ) -> Self {
+        Self::build(vertices, attributes, faces, true)
+    }
+
+    fn build(
+        vertices: Vec<Vec3D>,
+        attributes: VertexAttributes,
+        faces: Vec<[u32; 3]>,
+        spatial_splits: bool,
+    ) -> Self {
+        let n = vertices.len();
 
         for face in &faces {
             for &i in face {
@@ -33,51 +129,267 @@ impl Mesh {
             }
         }
 
-        let mut data = vec![];
-        data.extend(vertices);
-        data.extend(normals);
-        let data: Arc<[_]> = data.into();
+        let VertexAttributes {
+            normals,
+            uvs,
+            colors,
+        } = attributes;
 
-        let tris = faces
-            .into_iter()
-            .map(move |[a, b, c]| MeshTriangle {
-                vertices: [a, b, c],
-                data: data.clone(),
-            })
-            .collect::<Vec<_>>();
+        let normals = normals.unwrap_or_else(|| smooth_normals(&vertices, &faces));
 
-        Mesh {
-            tree: AABBTree::new(tris, 0.1),
+        if normals.len() != n {
+            panic!("invalid number of normals");
         }
+
+        if uvs.as_ref().is_some_and(|x| x.len() != n) {
+            panic!("invalid number of texture coordinates");
+        }
+
+        if colors.as_ref().is_some_and(|x| x.len() != n) {
+            panic!("invalid number of colors");
+        }
+
+        let data = MeshData {
+            positions: vertices.into(),
+            normals: normals.into(),
+            uvs: uvs.map(Storage::from),
+            colors: colors.map(Storage::from),
+        };
+
+        Self::from_data(data, faces, spatial_splits)
     }
 
-    pub fn from_vertices(vertices: Vec<Vec3D>, faces: Vec<[u32; 3]>) -> Self {
-        let n = vertices.len();
-        let mut normals = vec![Vec3D::zero(); n];
-
-        for &[i, j, k] in &faces {
-            let [a, b, c] = [
-                vertices[i as usize],
-                vertices[j as usize],
-                vertices[k as usize],
-            ];
-
-            let e1 = b - a;
-            let e2 = c - a;
-            let normal = Vec3D::cross(e1, e2);
-
-            unsafe {
-                *normals.get_unchecked_mut(i as usize) += normal;
-                *normals.get_unchecked_mut(j as usize) += normal;
-                *normals.get_unchecked_mut(k as usize) += normal;
+    fn from_data(data: MeshData, faces: Vec<[u32; 3]>, spatial_splits: bool) -> Self {
+        let tree = build_tree(&data, faces, spatial_splits);
+        let cdf = cumulative_areas(&tree, &data);
+        Mesh { tree, cdf, data }
+    }
+
+    // Faces in their original order, each face is included once even if it is referenced from
+    // several leaves.
+    fn faces(&self) -> Vec<[u32; 3]> {
+        let mut faces = vec![[0; 3]; self.tree.stats().objects];
+
+        for (&face, &i) in self.tree.objects().iter().zip(self.tree.order()) {
+            faces[i as usize] = face;
+        }
+
+        faces
+    }
+
+    // Moves the vertices, for example to the next frame of an animation. The normals are computed
+    // from the faces if none are given. The hierarchy is refit to the new positions and only
+    // rebuilt once its cost ratio exceeds `max_ratio`, returns whether it was rebuilt.
+    pub fn set_positions(
+        &mut self,
+        positions: Vec<Vec3D>,
+        normals: Option<Vec<Vec3D>>,
+        max_ratio: f32,
+    ) -> bool {
+        let n = self.data.positions.len();
+        if positions.len() != n {
+            panic!("invalid number of vertices");
+        }
+
+        let faces = self.faces();
+        let normals = normals.unwrap_or_else(|| smooth_normals(&positions, &faces));
+
+        if normals.len() != n {
+            panic!("invalid number of normals");
+        }
+
+        self.data.positions = positions.into();
+        self.data.normals = normals.into();
+
+        let data = &self.data;
+        let rebuild = self.tree.refit_by(|&face| data.bounding_box(face)) > max_ratio;
+
+        if rebuild {
+            // Trees built with spatial splits reference some faces from several leaves.
+            let stats = self.tree.stats();
+            let spatial_splits = stats.references > stats.objects;
+            self.tree = build_tree(&self.data, faces, spatial_splits);
+        }
+
+        self.cdf = cumulative_areas(&self.tree, &self.data);
+        rebuild
+    }
+
+    pub fn stats(&self) -> &BuildStats {
+        self.tree.stats()
+    }
+
+    // Writes the vertex data, the triangles in the order of the leaves and the flattened nodes.
+    // The key identifies the source of the mesh, `read_cache` rejects caches with another key.
+    pub fn write_cache<W: Write>(&self, key: u64, w: W) -> io::Result<()> {
+        let data = &self.data;
+        let faces = self.tree.objects();
+        let nodes = self.tree.nodes();
+        let stats = self.tree.stats();
+        let bbox = self.tree.bounds();
+
+        let mut flags = 0;
+        if data.uvs.is_some() {
+            flags |= CACHE_HAS_UVS;
+        }
+        if data.colors.is_some() {
+            flags |= CACHE_HAS_COLORS;
+        }
+
+        let mut w = CacheWriter { w, offset: 0 };
+        w.bytes(CACHE_MAGIC)?;
+        w.u32s(&[CACHE_VERSION])?;
+        w.u64(key)?;
+        w.u32s(&[
+            flags,
+            data.positions.len() as u32,
+            faces.len() as u32,
+            nodes.len() as u32,
+        ])?;
+
+        w.vectors(&[bbox.min, bbox.max])?;
+        for &x in &[
+            stats.objects,
+            stats.leaves,
+            stats.min_leaf,
+            stats.max_leaf,
+            stats.depth,
+            stats.spatial_splits,
+        ] {
+            w.u64(x as u64)?;
+        }
+        w.f32s(&[stats.sah_cost])?;
+
+        w.align()?;
+        w.vectors(&data.positions)?;
+        w.align()?;
+        w.vectors(&data.normals)?;
+
+        if let Some(uvs) = &data.uvs {
+            w.align()?;
+            for uv in uvs.iter() {
+                w.f32s(uv)?;
             }
         }
 
-        for normal in &mut normals {
-            *normal = normal.normalize();
+        if let Some(colors) = &data.colors {
+            w.align()?;
+            w.vectors(colors)?;
+        }
+
+        w.align()?;
+        for face in faces {
+            w.u32s(face)?;
         }
 
-        Self::new(vertices, normals, faces)
+        w.align()?;
+        w.u32s(self.tree.order())?;
+
+        // Fields in the order in which `WideNode` stores them.
+        w.align()?;
+        for node in nodes {
+            for axis in 0..3 {
+                w.f32s(&node.min[axis])?;
+            }
+            for axis in 0..3 {
+                w.f32s(&node.max[axis])?;
+            }
+            w.u32s(&node.child)?;
+            w.u32s(&node.count)?;
+        }
+
+        w.align()?;
+        w.f32s(&self.cdf)
+    }
+
+    // Reads a mesh written by `write_cache`. The arrays of the mesh are views of the mapping, which
+    // stays alive as long as the mesh. Returns `None` if the cache was written for another key or
+    // by another version, or if it is damaged. The build time in the statistics of the mesh is the
+    // time it took to read and check the cache.
+    pub fn read_cache(map: Arc<Mmap>, key: u64) -> Option<Self> {
+        // The arrays are only used in place if they are in the byte order of the machine.
+        if cfg!(target_endian = "big") {
+            return None;
+        }
+
+        let start = Instant::now();
+        let mut r = CacheReader {
+            map: &map,
+            offset: 0,
+        };
+
+        if r.take(8)? != CACHE_MAGIC || r.u32()? != CACHE_VERSION || r.u64()? != key {
+            return None;
+        }
+
+        let flags = r.u32()?;
+        let n = r.u32()? as usize;
+        let m = r.u32()? as usize;
+        let k = r.u32()? as usize;
+
+        let bbox = AABB {
+            min: r.vector()?,
+            max: r.vector()?,
+        };
+
+        let mut stats = BuildStats {
+            objects: r.u64()? as usize,
+            references: m,
+            nodes: k,
+            leaves: r.u64()? as usize,
+            min_leaf: r.u64()? as usize,
+            max_leaf: r.u64()? as usize,
+            depth: r.u64()? as usize,
+            spatial_splits: r.u64()? as usize,
+            sah_cost: r.f32()?,
+            ..BuildStats::default()
+        };
+
+        let positions = r.array(n)?;
+        let normals = r.array(n)?;
+
+        let uvs = if flags & CACHE_HAS_UVS != 0 {
+            Some(r.array(n)?)
+        } else {
+            None
+        };
+
+        let colors = if flags & CACHE_HAS_COLORS != 0 {
+            Some(r.array(n)?)
+        } else {
+            None
+        };
+
+        let faces = r.array::<[u32; 3]>(m)?;
+        let order = r.array(m)?;
+        let nodes = r.array(k)?;
+        let cdf = r.array(m)?;
+
+        if r.offset != map.len() {
+            return None;
+        }
+
+        // Faces index the vertex data without bounds checks and sampling the surface searches the
+        // cdf, the nodes and the order are checked by `AABBTree::from_parts`.
+        if faces.iter().flatten().any(|&i| i as usize >= n) || !is_valid_cdf(&cdf) {
+            return None;
+        }
+
+        let data = MeshData {
+            positions,
+            normals,
+            uvs,
+            colors,
+        };
+
+        stats.build_time = start.elapsed();
+        let tree = AABBTree::from_parts(faces, nodes, order, bbox, stats, HIT_COST)?;
+
+        Some(Mesh { tree, cdf, data })
+    }
+
+    pub fn from_vertices(vertices: Vec<Vec3D>, faces: Vec<[u32; 3]>) -> Self {
+        Self::with_attributes(vertices, VertexAttributes::default(), faces)
     }
 
     pub fn from_triangles(tris: Vec<Triangle>) -> Self {
@@ -116,53 +428,685 @@ impl Mesh {
     }
 }
 
-impl Geometry for MeshTriangle {
+// Writes the values of a cache in little-endian order, counting the bytes written so far to align
+// the arrays.
+struct CacheWriter<W> {
+    w: W,
+    offset: usize,
+}
+
+impl<W: Write> CacheWriter<W> {
+    fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.w.write_all(bytes)?;
+        self.offset += bytes.len();
+        Ok(())
+    }
+
+    // Pads the cache up to the start of the next array.
+    fn align(&mut self) -> io::Result<()> {
+        let padding = (CACHE_ALIGN - self.offset % CACHE_ALIGN) % CACHE_ALIGN;
+        self.bytes(&[0; CACHE_ALIGN][..padding])
+    }
+
+    fn u32s(&mut self, values: &[u32]) -> io::Result<()> {
+        for x in values {
+            self.bytes(&x.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn u64(&mut self, x: u64) -> io::Result<()> {
+        self.bytes(&x.to_le_bytes())
+    }
+
+    fn f32s(&mut self, values: &[f32]) -> io::Result<()> {
+        for x in values {
+            self.bytes(&x.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn vectors(&mut self, values: &[Vec3D]) -> io::Result<()> {
+        for v in values {
+            self.f32s(&[v[0], v[1], v[2]])?;
+        }
+
+        Ok(())
+    }
+}
+
+// Reads values from the front of a mapped cache, every read fails if there are too few bytes left.
+struct CacheReader<'a> {
+    map: &'a Arc<Mmap>,
+    offset: usize,
+}
+
+impl<'a> CacheReader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.map.get(self.offset..self.offset.checked_add(n)?)?;
+        self.offset += n;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let x = self.take(4)?;
+        Some(u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let (lo, hi) = (self.u32()? as u64, self.u32()? as u64);
+        Some(lo | hi << 32)
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        self.u32().map(f32::from_bits)
+    }
+
+    fn vector(&mut self) -> Option<Vec3D> {
+        Some(Vec3D::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    // View of the next `n` values, which start at the next multiple of `CACHE_ALIGN`.
+    fn array<T: Plain>(&mut self, n: usize) -> Option<Storage<T>> {
+        let offset = self.offset.checked_add(CACHE_ALIGN - 1)? / CACHE_ALIGN * CACHE_ALIGN;
+        let values = Storage::map(self.map, offset, n)?;
+        self.offset = offset + n * size_of::<T>();
+        Some(values)
+    }
+}
+
+// Cumulative areas are finite and never decrease.
+fn is_valid_cdf(cdf: &[f32]) -> bool {
+    let mut prev = 0.0;
+
+    cdf.iter().all(|&x| {
+        let valid = x.is_finite() && x >= prev;
+        prev = x;
+        valid
+    })
+}
+
+fn build_tree(data: &MeshData, faces: Vec<[u32; 3]>, spatial_splits: bool) -> AABBTree<[u32; 3]> {
+    let tris = faces
+        .into_iter()
+        .map(|vertices| MeshTriangle { vertices, data })
+        .collect::<Vec<_>>();
+
+    let tree = if spatial_splits {
+        AABBTree::with_spatial_splits(tris, HIT_COST)
+    } else {
+        AABBTree::new(tris, HIT_COST)
+    };
+
+    tree.map_objects(|tri| tri.vertices)
+}
+
+// Cumulative surface area of the triangles in the order of the leaves, used to sample the surface.
+fn cumulative_areas(tree: &AABBTree<[u32; 3]>, data: &MeshData) -> Storage<f32> {
+    // Triangles referenced from several leaves share their area between the references.
+    let mut references = vec![0; tree.stats().objects];
+    for &i in tree.order() {
+        references[i as usize] += 1;
+    }
+
+    let mut total = 0.0;
+    tree.objects()
+        .iter()
+        .zip(tree.order())
+        .map(|(&face, &i)| {
+            let [a, b, c] = data.corners(face);
+            total += 0.5 * Vec3D::cross(b - a, c - a).norm() / references[i as usize] as f32;
+            total
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+#[inline(always)]
+fn interpolate<T>(data: &[T], [i, j, k]: [u32; 3], [u, v]: [f32; 2]) -> T
+where
+    T: Copy + std::ops::Mul<f32, Output = T> + std::ops::Add<Output = T>,
+{
+    let a = unsafe { *data.get_unchecked(i as usize) };
+    let b = unsafe { *data.get_unchecked(j as usize) };
+    let c = unsafe { *data.get_unchecked(k as usize) };
+    a * (1.0 - u - v) + b * u + c * v
+}
+
+impl MeshData {
     #[inline(always)]
-    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult> {
-        let data = &*self.data;
-        let [i, j, k] = self.vertices;
-        let a = unsafe { *data.get_unchecked(i as usize) };
-        let b = unsafe { *data.get_unchecked(j as usize) };
-        let c = unsafe { *data.get_unchecked(k as usize) };
-
-        let [t, u, v] = moller_trumbore([a, b, c], ray);
-
-        if t >= 0.0 && t <= t_max && u >= 0.0 && v >= 0.0 && u + v <= 1.0 {
-            let offset = data.len() / 2;
-            let na = unsafe { *data.get_unchecked(offset + i as usize) };
-            let nb = unsafe { *data.get_unchecked(offset + j as usize) };
-            let nc = unsafe { *data.get_unchecked(offset + k as usize) };
-            let norm = (1.0 - u - v) * na + u * nb + v * nc;
-
-            Some(HitResult {
-                t,
-                norm,
-                pos: ray.at(t),
-                material: &DEFAULT_MATERIAL,
-                uv: [u, v],
-            })
+    fn corners(&self, [i, j, k]: [u32; 3]) -> [Vec3D; 3] {
+        let data = &self.positions;
+        [data[i as usize], data[j as usize], data[k as usize]]
+    }
+
+    fn bounding_box(&self, face: [u32; 3]) -> AABB {
+        let [a, b, c] = self.corners(face);
+        AABB::from_point(a).union_point(b).union_point(c)
+    }
+
+    // Corners without bounds checks, the indices are validated when the mesh is created.
+    #[inline(always)]
+    fn corners_unchecked(&self, [i, j, k]: [u32; 3]) -> [Vec3D; 3] {
+        let data = &*self.positions;
+        unsafe {
+            [
+                *data.get_unchecked(i as usize),
+                *data.get_unchecked(j as usize),
+                *data.get_unchecked(k as usize),
+            ]
+        }
+    }
+
+    #[inline(always)]
+    fn hit_result(&self, face: [u32; 3], ray: &Ray, [t, u, v]: [f32; 3]) -> HitResult<'static> {
+        let [i, j, k] = face;
+        let norm = interpolate(&self.normals, face, [u, v]);
+
+        let uv = match &self.uvs {
+            Some(uvs) => {
+                let [ua, va] = uvs[i as usize];
+                let [ub, vb] = uvs[j as usize];
+                let [uc, vc] = uvs[k as usize];
+                let w = 1.0 - u - v;
+                [w * ua + u * ub + v * uc, w * va + u * vb + v * vc]
+            }
+            None => [u, v],
+        };
+
+        let color = match &self.colors {
+            Some(colors) => interpolate(colors, face, [u, v]),
+            None => COLOR_WHITE,
+        };
+
+        HitResult {
+            t,
+            norm,
+            pos: ray.at(t),
+            material: &DEFAULT_MATERIAL,
+            uv,
+            color,
+        }
+    }
+}
+
+#[inline(always)]
+fn is_inside([t, u, v]: [f32; 3], t_max: f32) -> bool {
+    t >= 0.0 && t <= t_max && u >= 0.0 && v >= 0.0 && u + v <= 1.0
+}
+
+impl Intersect<[u32; 3]> for MeshData {
+    #[inline(always)]
+    fn hit<'a>(&'a self, face: &'a [u32; 3], ray: &Ray, t_max: f32) -> Option<HitResult<'a>> {
+        let tuv = moller_trumbore(self.corners_unchecked(*face), ray);
+
+        if is_inside(tuv, t_max) {
+            Some(self.hit_result(*face, ray, tuv))
         } else {
             None
         }
     }
 
+    #[inline(always)]
+    fn is_hit(&self, face: &[u32; 3], ray: &Ray, t_max: f32) -> bool {
+        is_inside(moller_trumbore(self.corners_unchecked(*face), ray), t_max)
+    }
+
+    // The corners are only loaded once for the entire packet.
+    #[inline(always)]
+    fn hit_packet<'a>(
+        &'a self,
+        face: &'a [u32; 3],
+        rays: &RayPacket,
+        t_max: [f32; PACKET_SIZE],
+    ) -> [Option<HitResult<'a>>; PACKET_SIZE] {
+        let corners = self.corners_unchecked(*face);
+        let mut hits: [Option<HitResult>; PACKET_SIZE] = Default::default();
+
+        for ((hit, ray), &t_max) in hits.iter_mut().zip(rays).zip(&t_max) {
+            if t_max < 0.0 {
+                continue;
+            }
+
+            let tuv = moller_trumbore(corners, ray);
+
+            if is_inside(tuv, t_max) {
+                *hit = Some(self.hit_result(*face, ray, tuv));
+            }
+        }
+
+        hits
+    }
+
+    #[inline(always)]
+    fn is_hit_packet(
+        &self,
+        face: &[u32; 3],
+        rays: &RayPacket,
+        t_max: [f32; PACKET_SIZE],
+    ) -> [bool; PACKET_SIZE] {
+        let corners = self.corners_unchecked(*face);
+        let mut hits = [false; PACKET_SIZE];
+
+        for ((hit, ray), &t_max) in hits.iter_mut().zip(rays).zip(&t_max) {
+            *hit = t_max >= 0.0 && is_inside(moller_trumbore(corners, ray), t_max);
+        }
+
+        hits
+    }
+}
+
+impl Geometry for MeshTriangle<'_> {
+    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>> {
+        self.data.hit(&self.vertices, ray, t_max)
+    }
+
     fn bounding_box(&self) -> AABB {
-        let data = &*self.data;
-        let [i, j, k] = self.vertices;
-        let a = unsafe { *data.get_unchecked(i as usize) };
-        let b = unsafe { *data.get_unchecked(j as usize) };
-        let c = unsafe { *data.get_unchecked(k as usize) };
+        self.data.bounding_box(self.vertices)
+    }
+}
 
-        AABB::from_point(a).union_point(b).union_point(c)
+impl Clip for MeshTriangle<'_> {
+    // Bounds of the part of the triangle between the two planes, which consists of the corners
+    // between the planes and the points where the edges cross the planes.
+    fn clip_bounds(&self, axis: usize, min: f32, max: f32) -> AABB {
+        let corners = self.data.corners(self.vertices);
+        let mut bbox = AABB::new();
+
+        for i in 0..3 {
+            let (p, q) = (corners[i], corners[(i + 1) % 3]);
+
+            if p[axis] >= min && p[axis] <= max {
+                bbox = bbox.union_point(p);
+            }
+
+            for &plane in &[min, max] {
+                let (a, b) = (p[axis] - plane, q[axis] - plane);
+
+                if (a < 0.0) != (b < 0.0) {
+                    let mut x = p + (q - p) * (a / (a - b));
+                    x[axis] = plane;
+                    bbox = bbox.union_point(x);
+                }
+            }
+        }
+
+        bbox
     }
 }
 
 impl Geometry for Mesh {
-    delegate! {
-        target self.tree {
-            fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult>;
-            fn is_hit(&self, ray: &Ray, t_max: f32) -> bool;
-            fn bounding_box(&self) -> AABB;
+    #[inline(never)]
+    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>> {
+        self.tree.hit_with(&self.data, ray, t_max)
+    }
+
+    #[inline(never)]
+    fn is_hit(&self, ray: &Ray, t_max: f32) -> bool {
+        self.tree.is_hit_with(&self.data, ray, t_max)
+    }
+
+    #[inline(never)]
+    fn hit_packet(
+        &self,
+        rays: &RayPacket,
+        t_max: [f32; PACKET_SIZE],
+    ) -> [Option<HitResult<'_>>; PACKET_SIZE] {
+        self.tree.hit_packet_with(&self.data, rays, t_max)
+    }
+
+    #[inline(never)]
+    fn is_hit_packet(&self, rays: &RayPacket, t_max: [f32; PACKET_SIZE]) -> [bool; PACKET_SIZE] {
+        self.tree.is_hit_packet_with(&self.data, rays, t_max)
+    }
+
+    fn bounding_box(&self) -> AABB {
+        self.tree.bounds()
+    }
+}
+
+impl Surface for Mesh {
+    fn sample_surface(&self, [u, v]: [f32; 2], _: f32) -> SurfaceSample {
+        let n = self.cdf.len();
+        if n == 0 {
+            return SurfaceSample {
+                pos: Vec3D::zero(),
+                norm: Vec3D::zero(),
+                pdf: 0.0,
+            };
+        }
+
+        let total = self.cdf[n - 1];
+        let x = u * total;
+        let index = match self.cdf.binary_search_by(|c| c.partial_cmp(&x).unwrap()) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        }
+        .min(n - 1);
+
+        // `iff!` evaluates both branches, which would index before the first triangle.
+        let lo = if index > 0 { self.cdf[index - 1] } else { 0.0 };
+        let u = ((x - lo) / (self.cdf[index] - lo)).clamp(0.0, 1.0);
+
+        let face = self.tree.objects()[index];
+        let mut s = sample_triangle(self.data.corners(face), [u, v]);
+        s.pdf = 1.0 / total;
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::wide::{self, WideNode};
+    use criterion::Criterion;
+    use memmap::MmapMut;
+    use rand::prelude::*;
+    use std::time::Duration;
+
+    const KEY: u64 = 0x1234_5678_9abc_def0;
+
+    // Height field of `2 n n` triangles, crossed by `n` long and thin diagonal triangles whose
+    // bounding boxes overlap most of the hierarchy, such that spatial splits pay off.
+    fn terrain(n: usize) -> (Vec<Vec3D>, Vec<[u32; 3]>) {
+        let mut vertices = vec![];
+        let mut faces = vec![];
+
+        for j in 0..=n {
+            for i in 0..=n {
+                let (x, y) = (i as f32 / n as f32, j as f32 / n as f32);
+                let z = 0.1 * (13.0 * x).sin() * (7.0 * y).cos();
+                vertices.push(Vec3D::new(x, y, z));
+            }
+        }
+
+        let w = n as u32 + 1;
+        for j in 0..n as u32 {
+            for i in 0..n as u32 {
+                let k = j * w + i;
+                faces.push([k, k + 1, k + w + 1]);
+                faces.push([k, k + w + 1, k + w]);
+            }
+        }
+
+        for i in 0..n {
+            let (s, k) = (i as f32 / n as f32, vertices.len() as u32);
+            vertices.push(Vec3D::new(0.0, s, 0.2));
+            vertices.push(Vec3D::new(1.0, 1.0 - s, 0.2));
+            vertices.push(Vec3D::new(1.0, 1.0 - s, 0.21));
+            faces.push([k, k + 1, k + 2]);
+        }
+
+        (vertices, faces)
+    }
+
+    fn random_ray(rng: &mut SmallRng) -> Ray {
+        let pos = Vec3D::new(rng.gen_range(-1.0, 2.0), rng.gen_range(-1.0, 2.0), 1.0);
+        let target = Vec3D::new(rng.gen_range(-0.1, 1.1), rng.gen_range(-0.1, 1.1), 0.0);
+        Ray::new(pos, (target - pos).normalize())
+    }
+
+    #[test]
+    fn hierarchy_matches_linear_scan() {
+        let (vertices, faces) = terrain(32);
+        let tris = faces
+            .iter()
+            .map(|f| {
+                Triangle::new(
+                    vertices[f[0] as usize],
+                    vertices[f[1] as usize],
+                    vertices[f[2] as usize],
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let plain = Mesh::from_vertices(vertices.clone(), faces.clone());
+        let split = Mesh::with_spatial_splits(vertices, VertexAttributes::default(), faces);
+        assert_eq!(plain.stats().spatial_splits, 0);
+        assert!(split.stats().spatial_splits > 0);
+
+        let mut rng = SmallRng::seed_from_u64(22);
+        let mut hits = 0;
+
+        for _ in 0..2000 {
+            let ray = random_ray(&mut rng);
+            let expected = tris
+                .iter()
+                .filter_map(|tri| tri.hit(&ray, 1e12))
+                .map(|hit| hit.t)
+                .min_by(|a, b| a.partial_cmp(b).unwrap());
+
+            for mesh in &[&plain, &split] {
+                match (mesh.hit(&ray, 1e12).map(|h| h.t), expected) {
+                    (Some(a), Some(b)) => assert!((a - b).abs() < 1e-5, "{} != {}", a, b),
+                    (None, None) => {}
+                    (a, b) => panic!("{:?}: {:?} != {:?}", ray, a, b),
+                }
+
+                assert_eq!(mesh.is_hit(&ray, 1e12), expected.is_some());
+            }
+
+            hits += expected.is_some() as usize;
+        }
+
+        assert!(hits > 1000, "only {} rays hit", hits);
+    }
+
+    // Mesh with every kind of vertex data, whose hierarchy references some faces more than once.
+    fn cached_mesh() -> Mesh {
+        let (vertices, faces) = terrain(8);
+        let attributes = VertexAttributes {
+            normals: None,
+            uvs: Some(vertices.iter().map(|v| [v[0], v[1]]).collect()),
+            colors: Some(vertices.clone()),
+        };
+
+        let mesh = Mesh::with_spatial_splits(vertices, attributes, faces);
+        assert!(mesh.stats().spatial_splits > 0);
+        mesh
+    }
+
+    fn write(mesh: &Mesh) -> Vec<u8> {
+        let mut bytes = vec![];
+        mesh.write_cache(KEY, &mut bytes).unwrap();
+        bytes
+    }
+
+    // Maps a copy of the bytes, the same as a cache file.
+    fn map_bytes(bytes: &[u8]) -> Arc<Mmap> {
+        let mut map = MmapMut::map_anon(bytes.len()).unwrap();
+        map.copy_from_slice(bytes);
+        Arc::new(map.make_read_only().unwrap())
+    }
+
+    // Offset of a mapped array in its mapping.
+    fn offset_in<T>(map: &Mmap, values: &[T]) -> usize {
+        values.as_ptr() as usize - map.as_ptr() as usize
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let mesh = cached_mesh();
+        let cached = Mesh::read_cache(map_bytes(&write(&mesh)), KEY).unwrap();
+
+        assert!(cached.data.positions.is_mapped());
+        assert!(cached.cdf.is_mapped());
+        assert_eq!(
+            BuildStats {
+                build_time: Duration::default(),
+                ..cached.stats().clone()
+            },
+            BuildStats {
+                build_time: Duration::default(),
+                ..mesh.stats().clone()
+            }
+        );
+        assert_eq!(cached.tree.objects(), mesh.tree.objects());
+        assert_eq!(cached.tree.order(), mesh.tree.order());
+        assert_eq!(cached.tree.nodes(), mesh.tree.nodes());
+        assert_eq!(&*cached.cdf, &*mesh.cdf);
+        assert_eq!(cached.bounding_box(), mesh.bounding_box());
+
+        let mut rng = SmallRng::seed_from_u64(23);
+        for _ in 0..1000 {
+            let ray = random_ray(&mut rng);
+
+            match (cached.hit(&ray, 1e12), mesh.hit(&ray, 1e12)) {
+                (Some(a), Some(b)) => {
+                    assert_eq!(a.t, b.t);
+                    assert_eq!(a.norm, b.norm);
+                    assert_eq!(a.uv, b.uv);
+                    assert_eq!(a.color, b.color);
+                }
+                (None, None) => {}
+                _ => panic!("{:?} hits only one of the meshes", ray),
+            }
+
+            let u = [rng.gen(), rng.gen()];
+            assert_eq!(cached.sample_surface(u, 0.0), mesh.sample_surface(u, 0.0));
+        }
+    }
+
+    #[test]
+    fn cache_rejects_stale_key() {
+        let bytes = write(&cached_mesh());
+        assert!(Mesh::read_cache(map_bytes(&bytes), KEY ^ 1).is_none());
+
+        let mut old = bytes.clone();
+        old[8..12].copy_from_slice(&(CACHE_VERSION - 1).to_le_bytes());
+        assert!(Mesh::read_cache(map_bytes(&old), KEY).is_none());
+    }
+
+    #[test]
+    fn cache_rejects_corrupted_payload() {
+        let mesh = cached_mesh();
+        let bytes = write(&mesh);
+        let map = map_bytes(&bytes);
+        let cached = Mesh::read_cache(map.clone(), KEY).unwrap();
+
+        let n = mesh.data.positions.len() as u32;
+        let m = mesh.cdf.len();
+        let faces = offset_in(&map, cached.tree.objects());
+        let order = offset_in(&map, cached.tree.order());
+        let nodes = offset_in(&map, cached.tree.nodes());
+        let cdf = offset_in(&map, &cached.cdf);
+
+        // Interior node of the root which refers to the root itself.
+        let mut cycle = WideNode::empty();
+        cycle.child[0] = 0;
+        let cycle = unsafe { transmute::<WideNode, [u8; 128]>(cycle) };
+
+        let corruptions: &[(&str, usize, &[u8])] = &[
+            ("NaN in the cdf", cdf + 4 * (m / 2), &f32::NAN.to_le_bytes()),
+            ("decreasing cdf", cdf + 4 * (m / 2), &0.0f32.to_le_bytes()),
+            (
+                "infinite cdf",
+                cdf + 4 * (m - 1),
+                &f32::INFINITY.to_le_bytes(),
+            ),
+            ("vertex out of range", faces + 4, &n.to_le_bytes()),
+            ("object out of range", order, &(m as u32 * 2).to_le_bytes()),
+            (
+                "child out of range",
+                nodes + 96,
+                &(m as u32 * 2).to_le_bytes(),
+            ),
+            ("node cycle", nodes, &cycle),
+        ];
+
+        for &(name, offset, value) in corruptions {
+            let mut corrupted = bytes.clone();
+            corrupted[offset..offset + value.len()].copy_from_slice(value);
+            let cached = Mesh::read_cache(map_bytes(&corrupted), KEY);
+            assert!(cached.is_none(), "accepted a cache with {}", name);
+        }
+
+        let truncated = &bytes[..bytes.len() - 4];
+        assert!(Mesh::read_cache(map_bytes(truncated), KEY).is_none());
+
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert!(Mesh::read_cache(map_bytes(&extended), KEY).is_none());
+    }
+
+    #[test]
+    fn cost_ratio_past_threshold_rebuilds() {
+        const MAX_RATIO: f32 = 1.5;
+        let (vertices, faces) = terrain(16);
+        let mut mesh = Mesh::from_vertices(vertices.clone(), faces.clone());
+
+        // A small wave keeps the tree close to its built cost, it is only refit.
+        let wave = vertices
+            .iter()
+            .map(|&v| v + Vec3D::new(0.0, 0.0, 0.02 * (5.0 * v[0]).sin()))
+            .collect::<Vec<_>>();
+        assert!(!mesh.set_positions(wave, None, MAX_RATIO));
+        assert!(mesh.tree.cost_ratio() <= MAX_RATIO);
+        assert!(mesh.tree.cost_ratio() > 1.0);
+
+        // Shuffling the vertices stretches most triangles across the entire mesh.
+        let mut rng = SmallRng::seed_from_u64(24);
+        let mut shuffled = vertices.clone();
+        shuffled.shuffle(&mut rng);
+
+        let mut refit = Mesh::from_vertices(vertices, faces);
+        assert!(!refit.set_positions(shuffled.clone(), None, f32::INFINITY));
+        assert!(refit.tree.cost_ratio() > MAX_RATIO);
+
+        assert!(mesh.set_positions(shuffled, None, MAX_RATIO));
+        assert_eq!(mesh.tree.cost_ratio(), 1.0);
+        let cost = |mesh: &Mesh| wide::sah_cost(mesh.tree.nodes(), HIT_COST);
+        assert!(cost(&mesh) < cost(&refit));
+
+        // Both trees find the same surface.
+        for _ in 0..1000 {
+            let ray = random_ray(&mut rng);
+            let (a, b) = (mesh.hit(&ray, 1e12), refit.hit(&ray, 1e12));
+            assert_eq!(a.map(|h| h.t), b.map(|h| h.t));
+        }
+    }
+
+    // Build and traversal times on a mesh of half a million triangles, run with
+    // `cargo test --release -- --ignored --nocapture benchmark_hierarchy`.
+    #[test]
+    #[ignore]
+    fn benchmark_hierarchy() {
+        let (vertices, faces) = terrain(512);
+        let mut rng = SmallRng::seed_from_u64(22);
+        let rays = (0..1 << 16)
+            .map(|_| random_ray(&mut rng))
+            .collect::<Vec<_>>();
+
+        let mut c = Criterion::default()
+            .sample_size(10)
+            .warm_up_time(Duration::from_secs(1))
+            .measurement_time(Duration::from_secs(10));
+
+        for &spatial_splits in &[false, true] {
+            let build = || {
+                let (v, f) = (vertices.clone(), faces.clone());
+
+                if spatial_splits {
+                    Mesh::with_spatial_splits(v, VertexAttributes::default(), f)
+                } else {
+                    Mesh::from_vertices(v, f)
+                }
+            };
+            let name = iff!(spatial_splits, "spatial splits", "object splits");
+
+            c.bench_function(&format!("build, {}", name), |b| b.iter(build));
+
+            let mesh = build();
+            println!("{}: {}", name, mesh.stats());
+            c.bench_function(&format!("trace 65536 rays, {}", name), |b| {
+                b.iter(|| {
+                    rays.iter()
+                        .filter(|ray| mesh.hit(ray, 1e12).is_some())
+                        .count()
+                })
+            });
         }
     }
 }
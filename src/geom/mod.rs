@@ -3,18 +3,28 @@ mod bvh;
 mod cuboid;
 mod mesh;
 mod sphere;
+mod storage;
 mod transform;
 mod triangle;
+mod wide;
 
+// Part of the geometry API, even where the renderer itself does not use them.
+#[allow(unused_imports)]
 pub use self::aggregate::{BoundingBox, GeometryList, Object};
-pub use self::bvh::AABBTree;
+pub use self::bvh::{AABBTree, BuildStats, Clip, TreeLayout};
+#[allow(unused_imports)]
 pub use self::cuboid::{Cuboid, UnitCuboid};
-pub use self::mesh::Mesh;
+pub use self::mesh::{Mesh, VertexAttributes};
+#[allow(unused_imports)]
 pub use self::sphere::{Sphere, UnitSphere};
-pub use self::transform::{Rotate, Scale, Transform, Translate};
+#[allow(unused_imports)]
+pub use self::transform::{
+    AffineTransform, AnimatedTransform, Rotate, Scale, Transform, Translate,
+};
 pub use self::triangle::Triangle;
 use crate::material::Material;
 use crate::math::*;
+use crate::texture::Color;
 use std::ops::Deref;
 
 pub struct HitResult<'a> {
@@ -22,21 +32,78 @@ pub struct HitResult<'a> {
     pub norm: Vec3D,
     pub t: f32,
     pub uv: [f32; 2],
+    pub color: Color,
     pub material: &'a (dyn Material + 'a),
 }
 
+// Number of rays in a packet, which matches the width of the SIMD box tests of `AABBTree`.
+pub const PACKET_SIZE: usize = 4;
+
+pub type RayPacket = [Ray; PACKET_SIZE];
+
 pub trait Geometry: Send + Sync {
     fn bounding_box(&self) -> AABB;
     fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>>;
     fn is_hit(&self, ray: &Ray, t_max: f32) -> bool {
         self.hit(ray, t_max).is_some()
     }
+
+    // Traces a packet of coherent rays, for example the camera rays of neighbouring pixels. Every
+    // ray has its own `t_max`, rays with a negative `t_max` are inactive and never hit anything.
+    fn hit_packet(
+        &self,
+        rays: &RayPacket,
+        t_max: [f32; PACKET_SIZE],
+    ) -> [Option<HitResult<'_>>; PACKET_SIZE] {
+        let mut hits: [Option<HitResult>; PACKET_SIZE] = Default::default();
+
+        for i in 0..PACKET_SIZE {
+            if t_max[i] >= 0.0 {
+                hits[i] = self.hit(&rays[i], t_max[i]);
+            }
+        }
+
+        hits
+    }
+
+    fn is_hit_packet(&self, rays: &RayPacket, t_max: [f32; PACKET_SIZE]) -> [bool; PACKET_SIZE] {
+        let mut hits = [false; PACKET_SIZE];
+
+        for i in 0..PACKET_SIZE {
+            hits[i] = t_max[i] >= 0.0 && self.is_hit(&rays[i], t_max[i]);
+        }
+
+        hits
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SurfaceSample {
+    pub pos: Vec3D,
+    pub norm: Vec3D,
+    pub pdf: f32,
+}
+
+// Geometry that can be sampled uniformly by area, `pdf` is with respect to surface area. Moving
+// geometry is sampled where it is at `time`.
+pub trait Surface: Geometry {
+    fn sample_surface(&self, u: [f32; 2], time: f32) -> SurfaceSample;
+}
+
+impl<T> Surface for T
+where
+    T: Deref + Send + Sync,
+    <T as Deref>::Target: Surface,
+{
+    fn sample_surface(&self, u: [f32; 2], time: f32) -> SurfaceSample {
+        self.deref().sample_surface(u, time)
+    }
 }
 
-impl<'a, T> Geometry for T
+impl<T> Geometry for T
 where
     T: Deref + Send + Sync,
-    <T as Deref>::Target: Geometry + 'a,
+    <T as Deref>::Target: Geometry,
 {
     fn bounding_box(&self) -> AABB {
         self.deref().bounding_box()
@@ -49,4 +116,16 @@ where
     fn is_hit(&self, ray: &Ray, t_max: f32) -> bool {
         self.deref().is_hit(ray, t_max)
     }
+
+    fn hit_packet(
+        &self,
+        rays: &RayPacket,
+        t_max: [f32; PACKET_SIZE],
+    ) -> [Option<HitResult<'_>>; PACKET_SIZE] {
+        self.deref().hit_packet(rays, t_max)
+    }
+
+    fn is_hit_packet(&self, rays: &RayPacket, t_max: [f32; PACKET_SIZE]) -> [bool; PACKET_SIZE] {
+        self.deref().is_hit_packet(rays, t_max)
+    }
 }
@@ -1,6 +1,7 @@
-use crate::geom::{Geometry, HitResult, Scale, Translate};
+use crate::geom::{Geometry, HitResult, Scale, Surface, SurfaceSample, Translate};
 use crate::material::DEFAULT_MATERIAL;
 use crate::math::*;
+use crate::texture::COLOR_WHITE;
 use std::f32::consts::PI;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -33,7 +34,7 @@ fn sphere_intersect(ray: &Ray) -> Option<(f32, f32)> {
 }
 
 impl Geometry for UnitSphere {
-    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult> {
+    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>> {
         let (t0, t1) = sphere_intersect(ray)?;
 
         let t = if t0 >= 0.0 && t0 <= t_max {
@@ -55,6 +56,7 @@ impl Geometry for UnitSphere {
             norm,
             pos,
             uv: [u, v],
+            color: COLOR_WHITE,
             material: &DEFAULT_MATERIAL,
         })
     }
@@ -74,7 +76,7 @@ impl Geometry for UnitSphere {
 }
 
 impl Geometry for Sphere {
-    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult> {
+    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>> {
         self.obj.hit(ray, t_max)
     }
 
@@ -86,3 +88,24 @@ impl Geometry for Sphere {
         self.obj.bounding_box()
     }
 }
+
+impl Surface for UnitSphere {
+    fn sample_surface(&self, [u, v]: [f32; 2], _: f32) -> SurfaceSample {
+        let z = 1.0 - 2.0 * u;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * v;
+        let pos = Vec3D::new(r * phi.cos(), r * phi.sin(), z);
+
+        SurfaceSample {
+            pos,
+            norm: pos,
+            pdf: 1.0 / (4.0 * PI),
+        }
+    }
+}
+
+impl Surface for Sphere {
+    fn sample_surface(&self, u: [f32; 2], time: f32) -> SurfaceSample {
+        self.obj.sample_surface(u, time)
+    }
+}
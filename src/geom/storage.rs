@@ -0,0 +1,125 @@
+use super::wide::WideNode;
+use crate::math::Vec3D;
+use memmap::Mmap;
+use std::marker::PhantomData;
+use std::mem::{align_of, size_of};
+use std::ops::Deref;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+// Types without pointers or padding for which every bit pattern is a valid value, such that they
+// can be used in place in a mapped file. Implementing it for any other type is unsound.
+#[allow(clippy::missing_safety_doc)]
+pub(super) unsafe trait Plain: Copy + Send + Sync + 'static {}
+
+unsafe impl Plain for u32 {}
+unsafe impl Plain for f32 {}
+unsafe impl<T: Plain, const N: usize> Plain for [T; N] {}
+unsafe impl Plain for Vec3D {}
+unsafe impl Plain for WideNode {}
+
+// Array that is either owned or a view of a mapped file. Views keep the mapping alive, they are
+// copied into an owned array the first time they are modified.
+pub(super) struct Storage<T>(Repr<T>);
+
+enum Repr<T> {
+    Owned(Box<[T]>),
+    Mapped {
+        map: Arc<Mmap>,
+        offset: usize,
+        len: usize,
+        marker: PhantomData<T>,
+    },
+}
+
+impl<T: Plain> Storage<T> {
+    // View of `len` values at `offset` bytes into the mapping. Returns `None` if the values do not
+    // fit into the mapping or are not aligned for `T`.
+    pub fn map(map: &Arc<Mmap>, offset: usize, len: usize) -> Option<Self> {
+        let end = len.checked_mul(size_of::<T>())?.checked_add(offset)?;
+
+        if end > map.len() || !(map.as_ptr() as usize + offset).is_multiple_of(align_of::<T>()) {
+            return None;
+        }
+
+        Some(Storage(Repr::Mapped {
+            map: map.clone(),
+            offset,
+            len,
+            marker: PhantomData,
+        }))
+    }
+}
+
+impl<T> Storage<T> {
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.0, Repr::Mapped { .. })
+    }
+
+    pub fn to_mut(&mut self) -> &mut [T] {
+        if self.is_mapped() {
+            *self = Storage::from(self.copy_mapped());
+        }
+
+        match &mut self.0 {
+            Repr::Owned(x) => x,
+            Repr::Mapped { .. } => unreachable!(),
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<T> {
+        match self.0 {
+            Repr::Owned(x) => x.into_vec(),
+            Repr::Mapped { .. } => self.copy_mapped(),
+        }
+    }
+
+    // Views only exist for `Plain` types, which can be copied bit by bit.
+    fn copy_mapped(&self) -> Vec<T> {
+        let mut values = Vec::with_capacity(self.len());
+
+        unsafe {
+            ptr::copy_nonoverlapping(self.as_ptr(), values.as_mut_ptr(), self.len());
+            values.set_len(self.len());
+        }
+
+        values
+    }
+}
+
+impl<T> Deref for Storage<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match &self.0 {
+            Repr::Owned(x) => x,
+            // The bounds and the alignment are checked when the view is created.
+            Repr::Mapped {
+                map, offset, len, ..
+            } => unsafe { slice::from_raw_parts(map.as_ptr().add(*offset) as *const T, *len) },
+        }
+    }
+}
+
+impl<T: Clone> Clone for Storage<T> {
+    fn clone(&self) -> Self {
+        match &self.0 {
+            Repr::Owned(x) => Storage(Repr::Owned(x.clone())),
+            Repr::Mapped {
+                map, offset, len, ..
+            } => Storage(Repr::Mapped {
+                map: map.clone(),
+                offset: *offset,
+                len: *len,
+                marker: PhantomData,
+            }),
+        }
+    }
+}
+
+impl<T> From<Vec<T>> for Storage<T> {
+    fn from(values: Vec<T>) -> Self {
+        Storage(Repr::Owned(values.into_boxed_slice()))
+    }
+}
@@ -1,6 +1,5 @@
-use super::{Geometry, HitResult};
+use super::{Geometry, HitResult, RayPacket, Surface, SurfaceSample, PACKET_SIZE};
 use crate::math::*;
-use crunchy::unroll;
 
 #[derive(PartialEq, Debug, Clone)]
 pub struct Translate<T> {
@@ -26,6 +25,29 @@ pub struct Transform<T> {
     obj: Translate<Scale<Rotate<T>>>,
 }
 
+#[derive(PartialEq, Debug, Clone)]
+pub struct AffineTransform<T> {
+    obj: T,
+    fwd: Affine3D,
+    inv: Affine3D,
+}
+
+// Decomposed transformation at one end of an `AnimatedTransform`.
+#[derive(PartialEq, Debug, Copy, Clone)]
+struct Keyframe {
+    translation: Vec3D,
+    rotation: Quaternion,
+    stretch: Mat3D,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct AnimatedTransform<T> {
+    obj: T,
+    times: [f32; 2],
+    keys: [Keyframe; 2],
+    bbox: AABB,
+}
+
 impl<T: Geometry> Translate<T> {
     pub fn new(obj: T) -> Self {
         Self::with(obj, Vec3D::zero())
@@ -54,8 +76,8 @@ impl<T: Geometry> Translate<T> {
 
 impl<T: Geometry> Geometry for Translate<T> {
     #[inline(always)]
-    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult> {
-        let new_ray = Ray::new(ray.pos - self.offset, ray.dir);
+    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>> {
+        let new_ray = Ray::with_time(ray.pos - self.offset, ray.dir, ray.time);
 
         if let Some(mut h) = self.obj.hit(&new_ray, t_max) {
             h.pos += self.offset;
@@ -67,7 +89,7 @@ impl<T: Geometry> Geometry for Translate<T> {
 
     #[inline(always)]
     fn is_hit(&self, ray: &Ray, t_max: f32) -> bool {
-        let new_ray = Ray::new(ray.pos - self.offset, ray.dir);
+        let new_ray = Ray::with_time(ray.pos - self.offset, ray.dir, ray.time);
         self.obj.is_hit(&new_ray, t_max)
     }
 
@@ -99,9 +121,9 @@ impl<T: Geometry> Scale<T> {
 
 impl<T: Geometry> Geometry for Scale<T> {
     #[inline(always)]
-    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult> {
+    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>> {
         let (scale, inv_scale) = (self.scale, self.inv_scale);
-        let new_ray = Ray::new(ray.pos * inv_scale, ray.dir);
+        let new_ray = Ray::with_time(ray.pos * inv_scale, ray.dir, ray.time);
 
         if let Some(mut h) = self.obj.hit(&new_ray, t_max * inv_scale) {
             h.t *= scale;
@@ -115,7 +137,7 @@ impl<T: Geometry> Geometry for Scale<T> {
     #[inline(always)]
     fn is_hit(&self, ray: &Ray, t_max: f32) -> bool {
         let inv_scale = self.inv_scale;
-        let new_ray = Ray::new(ray.pos * inv_scale, ray.dir);
+        let new_ray = Ray::with_time(ray.pos * inv_scale, ray.dir, ray.time);
         self.obj.is_hit(&new_ray, t_max * inv_scale)
     }
 
@@ -174,10 +196,10 @@ impl<T: Geometry> Rotate<T> {
 
 impl<T: Geometry> Geometry for Rotate<T> {
     #[inline(always)]
-    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult> {
+    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>> {
         let p = self.mat.transpose_apply(ray.pos);
         let d = self.mat.transpose_apply(ray.dir);
-        let new_ray = Ray::new(p, d);
+        let new_ray = Ray::with_time(p, d, ray.time);
 
         if let Some(mut result) = self.obj.hit(&new_ray, t_max) {
             result.pos = self.mat.apply(result.pos);
@@ -192,31 +214,14 @@ impl<T: Geometry> Geometry for Rotate<T> {
     fn is_hit(&self, ray: &Ray, t_max: f32) -> bool {
         let p = self.mat.transpose_apply(ray.pos);
         let d = self.mat.transpose_apply(ray.dir);
-        let new_ray = Ray::new(p, d);
+        let new_ray = Ray::with_time(p, d, ray.time);
 
         self.obj.is_hit(&new_ray, t_max)
     }
 
     #[inline(always)]
     fn bounding_box(&self) -> AABB {
-        let old_bbox = self.obj.bounding_box();
-        let corners = [old_bbox.min, old_bbox.max];
-        let mut new_bbox = AABB::new();
-
-        unroll! {
-            for i in 0..8 {
-                let p = Vec3D::new(
-                    corners[i % 2][0],
-                    corners[(i / 2) % 2][1],
-                    corners[(i / 4) % 2][2],
-                );
-
-                let q = self.mat.apply(p);
-                new_bbox = new_bbox.union_point(q);
-            }
-        }
-
-        new_bbox
+        self.obj.bounding_box().map_corners(|p| self.mat.apply(p))
     }
 }
 
@@ -293,7 +298,7 @@ impl<T: Geometry> Transform<T> {
 
 impl<T: Geometry> Geometry for Transform<T> {
     #[inline(always)]
-    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult> {
+    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>> {
         self.obj.hit(ray, t_max)
     }
 
@@ -307,3 +312,533 @@ impl<T: Geometry> Geometry for Transform<T> {
         self.obj.bounding_box()
     }
 }
+
+impl<T: Geometry> AffineTransform<T> {
+    pub fn new(obj: T) -> Self {
+        Self::with(obj, Affine3D::identity())
+    }
+
+    pub fn with(obj: T, fwd: Affine3D) -> Self {
+        let inv = fwd.inverse().expect("transformation is not invertible");
+        Self { obj, fwd, inv }
+    }
+
+    pub fn matrix(&self) -> Affine3D {
+        self.fwd
+    }
+
+    // Applies `m` after the current transformation.
+    pub fn transform(self, m: Affine3D) -> Self {
+        let fwd = self.fwd.then(m);
+        Self::with(self.obj, fwd)
+    }
+
+    pub fn translate(self, offset: Vec3D) -> Self {
+        self.transform(Affine3D::new_translation(offset))
+    }
+
+    pub fn scale(self, factor: f32) -> Self {
+        self.scale_nonuniform(Vec3D::fill(factor))
+    }
+
+    pub fn scale_nonuniform(self, factor: Vec3D) -> Self {
+        self.transform(Affine3D::new_scaling(factor))
+    }
+
+    pub fn rotate(self, axis: Vec3D, angle: f32) -> Self {
+        self.transform(Affine3D::new_rotation(axis, angle))
+    }
+
+    pub fn rotate_x(self, angle: f32) -> Self {
+        self.rotate(Vec3D::x_axis(), angle)
+    }
+
+    pub fn rotate_y(self, angle: f32) -> Self {
+        self.rotate(Vec3D::y_axis(), angle)
+    }
+
+    pub fn rotate_z(self, angle: f32) -> Self {
+        self.rotate(Vec3D::z_axis(), angle)
+    }
+
+    pub fn reflect(self, axis: Vec3D) -> Self {
+        self.transform(Affine3D::new_reflection(axis))
+    }
+
+    #[inline(always)]
+    fn object_ray(&self, ray: &Ray) -> (Ray, f32) {
+        let p = self.inv.apply_point(ray.pos);
+        let d = self.inv.apply_vector(ray.dir);
+        let len = d.norm();
+
+        (Ray::with_time(p, d / len, ray.time), len)
+    }
+
+    // Object space rays of a packet, inactive lanes are left untouched since scaling their
+    // negative t_max by a zero length would make them active again.
+    fn object_packet(
+        &self,
+        rays: &RayPacket,
+        t_max: &mut [f32; PACKET_SIZE],
+    ) -> (RayPacket, [f32; PACKET_SIZE]) {
+        let mut new_rays = *rays;
+        let mut lens = [1.0; PACKET_SIZE];
+
+        for i in 0..PACKET_SIZE {
+            if t_max[i] < 0.0 {
+                continue;
+            }
+
+            let (new_ray, len) = self.object_ray(&rays[i]);
+            new_rays[i] = new_ray;
+            lens[i] = len;
+            t_max[i] *= len;
+        }
+
+        (new_rays, lens)
+    }
+
+    // Normals transform with the inverse transpose to remain perpendicular to the surface.
+    #[inline(always)]
+    fn world_normal(&self, n: Vec3D) -> Vec3D {
+        self.inv.mat.transpose_apply(n)
+    }
+}
+
+impl<T: Geometry> Geometry for AffineTransform<T> {
+    #[inline(always)]
+    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>> {
+        let (new_ray, len) = self.object_ray(ray);
+
+        if let Some(mut h) = self.obj.hit(&new_ray, t_max * len) {
+            h.t /= len;
+            h.pos = self.fwd.apply_point(h.pos);
+            h.norm = self.world_normal(h.norm).normalize();
+            Some(h)
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    fn is_hit(&self, ray: &Ray, t_max: f32) -> bool {
+        let (new_ray, len) = self.object_ray(ray);
+        self.obj.is_hit(&new_ray, t_max * len)
+    }
+
+    fn hit_packet(
+        &self,
+        rays: &RayPacket,
+        mut t_max: [f32; PACKET_SIZE],
+    ) -> [Option<HitResult<'_>>; PACKET_SIZE] {
+        let (new_rays, lens) = self.object_packet(rays, &mut t_max);
+        let mut hits = self.obj.hit_packet(&new_rays, t_max);
+
+        for (hit, len) in hits.iter_mut().zip(&lens) {
+            if let Some(h) = hit {
+                h.t /= len;
+                h.pos = self.fwd.apply_point(h.pos);
+                h.norm = self.world_normal(h.norm).normalize();
+            }
+        }
+
+        hits
+    }
+
+    fn is_hit_packet(
+        &self,
+        rays: &RayPacket,
+        mut t_max: [f32; PACKET_SIZE],
+    ) -> [bool; PACKET_SIZE] {
+        let (new_rays, _) = self.object_packet(rays, &mut t_max);
+        self.obj.is_hit_packet(&new_rays, t_max)
+    }
+
+    #[inline(always)]
+    fn bounding_box(&self) -> AABB {
+        self.obj
+            .bounding_box()
+            .map_corners(|p| self.fwd.apply_point(p))
+    }
+}
+
+impl Keyframe {
+    fn new(m: Affine3D) -> Self {
+        let (translation, rotation, stretch) =
+            m.decompose().expect("transformation is not invertible");
+
+        Self {
+            translation,
+            rotation,
+            stretch,
+        }
+    }
+
+    fn interpolate(&self, other: &Self, t: f32) -> Affine3D {
+        let mut stretch = self.stretch;
+
+        for i in 0..3 {
+            for j in 0..3 {
+                stretch[[i, j]] += t * (other.stretch[[i, j]] - self.stretch[[i, j]]);
+            }
+        }
+
+        Affine3D::compose(
+            self.translation + t * (other.translation - self.translation),
+            self.rotation.slerp(&other.rotation, t),
+            stretch,
+        )
+    }
+}
+
+// Number of steps at which the motion is sampled to compute the bounding box.
+const MOTION_BOUNDS_STEPS: usize = 64;
+
+impl<T: Geometry> AnimatedTransform<T> {
+    // Moves the object from `start` at time 0 to `end` at time 1. Translation and stretch are
+    // interpolated linearly, rotation is interpolated using quaternion slerp.
+    pub fn new(obj: T, start: Affine3D, end: Affine3D) -> Self {
+        let keys = [Keyframe::new(start), Keyframe::new(end)];
+        let bbox = Self::motion_bounds(&obj, &keys);
+
+        Self {
+            obj,
+            times: [0.0, 1.0],
+            keys,
+            bbox,
+        }
+    }
+
+    // Sets the moments at which the object is at the start and end of its motion, outside of
+    // this interval the object stands still.
+    pub fn times(mut self, start: f32, end: f32) -> Self {
+        self.times = [start, end];
+        self
+    }
+
+    pub fn matrix_at(&self, time: f32) -> Affine3D {
+        let [t0, t1] = self.times;
+        let t = iff!(t1 > t0, ((time - t0) / (t1 - t0)).clamp(0.0, 1.0), 0.0);
+
+        self.keys[0].interpolate(&self.keys[1], t)
+    }
+
+    // Union of the bounding boxes along the motion. Corners move along curves between the
+    // steps, so the box is padded by half the largest distance a corner moves within one step.
+    fn motion_bounds(obj: &T, keys: &[Keyframe; 2]) -> AABB {
+        let bbox = obj.bounding_box();
+        let corners = (0..8)
+            .map(|i| {
+                let pick = |bit: usize, axis: usize| iff!(i & bit == 0, bbox.min, bbox.max)[axis];
+                Vec3D::new(pick(1, 0), pick(2, 1), pick(4, 2))
+            })
+            .collect::<Vec<_>>();
+
+        let mut result = AABB::new();
+        let mut prev = corners.clone();
+        let mut pad = 0.0f32;
+
+        for step in 0..=MOTION_BOUNDS_STEPS {
+            let m = keys[0].interpolate(&keys[1], step as f32 / MOTION_BOUNDS_STEPS as f32);
+
+            for (p, q) in corners.iter().zip(&mut prev) {
+                let p = m.apply_point(*p);
+                result = result.union_point(p);
+
+                if step > 0 {
+                    pad = pad.max((p - *q).norm() / 2.0);
+                }
+
+                *q = p;
+            }
+        }
+
+        let pad = Vec3D::fill(pad);
+        AABB::from_min_max(result.min - pad, result.max + pad)
+    }
+}
+
+impl<T: Geometry> AnimatedTransform<T> {
+    // Ray in object space at the time of the ray, together with the length of its direction and
+    // the forward and inverse transformations at that time.
+    #[inline(always)]
+    fn object_ray(&self, ray: &Ray) -> Option<(Ray, f32, Affine3D, Affine3D)> {
+        let fwd = self.matrix_at(ray.time);
+        let inv = fwd.inverse()?;
+        let d = inv.apply_vector(ray.dir);
+        let len = d.norm();
+        let new_ray = Ray::with_time(inv.apply_point(ray.pos), d / len, ray.time);
+
+        Some((new_ray, len, fwd, inv))
+    }
+
+    // Object space rays of a packet, rays at a time where the transformation is not invertible
+    // become inactive.
+    fn object_packet(
+        &self,
+        rays: &RayPacket,
+        t_max: &mut [f32; PACKET_SIZE],
+    ) -> (RayPacket, [(f32, Affine3D, Affine3D); PACKET_SIZE]) {
+        let mut new_rays = *rays;
+        let mut transforms = [(1.0, Affine3D::identity(), Affine3D::identity()); PACKET_SIZE];
+
+        for i in 0..PACKET_SIZE {
+            if t_max[i] < 0.0 {
+                continue;
+            }
+
+            match self.object_ray(&rays[i]) {
+                Some((new_ray, len, fwd, inv)) => {
+                    new_rays[i] = new_ray;
+                    transforms[i] = (len, fwd, inv);
+                    t_max[i] *= len;
+                }
+                None => t_max[i] = -1.0,
+            }
+        }
+
+        (new_rays, transforms)
+    }
+}
+
+impl<T: Geometry> Geometry for AnimatedTransform<T> {
+    #[inline(always)]
+    fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitResult<'_>> {
+        let (new_ray, len, fwd, inv) = self.object_ray(ray)?;
+
+        if let Some(mut h) = self.obj.hit(&new_ray, t_max * len) {
+            h.t /= len;
+            h.pos = fwd.apply_point(h.pos);
+            h.norm = inv.mat.transpose_apply(h.norm).normalize();
+            Some(h)
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    fn is_hit(&self, ray: &Ray, t_max: f32) -> bool {
+        match self.object_ray(ray) {
+            Some((new_ray, len, _, _)) => self.obj.is_hit(&new_ray, t_max * len),
+            None => false,
+        }
+    }
+
+    fn hit_packet(
+        &self,
+        rays: &RayPacket,
+        mut t_max: [f32; PACKET_SIZE],
+    ) -> [Option<HitResult<'_>>; PACKET_SIZE] {
+        let (new_rays, transforms) = self.object_packet(rays, &mut t_max);
+        let mut hits = self.obj.hit_packet(&new_rays, t_max);
+
+        for (hit, (len, fwd, inv)) in hits.iter_mut().zip(&transforms) {
+            if let Some(h) = hit {
+                h.t /= len;
+                h.pos = fwd.apply_point(h.pos);
+                h.norm = inv.mat.transpose_apply(h.norm).normalize();
+            }
+        }
+
+        hits
+    }
+
+    fn is_hit_packet(
+        &self,
+        rays: &RayPacket,
+        mut t_max: [f32; PACKET_SIZE],
+    ) -> [bool; PACKET_SIZE] {
+        let (new_rays, _) = self.object_packet(rays, &mut t_max);
+        self.obj.is_hit_packet(&new_rays, t_max)
+    }
+
+    #[inline(always)]
+    fn bounding_box(&self) -> AABB {
+        self.bbox
+    }
+}
+
+// Emitters are sampled where they are at the time of the ray, at times where the transformation
+// is not invertible the sample has a zero density.
+impl<T: Surface> Surface for AnimatedTransform<T> {
+    fn sample_surface(&self, u: [f32; 2], time: f32) -> SurfaceSample {
+        let fwd = self.matrix_at(time);
+        let mut s = self.obj.sample_surface(u, time);
+        let inv = match fwd.inverse() {
+            Some(inv) => inv,
+            None => return SurfaceSample { pdf: 0.0, ..s },
+        };
+
+        let m = inv.mat.transpose_apply(s.norm.normalize());
+
+        s.pos = fwd.apply_point(s.pos);
+        s.norm = m.normalize();
+        s.pdf /= fwd.det().abs() * m.norm();
+        s
+    }
+}
+
+impl<T: Surface> Surface for AffineTransform<T> {
+    fn sample_surface(&self, u: [f32; 2], time: f32) -> SurfaceSample {
+        let mut s = self.obj.sample_surface(u, time);
+        let n = s.norm.normalize();
+        let m = self.world_normal(n);
+
+        // A surface element with unit normal `n` is scaled by `|det| * |M^-T n|`.
+        s.pos = self.fwd.apply_point(s.pos);
+        s.norm = m.normalize();
+        s.pdf /= self.fwd.det().abs() * m.norm();
+        s
+    }
+}
+
+impl<T: Surface> Surface for Translate<T> {
+    fn sample_surface(&self, u: [f32; 2], time: f32) -> SurfaceSample {
+        let mut s = self.obj.sample_surface(u, time);
+        s.pos += self.offset;
+        s
+    }
+}
+
+impl<T: Surface> Surface for Scale<T> {
+    fn sample_surface(&self, u: [f32; 2], time: f32) -> SurfaceSample {
+        let mut s = self.obj.sample_surface(u, time);
+        s.pos *= self.scale;
+        s.pdf *= self.inv_scale * self.inv_scale;
+        s
+    }
+}
+
+impl<T: Surface> Surface for Rotate<T> {
+    fn sample_surface(&self, u: [f32; 2], time: f32) -> SurfaceSample {
+        let mut s = self.obj.sample_surface(u, time);
+        s.pos = self.mat.apply(s.pos);
+        s.norm = self.mat.apply(s.norm);
+        s
+    }
+}
+
+impl<T: Surface> Surface for Transform<T> {
+    fn sample_surface(&self, u: [f32; 2], time: f32) -> SurfaceSample {
+        self.obj.sample_surface(u, time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::Mesh;
+
+    fn grid() -> Mesh {
+        let n = 8;
+        let mut vertices = vec![];
+        let mut faces = vec![];
+
+        for y in 0..=n {
+            for x in 0..=n {
+                vertices.push(Vec3D::new(x as f32, y as f32, 0.0) / n as f32);
+            }
+        }
+
+        for y in 0..n {
+            for x in 0..n {
+                let i = y * (n + 1) + x;
+                faces.push([i, i + 1, i + n + 2]);
+                faces.push([i, i + n + 2, i + n + 1]);
+            }
+        }
+
+        Mesh::from_vertices(vertices, faces)
+    }
+
+    #[test]
+    fn packets_match_single_rays() {
+        let geom = AffineTransform::new(grid())
+            .scale_nonuniform(Vec3D::new(4.0, 2.0, 1.0))
+            .rotate_y(0.3)
+            .translate(Vec3D::new(-1.0, -1.0, 3.0));
+
+        let ray = |x: f32, y: f32| Ray::new(Vec3D::zero(), Vec3D::new(x, y, 1.0).normalize());
+        let dummy = Ray::new(Vec3D::zero(), Vec3D::zero());
+        let cases = [
+            (
+                [ray(0.0, 0.0), ray(0.1, 0.0), ray(0.0, 0.1), ray(0.1, 0.1)],
+                [1e12; 4],
+            ),
+            (
+                [ray(0.0, 0.0), dummy, ray(0.2, -0.1), dummy],
+                [1e12, -1.0, 1e12, -1.0],
+            ),
+            (
+                [ray(0.05, 0.0), ray(0.3, 0.2), ray(5.0, 5.0), dummy],
+                [1e12, -1.0, 2.0, -1.0],
+            ),
+            (
+                [dummy, dummy, dummy, ray(0.1, 0.2)],
+                [-1.0, -1.0, -1.0, 1e12],
+            ),
+        ];
+
+        for (rays, t_max) in &cases {
+            let hits = geom.hit_packet(rays, *t_max);
+            let occluded = geom.is_hit_packet(rays, *t_max);
+
+            for i in 0..PACKET_SIZE {
+                let expected = iff!(t_max[i] >= 0.0, geom.hit(&rays[i], t_max[i]), None);
+
+                match (&hits[i], &expected) {
+                    (Some(a), Some(b)) => {
+                        assert!((a.t - b.t).abs() < 1e-5, "{} != {}", a.t, b.t);
+                        assert!((a.pos - b.pos).norm() < 1e-5);
+                        assert!((a.norm - b.norm).norm() < 1e-5);
+                    }
+                    (None, None) => {}
+                    _ => panic!("lane {} of {:?}: packet and single ray disagree", i, t_max),
+                }
+
+                assert_eq!(occluded[i], expected.is_some());
+            }
+        }
+    }
+
+    fn contains(bbox: &AABB, p: Vec3D) -> bool {
+        (0..3).all(|i| bbox.min[i] <= p[i] && p[i] <= bbox.max[i])
+    }
+
+    #[test]
+    fn motion_bounds_enclose_the_motion() {
+        // A large rotation, such that the corners move along arcs far outside the boxes at both
+        // ends of the motion.
+        let angle = 170f32.to_radians();
+        let end = Affine3D::new_rotation(Vec3D::z_axis(), angle)
+            .then(Affine3D::new_translation(Vec3D::new(2.0, 0.0, 1.0)));
+        let geom = AnimatedTransform::new(grid(), Affine3D::identity(), end).times(1.0, 3.0);
+        let bbox = geom.bounding_box();
+
+        let corners = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0], [1.0, 1.0]];
+        let corners = corners.map(|[x, y]| Vec3D::new(x, y, 0.0));
+
+        // Rotation is interpolated using slerp, so halfway the object is rotated by half the angle.
+        let halfway = Affine3D::new_rotation(Vec3D::z_axis(), angle / 2.0)
+            .then(Affine3D::new_translation(Vec3D::new(1.0, 0.0, 0.5)));
+
+        for (time, m) in [(1.0, Affine3D::identity()), (2.0, halfway), (3.0, end)] {
+            for &p in &corners {
+                let expected = m.apply_point(p);
+                let actual = geom.matrix_at(time).apply_point(p);
+
+                let error = (actual - expected).norm();
+                assert!(error < 1e-5, "time {}: {:?}", time, actual);
+                assert!(contains(&bbox, expected), "time {}: {:?}", time, expected);
+            }
+        }
+
+        for step in 0..=1000 {
+            let m = geom.matrix_at(1.0 + step as f32 / 500.0);
+
+            for &p in &corners {
+                assert!(contains(&bbox, m.apply_point(p)), "step {}", step);
+            }
+        }
+    }
+}
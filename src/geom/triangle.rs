@@ -1,6 +1,7 @@
-use super::{Geometry, HitResult};
+use super::{Geometry, HitResult, Surface, SurfaceSample};
 use crate::material::DEFAULT_MATERIAL;
 use crate::math::*;
+use crate::texture::COLOR_WHITE;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Triangle {
@@ -49,6 +50,7 @@ impl Geometry for Triangle {
                 norm,
                 pos: ray.at(t),
                 uv: [u, v],
+                color: COLOR_WHITE,
                 material: &DEFAULT_MATERIAL,
             })
         } else {
@@ -67,3 +69,23 @@ impl Geometry for Triangle {
             .union_point(self.c)
     }
 }
+
+#[inline(always)]
+pub fn sample_triangle([a, b, c]: [Vec3D; 3], [u, v]: [f32; 2]) -> SurfaceSample {
+    let su = u.sqrt();
+    let (s, t) = (1.0 - su, v * su);
+    let norm = Vec3D::cross(b - a, c - a);
+    let area = 0.5 * norm.norm();
+
+    SurfaceSample {
+        pos: a * (1.0 - s - t) + b * s + c * t,
+        norm: norm / (2.0 * area),
+        pdf: 1.0 / area,
+    }
+}
+
+impl Surface for Triangle {
+    fn sample_surface(&self, u: [f32; 2], _: f32) -> SurfaceSample {
+        sample_triangle([self.a, self.b, self.c], u)
+    }
+}
@@ -0,0 +1,345 @@
+use crate::math::*;
+use std::f32;
+
+pub const WIDTH: usize = 4;
+pub const EMPTY: u32 = !0;
+
+// Node of a 4-wide hierarchy. The bounds are stored per axis so that the four children can be
+// tested at once. A child is a leaf if `count` is nonzero, `child` is then the index of its first
+// object. Unused slots have `child` set to `EMPTY` and inverted bounds, which are never hit.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C, align(16))]
+pub struct WideNode {
+    pub min: [[f32; WIDTH]; 3],
+    pub max: [[f32; WIDTH]; 3],
+    pub child: [u32; WIDTH],
+    pub count: [u32; WIDTH],
+}
+
+// Ray data shared by all node tests along a traversal.
+pub struct WideRay {
+    pub pos: Vec3D,
+    pub inv_dir: Vec3D,
+    pub neg_dir: [bool; 3],
+}
+
+impl WideRay {
+    pub fn new(ray: &Ray) -> Self {
+        let inv_dir = 1.0 / ray.dir;
+
+        Self {
+            pos: ray.pos,
+            inv_dir,
+            neg_dir: [
+                inv_dir[0].is_sign_negative(),
+                inv_dir[1].is_sign_negative(),
+                inv_dir[2].is_sign_negative(),
+            ],
+        }
+    }
+}
+
+// Ray data of a packet of four rays, stored per axis such that every lane holds one ray. The
+// signs of the direction are stored as lane masks.
+#[repr(C, align(16))]
+pub struct WidePacket {
+    pub pos: [[f32; WIDTH]; 3],
+    pub inv_dir: [[f32; WIDTH]; 3],
+    pub neg_dir: [[u32; WIDTH]; 3],
+}
+
+impl WidePacket {
+    pub fn new(rays: &[Ray; WIDTH]) -> Self {
+        let mut packet = Self {
+            pos: [[0.0; WIDTH]; 3],
+            inv_dir: [[0.0; WIDTH]; 3],
+            neg_dir: [[0; WIDTH]; 3],
+        };
+
+        for (lane, ray) in rays.iter().enumerate() {
+            let inv_dir = 1.0 / ray.dir;
+
+            for axis in 0..3 {
+                packet.pos[axis][lane] = ray.pos[axis];
+                packet.inv_dir[axis][lane] = inv_dir[axis];
+                packet.neg_dir[axis][lane] = iff!(inv_dir[axis].is_sign_negative(), !0, 0);
+            }
+        }
+
+        packet
+    }
+
+    pub fn lane(&self, lane: usize) -> WideRay {
+        WideRay {
+            pos: Vec3D::from_map(|axis| self.pos[axis][lane]),
+            inv_dir: Vec3D::from_map(|axis| self.inv_dir[axis][lane]),
+            neg_dir: [0, 1, 2].map(|axis| self.neg_dir[axis][lane] != 0),
+        }
+    }
+}
+
+impl WideNode {
+    pub fn empty() -> Self {
+        Self {
+            min: [[f32::INFINITY; WIDTH]; 3],
+            max: [[-f32::INFINITY; WIDTH]; 3],
+            child: [EMPTY; WIDTH],
+            count: [0; WIDTH],
+        }
+    }
+
+    pub fn set_bounds(&mut self, slot: usize, bbox: AABB) {
+        for axis in 0..3 {
+            self.min[axis][slot] = bbox.min[axis];
+            self.max[axis][slot] = bbox.max[axis];
+        }
+    }
+
+    pub fn bounds(&self, slot: usize) -> AABB {
+        AABB {
+            min: Vec3D::from_map(|axis| self.min[axis][slot]),
+            max: Vec3D::from_map(|axis| self.max[axis][slot]),
+        }
+    }
+
+    // Near and far planes for every axis, depending on the direction of the ray.
+    #[inline(always)]
+    fn planes(&self, ray: &WideRay, axis: usize) -> (&[f32; WIDTH], &[f32; WIDTH]) {
+        if ray.neg_dir[axis] {
+            (&self.max[axis], &self.min[axis])
+        } else {
+            (&self.min[axis], &self.max[axis])
+        }
+    }
+
+    // Tests the ray against the four children, returns a bit mask of the children which are hit
+    // and the entry distance for each child. Slabs which produce NaN do not cull anything.
+    #[cfg(target_arch = "x86_64")]
+    #[inline(always)]
+    pub fn intersect(&self, ray: &WideRay, t_max: f32) -> (u32, [f32; WIDTH]) {
+        use std::arch::x86_64::*;
+
+        // SSE is part of the x86_64 baseline and the bounds are aligned to 16 bytes.
+        unsafe {
+            let mut t_near = _mm_set1_ps(-0.01);
+            let mut t_far = _mm_set1_ps(t_max + 0.01);
+
+            for axis in 0..3 {
+                let (near, far) = self.planes(ray, axis);
+                let pos = _mm_set1_ps(ray.pos[axis]);
+                let inv_dir = _mm_set1_ps(ray.inv_dir[axis]);
+
+                let a = _mm_mul_ps(_mm_sub_ps(_mm_load_ps(near.as_ptr()), pos), inv_dir);
+                let b = _mm_mul_ps(_mm_sub_ps(_mm_load_ps(far.as_ptr()), pos), inv_dir);
+                t_near = _mm_max_ps(a, t_near);
+                t_far = _mm_min_ps(b, t_far);
+            }
+
+            let mask = _mm_movemask_ps(_mm_cmple_ps(t_near, t_far)) as u32;
+            let mut dist = [0.0; WIDTH];
+            _mm_storeu_ps(dist.as_mut_ptr(), t_near);
+
+            (mask, dist)
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    #[inline(always)]
+    pub fn intersect(&self, ray: &WideRay, t_max: f32) -> (u32, [f32; WIDTH]) {
+        let mut t_near = [-0.01f32; WIDTH];
+        let mut t_far = [t_max + 0.01; WIDTH];
+
+        for axis in 0..3 {
+            let (near, far) = self.planes(ray, axis);
+
+            for i in 0..WIDTH {
+                let a = (near[i] - ray.pos[axis]) * ray.inv_dir[axis];
+                let b = (far[i] - ray.pos[axis]) * ray.inv_dir[axis];
+                t_near[i] = t_near[i].max(a);
+                t_far[i] = t_far[i].min(b);
+            }
+        }
+
+        let mut mask = 0;
+        for i in 0..WIDTH {
+            mask |= ((t_near[i] <= t_far[i]) as u32) << i;
+        }
+
+        (mask, t_near)
+    }
+
+    // Tests the four rays of a packet against every child, the rays are processed in parallel
+    // instead of the children. Returns the mask of the rays which hit each child and their entry
+    // distances. For every ray the result is the same as `intersect` and rays with a `t_max` of
+    // minus infinity never hit.
+    #[cfg(target_arch = "x86_64")]
+    #[inline(always)]
+    pub fn intersect_packet(
+        &self,
+        packet: &WidePacket,
+        t_max: [f32; WIDTH],
+    ) -> ([u32; WIDTH], [[f32; WIDTH]; WIDTH]) {
+        use std::arch::x86_64::*;
+
+        let mut masks = [0; WIDTH];
+        let mut dists = [[0.0; WIDTH]; WIDTH];
+
+        unsafe {
+            let t_max = _mm_add_ps(_mm_loadu_ps(t_max.as_ptr()), _mm_set1_ps(0.01));
+            // Load through references, a copy of a row is not guaranteed to be aligned.
+            let pos = packet.pos.each_ref().map(|p| _mm_load_ps(p.as_ptr()));
+            let inv_dir = packet.inv_dir.each_ref().map(|d| _mm_load_ps(d.as_ptr()));
+            let neg_dir = packet
+                .neg_dir
+                .each_ref()
+                .map(|n| _mm_load_ps(n.as_ptr() as *const f32));
+
+            for slot in 0..WIDTH {
+                let mut t_near = _mm_set1_ps(-0.01);
+                let mut t_far = t_max;
+
+                for axis in 0..3 {
+                    let min = _mm_set1_ps(self.min[axis][slot]);
+                    let max = _mm_set1_ps(self.max[axis][slot]);
+                    let neg = neg_dir[axis];
+                    let near = _mm_or_ps(_mm_and_ps(neg, max), _mm_andnot_ps(neg, min));
+                    let far = _mm_or_ps(_mm_and_ps(neg, min), _mm_andnot_ps(neg, max));
+
+                    let a = _mm_mul_ps(_mm_sub_ps(near, pos[axis]), inv_dir[axis]);
+                    let b = _mm_mul_ps(_mm_sub_ps(far, pos[axis]), inv_dir[axis]);
+                    t_near = _mm_max_ps(a, t_near);
+                    t_far = _mm_min_ps(b, t_far);
+                }
+
+                masks[slot] = _mm_movemask_ps(_mm_cmple_ps(t_near, t_far)) as u32;
+                _mm_storeu_ps(dists[slot].as_mut_ptr(), t_near);
+            }
+        }
+
+        (masks, dists)
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    #[inline(always)]
+    pub fn intersect_packet(
+        &self,
+        packet: &WidePacket,
+        t_max: [f32; WIDTH],
+    ) -> ([u32; WIDTH], [[f32; WIDTH]; WIDTH]) {
+        let mut masks = [0; WIDTH];
+        let mut dists = [[0.0; WIDTH]; WIDTH];
+
+        for (lane, &t_max) in t_max.iter().enumerate() {
+            let (mask, t_near) = self.intersect(&packet.lane(lane), t_max);
+
+            for slot in 0..WIDTH {
+                masks[slot] |= ((mask >> slot) & 1) << lane;
+                dists[slot][lane] = t_near[slot];
+            }
+        }
+
+        (masks, dists)
+    }
+}
+
+// SAH cost of a wide hierarchy relative to the surface area of its root. Unlike the cost during
+// construction this also holds after the bounds have been refit.
+pub fn sah_cost(nodes: &[WideNode], hit_cost: f32) -> f32 {
+    let root = (0..WIDTH).fold(AABB::new(), |b, slot| b.union(nodes[0].bounds(slot)));
+    let root_area = root.surface_area();
+    let mut cost = 1.0;
+
+    for node in nodes {
+        for slot in 0..WIDTH {
+            if node.child[slot] == EMPTY {
+                continue;
+            }
+
+            let area = node.bounds(slot).surface_area();
+            let area = iff!(root_area > 0.0 && area.is_finite(), area / root_area, 1.0);
+            let count = node.count[slot];
+            cost += area * iff!(count > 0, hit_cost * count as f32, 1.0);
+        }
+    }
+
+    cost
+}
+
+// Collapses a binary hierarchy in the skip-pointer layout of `AABBTree` into a 4-wide
+// hierarchy. Every wide node takes the children of a binary node and keeps opening the internal
+// child with the largest surface area until all four slots are filled.
+pub fn collapse(binary: &[(AABB, u32, u32)]) -> Vec<WideNode> {
+    let mut nodes = vec![];
+
+    // Trees without objects consist of a single empty leaf.
+    if binary[binary.len() - 1].2 == 0 {
+        nodes.push(WideNode::empty());
+    } else if is_leaf(binary, 0) {
+        let mut root = WideNode::empty();
+        fill_slot(binary, &mut nodes, &mut root, 0, 0);
+        nodes.push(root);
+    } else {
+        collapse_node(binary, &mut nodes, 0);
+    }
+
+    nodes
+}
+
+fn is_leaf(binary: &[(AABB, u32, u32)], i: usize) -> bool {
+    binary[i].1 as usize == i + 1
+}
+
+fn collapse_node(binary: &[(AABB, u32, u32)], nodes: &mut Vec<WideNode>, i: usize) -> u32 {
+    let mut slots = vec![i + 1, binary[i + 1].1 as usize];
+
+    while slots.len() < WIDTH {
+        let best = slots
+            .iter()
+            .enumerate()
+            .filter(|&(_, &j)| !is_leaf(binary, j))
+            .max_by(|a, b| {
+                let (x, y) = (binary[*a.1].0, binary[*b.1].0);
+                x.surface_area().total_cmp(&y.surface_area())
+            })
+            .map(|(k, _)| k);
+
+        match best {
+            Some(k) => {
+                let j = slots[k];
+                slots[k] = j + 1;
+                slots.insert(k + 1, binary[j + 1].1 as usize);
+            }
+            None => break,
+        }
+    }
+
+    // Nodes are stored in depth-first order, children directly after their parent.
+    let index = nodes.len();
+    nodes.push(WideNode::empty());
+
+    let mut node = WideNode::empty();
+    for (slot, &j) in slots.iter().enumerate() {
+        fill_slot(binary, nodes, &mut node, slot, j);
+    }
+
+    nodes[index] = node;
+    index as u32
+}
+
+fn fill_slot(
+    binary: &[(AABB, u32, u32)],
+    nodes: &mut Vec<WideNode>,
+    node: &mut WideNode,
+    slot: usize,
+    i: usize,
+) {
+    node.set_bounds(slot, binary[i].0);
+
+    if is_leaf(binary, i) {
+        let (begin, end) = (binary[i].2, binary[i + 1].2);
+        node.child[slot] = begin;
+        node.count[slot] = end - begin;
+    } else {
+        node.child[slot] = collapse_node(binary, nodes, i);
+    }
+}
@@ -1,21 +1,41 @@
+use crate::geom::{HitResult, RayPacket, PACKET_SIZE};
 use crate::light::Light;
+use crate::material::BsdfFlags;
 use crate::math::*;
+use crate::sampler::Sampler;
 use crate::scene::Scene;
 use crate::texture::Color;
-use crate::material::Material;
-use rand::prelude::*;
 use std::f32;
 
-use std::sync::{atomic::{Ordering::SeqCst, AtomicUsize}, Arc};
+pub trait Integrator: Send + Sync {
+    fn radiance(&self, scene: &Scene, ray: &Ray, sampler: &mut dyn Sampler) -> Color;
+
+    // Radiance for a packet of camera rays of neighbouring pixels, each with its own sampler.
+    // Inactive rays are ignored and return black.
+    fn radiance_packet(
+        &self,
+        scene: &Scene,
+        rays: &RayPacket,
+        active: [bool; PACKET_SIZE],
+        samplers: &mut [Box<dyn Sampler>; PACKET_SIZE],
+    ) -> [Color; PACKET_SIZE] {
+        let mut colors = [Color::zero(); PACKET_SIZE];
+
+        for (lane, sampler) in samplers.iter_mut().enumerate() {
+            if active[lane] {
+                colors[lane] = self.radiance(scene, &rays[lane], &mut **sampler);
+            }
+        }
+
+        colors
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct WhittedIntegrator {
     pub max_depth: i32,
     pub shadow_rays: i32,
     pub scatter_rays: i32,
-    pub antialiasing: i32,
-    pub gamma: f32,
-    pub dd: Arc<AtomicUsize>,
 }
 
 impl WhittedIntegrator {
@@ -24,81 +44,78 @@ impl WhittedIntegrator {
             max_depth: 10,
             shadow_rays: 5,
             scatter_rays: 1,
-            antialiasing: 1,
-            gamma: 2.2,
-            dd: Arc::new(AtomicUsize::new(0)),
         }
     }
 
-    pub fn calculate_pixel(&self, scene: &Scene, cx: usize, cy: usize) -> Color {
-        let n = self.antialiasing;
-        let mut pixels = vec![];
-        let mut rng = SmallRng::seed_from_u64((cx.to_le() ^ cy.to_be()) as u64);
-        self.dd.store(0, SeqCst);
-        
-        for i in 0..n {
-            for j in 0..n {
-                let x = (cx as f32) + (i as f32 + 0.5) / n as f32 - 0.5;
-                let y = (cy as f32) + (j as f32 + 0.5) / n as f32 - 0.5;
-
-                let ray = scene.camera.generate_ray(x, y);
-                let pixel = self.integrate_recur(scene, &ray, 0, &mut rng);
-                pixels.push(pixel);
-            }
-        }
-
-        let len = pixels.len() as f32;
-        pixels
-            .into_iter()
-            .fold(Vec3D::zero(), |a, b| a + b) / len
-
-        //Color::fill(self.dd.load(SeqCst) as f32 / 1000.0)
-    }
-
-    fn integrate_recur(&self, scene: &Scene, ray: &Ray, depth: i32, rng: &mut SmallRng) -> Color {
+    // `specular` is set for camera rays and rays after a delta bounce, see `shade`.
+    fn integrate_recur(
+        &self,
+        scene: &Scene,
+        ray: &Ray,
+        depth: i32,
+        specular: bool,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
         if depth >= self.max_depth {
             return scene.calculate_background(ray);
         }
-        //self.dd.fetch_add(1, SeqCst);
-
-        let hit = match scene.root.hit(ray, 1e12) {
-            Some(x) => x,
-            None => return scene.calculate_background(ray),
-        };
 
+        match scene.root.hit(ray, 1e12) {
+            Some(hit) => self.shade(scene, ray, &hit, depth, specular, sampler),
+            None => scene.calculate_background(ray),
+        }
+    }
 
+    fn shade(
+        &self,
+        scene: &Scene,
+        ray: &Ray,
+        hit: &HitResult,
+        depth: i32,
+        specular: bool,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
         let mut color = Color::zero();
-        let [u, v] = hit.uv;
         let n = hit.norm.normalize();
-        let outside = Vec3D::dot(n, -ray.dir) > 0.0;
+        let frame = Frame::new(n);
+        let wo = frame.to_local(-ray.dir);
         let p_out = hit.pos + n * 0.001;
         let p_in = hit.pos - n * 0.001;
-        let p = iff!(outside, p_out, p_in);
 
-        let diffuse = hit.material.sample_at(u, v);
+        let material = hit.material;
+        let flags = material.flags();
 
-        if !diffuse.is_zero() {
-            let mut illumination = Vec3D::zero();
+        // Light sampling below covers the diffuse and glossy lobes, so emission reached through
+        // a glossy bounce would be counted twice.
+        if specular {
+            color += material.emission(hit, wo);
+        }
 
+        if flags.has_non_delta() {
             for light in &scene.lights {
-                illumination += self.illumination(scene, &**light, p, n, rng);
+                color += self.illumination(scene, &**light, ray, hit, frame, sampler);
             }
-
-            color += diffuse * illumination;
         }
 
+        // Indirect light is only followed along the glossy and specular lobes.
+        let lobes = BsdfFlags::GLOSSY | BsdfFlags::SPECULAR;
+
         for _ in 0..self.scatter_rays {
-            let scatter = hit.material.scatter(n, ray.dir, rng);
+            let sample = match material.sample_lobes(hit, wo, sampler.next_2d(), lobes) {
+                Some(s) if s.pdf > 0.0 && !s.f.is_zero() => s,
+                _ => continue,
+            };
 
-            if let Some((out, scatter)) = scatter {
-                let p = iff!(Vec3D::dot(out, n) > 0.0, p_out, p_in);
+            let out = frame.to_world(sample.wi);
+            let p = iff!(sample.wi[2] > 0.0, p_out, p_in);
+            let weight = sample.f * sample.wi[2].abs() / sample.pdf;
 
-                color += scatter * self.integrate_recur(
-                    scene,
-                    &Ray::new(p, out),
-                    depth + 1,
-                    rng) / (self.scatter_rays as f32);
-            }
+            color += weight * self.integrate_recur(
+                scene,
+                &Ray::with_time(p, out, ray.time),
+                depth + 1,
+                sample.flags.is_delta(),
+                sampler) / (self.scatter_rays as f32);
         }
 
         color
@@ -108,26 +125,323 @@ impl WhittedIntegrator {
         &self,
         scene: &Scene,
         light: &dyn Light,
-        pos: Vec3D,
-        normal: Vec3D,
-        rng: &mut SmallRng,
+        ray: &Ray,
+        hit: &HitResult,
+        frame: Frame,
+        sampler: &mut dyn Sampler,
     ) -> Vec3D {
+        let wo = frame.to_local(-ray.dir);
+        let normal = iff!(wo[2] > 0.0, frame.normal(), -frame.normal());
+        let pos = hit.pos + normal * 0.001;
         let mut total = Color::zero();
         let n = iff!(light.is_delta_distribution(), 1, self.shadow_rays);
 
-        for _ in 0..n {
-            let (dir, t_max, ill) = light.sample_incidence(pos, normal, rng);
-            let ray = Ray::new(pos, dir);
+        // Shadow rays all start at the same point and are traced as packets. Rays which need no
+        // occlusion test are inactive, the contributions are summed in the order of the samples.
+        let mut rays = [*ray; PACKET_SIZE];
+        let mut t_max = [-1.0; PACKET_SIZE];
+        let mut contrib = [Color::zero(); PACKET_SIZE];
+        let mut count = 0;
+
+        for i in 0..n {
+            let (dir, t, ill) = light.sample_incidence(pos, normal, ray.time, sampler);
+            let f = hit.material.eval(hit, wo, frame.to_local(dir));
+
+            if !f.is_zero() && !ill.is_zero() {
+                rays[count] = Ray::with_time(pos, dir, ray.time);
+                t_max[count] = iff!(t == 0.0, -1.0, t);
+                contrib[count] = f * ill;
+                count += 1;
+            }
+
+            if count == PACKET_SIZE || (count > 0 && i == n - 1) {
+                let occluded = scene.root.is_hit_packet(&rays, t_max);
 
-            //println!("{:?} {:?} {:?} {:?}", dir, ill, t_max, Vec3D::dot(dir, normal));
+                for (&c, &occluded) in contrib[..count].iter().zip(&occluded) {
+                    if !occluded {
+                        total += c;
+                    }
+                }
 
-            if t_max == 0.0 || !scene.root.is_hit(&ray, t_max) {
-                total += ill;
+                t_max = [-1.0; PACKET_SIZE];
+                count = 0;
             }
         }
 
-        //println!("total={} {:?}", n, total / (n as f32));
-        //println!("");
         total / n as f32
     }
 }
+
+impl Integrator for WhittedIntegrator {
+    fn radiance(&self, scene: &Scene, ray: &Ray, sampler: &mut dyn Sampler) -> Color {
+        self.integrate_recur(scene, ray, 0, true, sampler)
+    }
+
+    // Camera rays are traced as a packet, shading and secondary rays are per ray.
+    fn radiance_packet(
+        &self,
+        scene: &Scene,
+        rays: &RayPacket,
+        active: [bool; PACKET_SIZE],
+        samplers: &mut [Box<dyn Sampler>; PACKET_SIZE],
+    ) -> [Color; PACKET_SIZE] {
+        let t_max = active.map(|a| iff!(a && self.max_depth > 0, 1e12, -1.0));
+        let hits = scene.root.hit_packet(rays, t_max);
+        let mut colors = [Color::zero(); PACKET_SIZE];
+
+        for (lane, sampler) in samplers.iter_mut().enumerate() {
+            if !active[lane] {
+                continue;
+            }
+
+            let ray = &rays[lane];
+            colors[lane] = match &hits[lane] {
+                Some(hit) => self.shade(scene, ray, hit, 0, true, &mut **sampler),
+                None => scene.calculate_background(ray),
+            };
+        }
+
+        colors
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PathIntegrator {
+    pub max_depth: i32,
+    pub roulette_depth: i32,
+}
+
+impl PathIntegrator {
+    pub fn new() -> Self {
+        Self {
+            max_depth: 64,
+            roulette_depth: 3,
+        }
+    }
+}
+
+impl Integrator for PathIntegrator {
+    fn radiance(&self, scene: &Scene, ray: &Ray, sampler: &mut dyn Sampler) -> Color {
+        let mut ray = *ray;
+        let mut color = Color::zero();
+        let mut throughput = Color::one();
+        let mut specular = true;
+
+        for depth in 0..self.max_depth {
+            let hit = match scene.root.hit(&ray, 1e12) {
+                Some(x) => x,
+                None => {
+                    color += throughput * scene.calculate_background(&ray);
+                    break;
+                }
+            };
+
+            let n = hit.norm.normalize();
+            let frame = Frame::new(n);
+            let wo = frame.to_local(-ray.dir);
+            let outside = wo[2] > 0.0;
+            let p_out = hit.pos + n * 0.001;
+            let p_in = hit.pos - n * 0.001;
+            let (p, facing) = iff!(outside, (p_out, n), (p_in, -n));
+
+            let material = hit.material;
+
+            // Emission is only counted directly for camera rays and after delta bounces,
+            // otherwise it is already accounted for by the light sampling below.
+            if specular {
+                color += throughput * material.emission(&hit, wo);
+            }
+
+            // Next event estimation: explicit light sampling for the non-delta lobes.
+            if material.flags().has_non_delta() {
+                for light in &scene.lights {
+                    let (dir, t_max, ill) = light.sample_incidence(p, facing, ray.time, sampler);
+                    let f = material.eval(&hit, wo, frame.to_local(dir));
+
+                    if f.is_zero() || ill.is_zero() {
+                        continue;
+                    }
+
+                    if t_max == 0.0 || !scene.root.is_hit(&Ray::with_time(p, dir, ray.time), t_max) {
+                        color += throughput * f * ill;
+                    }
+                }
+            }
+
+            let sample = match material.sample(&hit, wo, sampler.next_2d()) {
+                Some(s) if s.pdf > 0.0 => s,
+                _ => break,
+            };
+
+            throughput *= sample.f * sample.wi[2].abs() / sample.pdf;
+            specular = sample.flags.is_delta();
+
+            if depth >= self.roulette_depth {
+                let q = max!(throughput[0], throughput[1], throughput[2]).min(0.95);
+
+                if sampler.next_1d() >= q {
+                    break;
+                }
+
+                throughput /= q;
+            }
+
+            let dir = frame.to_world(sample.wi);
+            let p = iff!(sample.wi[2] > 0.0, p_out, p_in);
+            ray = Ray::with_time(p, dir, ray.time);
+        }
+
+        color
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AmbientOcclusionIntegrator {
+    pub samples: i32,
+    pub distance: f32,
+}
+
+impl AmbientOcclusionIntegrator {
+    pub fn new() -> Self {
+        Self {
+            samples: 16,
+            distance: 1e12,
+        }
+    }
+}
+
+impl Integrator for AmbientOcclusionIntegrator {
+    fn radiance(&self, scene: &Scene, ray: &Ray, sampler: &mut dyn Sampler) -> Color {
+        let hit = match scene.root.hit(ray, 1e12) {
+            Some(x) => x,
+            None => return Color::one(),
+        };
+
+        let n = hit.norm.normalize();
+        let n = iff!(Vec3D::dot(n, ray.dir) < 0.0, n, -n);
+        let p = hit.pos + n * 0.001;
+        let frame = Frame::new(n);
+        let mut visible = 0;
+
+        for _ in 0..self.samples {
+            let local = sample::cosine_hemisphere(sampler.next_2d());
+            let dir = frame.to_world(local);
+
+            if !scene.root.is_hit(&Ray::with_time(p, dir, ray.time), self.distance) {
+                visible += 1;
+            }
+        }
+
+        Color::fill(visible as f32 / self.samples as f32)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NormalIntegrator;
+
+impl Integrator for NormalIntegrator {
+    fn radiance(&self, scene: &Scene, ray: &Ray, _: &mut dyn Sampler) -> Color {
+        match scene.root.hit(ray, 1e12) {
+            Some(hit) => hit.norm.normalize() * 0.5 + Vec3D::fill(0.5),
+            None => Color::zero(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DepthIntegrator;
+
+impl Integrator for DepthIntegrator {
+    fn radiance(&self, scene: &Scene, ray: &Ray, _: &mut dyn Sampler) -> Color {
+        let bbox = scene.root.bounding_box();
+        let scale = (bbox.max - bbox.min).norm();
+
+        match scene.root.hit(ray, 1e12) {
+            Some(hit) => Color::fill(1.0 - hit.t / scale),
+            None => Color::zero(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::load_scene;
+    use crate::render::{parallel_render, RenderOptions};
+
+    // Follows the sampled BSDF directions and only counts the emission of the surfaces which are
+    // hit. No light sampling or Russian roulette, simple enough to serve as a reference.
+    struct BruteForceIntegrator {
+        max_depth: i32,
+    }
+
+    impl Integrator for BruteForceIntegrator {
+        fn radiance(&self, scene: &Scene, ray: &Ray, sampler: &mut dyn Sampler) -> Color {
+            let mut ray = *ray;
+            let mut color = Color::zero();
+            let mut throughput = Color::one();
+
+            for _ in 0..=self.max_depth {
+                let hit = match scene.root.hit(&ray, 1e12) {
+                    Some(x) => x,
+                    None => return color + throughput * scene.calculate_background(&ray),
+                };
+
+                let n = hit.norm.normalize();
+                let frame = Frame::new(n);
+                let wo = frame.to_local(-ray.dir);
+                color += throughput * hit.material.emission(&hit, wo);
+
+                let sample = match hit.material.sample(&hit, wo, sampler.next_2d()) {
+                    Some(s) if s.pdf > 0.0 => s,
+                    _ => break,
+                };
+
+                throughput *= sample.f * sample.wi[2].abs() / sample.pdf;
+                let p = hit.pos + n * 0.001f32.copysign(sample.wi[2]);
+                ray = Ray::with_time(p, frame.to_world(sample.wi), ray.time);
+            }
+
+            color
+        }
+    }
+
+    #[test]
+    fn path_integrator_matches_brute_force() {
+        let mut scene = load_scene(concat!(env!("CARGO_MANIFEST_DIR"), "/scenes/cornell.json"))
+            .expect("failed to load scene");
+        scene.camera = scene.camera.resolution(8, 8);
+
+        let mut options = RenderOptions::new();
+        options.quiet = true;
+        options.antialiasing = 8;
+        options.max_samples = Some(512);
+        let path = parallel_render(&scene, &PathIntegrator::new(), &options);
+
+        options.max_samples = Some(4096);
+        let reference = parallel_render(&scene, &BruteForceIntegrator { max_depth: 64 }, &options);
+
+        // Single pixels are too noisy, compare the average of each quadrant instead.
+        let quadrant = |image: &[Color], qx: usize, qy: usize| {
+            let mut total = Color::zero();
+
+            for y in 0..4 {
+                for x in 0..4 {
+                    total += image[(4 * qy + y) * 8 + 4 * qx + x];
+                }
+            }
+
+            total / 16.0
+        };
+
+        // The light touches the ceiling, so light sampling next to it has a heavy tail and the
+        // path integrator converges slowly there. The tolerance still catches missing or doubly
+        // counted light.
+        for (qx, qy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let a = quadrant(&path, qx, qy);
+            let b = quadrant(&reference, qx, qy);
+            let error = (a - b).norm() / b.norm();
+            assert!(error < 0.1, "quadrant ({}, {}): {:?} != {:?}", qx, qy, a, b);
+        }
+    }
+}
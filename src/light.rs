@@ -1,14 +1,31 @@
-use crate::math::Vec3D;
+use crate::geom::Surface;
+use crate::math::{sample, Vec3D};
+use crate::sampler::Sampler;
 use crate::texture::Color;
-use rand::prelude::*;
+use std::f32::consts::PI;
 
 pub trait Light: Send + Sync {
-    fn sample_incidence(&self, pos: Vec3D, norm: Vec3D, rng: &mut SmallRng) -> (Vec3D, f32, Color);
+    // Samples the light arriving at `pos` at the given time, returns the direction towards the
+    // light, the distance to it (zero if no occlusion test is needed) and the incident light.
+    fn sample_incidence(
+        &self,
+        pos: Vec3D,
+        norm: Vec3D,
+        time: f32,
+        sampler: &mut dyn Sampler,
+    ) -> (Vec3D, f32, Color);
     fn is_delta_distribution(&self) -> bool {
         false
     }
 }
 
+// Intensities of the ambient, point, directional and occlusion lights keep the meaning they had
+// when shading multiplied the albedo directly with the incoming light. The BSDFs include the
+// 1 / pi of the Lambertian lobe, so these lights are scaled by pi to keep scenes equally bright.
+fn legacy_emission(color: Color, intensity: f32) -> Color {
+    color * intensity * PI
+}
+
 pub struct AmbientLight {
     emission: Color,
 }
@@ -16,13 +33,19 @@ pub struct AmbientLight {
 impl AmbientLight {
     pub fn new(color: Color, intensity: f32) -> Self {
         AmbientLight {
-            emission: color * intensity,
+            emission: legacy_emission(color, intensity),
         }
     }
 }
 
 impl Light for AmbientLight {
-    fn sample_incidence(&self, _: Vec3D, normal: Vec3D, _: &mut SmallRng) -> (Vec3D, f32, Color) {
+    fn sample_incidence(
+        &self,
+        _: Vec3D,
+        normal: Vec3D,
+        _: f32,
+        _: &mut dyn Sampler,
+    ) -> (Vec3D, f32, Color) {
         (normal, 0.0, self.emission)
     }
 
@@ -42,7 +65,7 @@ impl PointLight {
         PointLight {
             pos,
             radius,
-            emission: color * intensity,
+            emission: legacy_emission(color, intensity),
         }
     }
 }
@@ -52,18 +75,11 @@ impl Light for PointLight {
         &self,
         pos: Vec3D,
         normal: Vec3D,
-        rng: &mut SmallRng,
+        _: f32,
+        sampler: &mut dyn Sampler,
     ) -> (Vec3D, f32, Color) {
-        let offset = loop {
-            let f = Vec3D::new(
-                rng.gen_range(-1.0, 1.0),
-                rng.gen_range(-1.0, 1.0),
-                rng.gen_range(-1.0, 1.0));
-
-            if f.norm_squared() < 1.0 { 
-                break self.pos + f * self.radius - pos;
-            }
-        };
+        let f = sample::uniform_ball(sampler.next_2d(), sampler.next_1d());
+        let offset = self.pos + f * self.radius - pos;
 
         let dist_sq = offset.norm_squared();
         let dist = dist_sq.sqrt();
@@ -89,7 +105,7 @@ impl DirectionLight {
         DirectionLight {
             dir: dir.normalize(),
             spread: iff!(spread > 0.0, Some(spread), None),
-            emission: color * intensity,
+            emission: legacy_emission(color, intensity),
         }
     }
 }
@@ -99,11 +115,12 @@ impl Light for DirectionLight {
         &self,
         _: Vec3D,
         normal: Vec3D,
-        rng: &mut SmallRng,
+        _: f32,
+        sampler: &mut dyn Sampler,
     ) -> (Vec3D, f32, Color) {
         let o = if let Some(spread) = self.spread {
-            let theta = rng.gen::<f32>() * 2.0 * std::f32::consts::PI;
-            let u = rng.gen::<f32>();
+            let [v, u] = sampler.next_2d();
+            let theta = v * 2.0 * std::f32::consts::PI;
 
             let p = u.powf(spread);
             let r = (1.0 - p).sqrt();
@@ -136,15 +153,21 @@ impl AmbientOcclusion {
     pub fn new(dist: f32, color: Color, intensity: f32) -> Self {
         Self {
             dist: dist.abs(),
-            emission: color * intensity,
+            emission: legacy_emission(color, intensity),
         }
     }
 }
 
 impl Light for AmbientOcclusion {
-    fn sample_incidence(&self, _: Vec3D, normal: Vec3D, rng: &mut SmallRng) -> (Vec3D, f32, Color) {
-        let theta = rng.gen::<f32>() * 2.0 * std::f32::consts::PI;
-        let u = rng.gen::<f32>();
+    fn sample_incidence(
+        &self,
+        _: Vec3D,
+        normal: Vec3D,
+        _: f32,
+        sampler: &mut dyn Sampler,
+    ) -> (Vec3D, f32, Color) {
+        let [v, u] = sampler.next_2d();
+        let theta = v * 2.0 * std::f32::consts::PI;
         let r = u.sqrt();
 
         let x = r * theta.cos();
@@ -157,3 +180,89 @@ impl Light for AmbientOcclusion {
         (dir, self.dist, self.emission)
     }
 }
+
+pub struct AreaLight<G> {
+    geometry: G,
+    emission: Color,
+}
+
+impl<G: Surface> AreaLight<G> {
+    pub fn new(geometry: G, color: Color, intensity: f32) -> Self {
+        Self {
+            geometry,
+            emission: color * intensity,
+        }
+    }
+}
+
+impl<G: Surface> Light for AreaLight<G> {
+    fn sample_incidence(
+        &self,
+        pos: Vec3D,
+        normal: Vec3D,
+        time: f32,
+        sampler: &mut dyn Sampler,
+    ) -> (Vec3D, f32, Color) {
+        let s = self.geometry.sample_surface(sampler.next_2d(), time);
+        let offset = s.pos - pos;
+        let dist_sq = offset.norm_squared();
+        let dist = dist_sq.sqrt();
+        let dir = offset / dist;
+        let cos_light = -Vec3D::dot(s.norm.normalize(), dir);
+
+        if s.pdf <= 0.0 || cos_light <= 0.0 || dist_sq == 0.0 {
+            return (dir, dist, Color::zero());
+        }
+
+        // Convert the area density to a density over solid angle as seen from `pos`.
+        let pdf = s.pdf * dist_sq / cos_light;
+        let cos = Vec3D::dot(dir, normal).max(0.0);
+
+        // Stop the shadow ray just short of the light, since the light's own geometry
+        // is also part of the scene.
+        (dir, dist * (1.0 - 1e-3), self.emission * cos / pdf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::Sphere;
+    use crate::sampler::IndependentSampler;
+    use crate::texture::COLOR_WHITE;
+
+    // Monte Carlo estimate of the irradiance at `pos` on a surface with normal `norm`.
+    fn irradiance(light: &dyn Light, pos: Vec3D, norm: Vec3D, n: u32) -> Color {
+        let mut sampler = IndependentSampler::new(7);
+        let mut total = Color::zero();
+
+        for i in 0..n {
+            sampler.start_sample([0, 0], i);
+            total += light.sample_incidence(pos, norm, 0.0, &mut sampler).2;
+        }
+
+        total / n as f32
+    }
+
+    #[test]
+    fn sphere_light_matches_analytic_irradiance() {
+        let (radius, dist) = (0.5, 2.0);
+        let light = AreaLight::new(Sphere::new(Vec3D::zero(), radius), COLOR_WHITE, 3.0);
+        let pos = Vec3D::new(0.0, 0.0, dist);
+
+        // A sphere entirely above the horizon delivers pi L sin^2(theta) cos(phi), where theta is
+        // its angular radius and phi the angle between the normal and its center.
+        let sin2 = (radius / dist) * (radius / dist);
+
+        for &phi in &[0.0f32, 1.0] {
+            let norm = Vec3D::new(phi.sin(), 0.0, -phi.cos());
+            let expected = PI * 3.0 * sin2 * phi.cos();
+            let e = irradiance(&light, pos, norm, 200_000);
+
+            for c in 0..3 {
+                let error = (e[c] / expected - 1.0).abs();
+                assert!(error < 0.02, "{} != {}", e[c], expected);
+            }
+        }
+    }
+}
@@ -1,3 +1,7 @@
+mod obj;
 mod ply;
+mod scene;
 
+pub use self::obj::*;
 pub use self::ply::*;
+pub use self::scene::*;
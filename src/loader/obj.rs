@@ -0,0 +1,489 @@
+use crate::geom::{GeometryList, Mesh, Object, VertexAttributes};
+use crate::material::*;
+use crate::math::Vec3D;
+use crate::texture::{Color, Image, Texture, Tinted, COLOR_BLACK, COLOR_WHITE};
+use failure::Fail;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum ObjError {
+    IO(String, io::Error),
+    Parse(String, usize, String),
+    Image(String, image::ImageError),
+}
+
+// Implemented by hand for the same reason as `LoadError`.
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ObjError::*;
+
+        match self {
+            IO(file, _) => write!(f, "Error while reading {}", file),
+            Parse(file, line, msg) => write!(f, "Parse error at {}:{}: {}", file, line, msg),
+            Image(file, _) => write!(f, "Error while loading texture {}", file),
+        }
+    }
+}
+
+impl Fail for ObjError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        use ObjError::*;
+
+        match self {
+            IO(_, e) => Some(e),
+            Image(_, e) => Some(e),
+            Parse(..) => None,
+        }
+    }
+}
+
+const MISSING: usize = !0;
+
+struct Lines {
+    file: String,
+    lineno: usize,
+    inner: io::Lines<BufReader<File>>,
+}
+
+impl Lines {
+    fn open(file: &Path) -> Result<Self, ObjError> {
+        let name = file.to_string_lossy().to_string();
+        let f = File::open(file).map_err(|e| ObjError::IO(name.clone(), e))?;
+
+        Ok(Self {
+            file: name,
+            lineno: 0,
+            inner: BufReader::new(f).lines(),
+        })
+    }
+
+    fn next(&mut self) -> Result<Option<String>, ObjError> {
+        match self.inner.next() {
+            Some(Ok(mut line)) => {
+                self.lineno += 1;
+
+                // Only a '#' that starts a token opens a comment, file names may contain one.
+                let bytes = line.as_bytes();
+                let comment = (0..bytes.len())
+                    .find(|&i| bytes[i] == b'#' && (i == 0 || bytes[i - 1].is_ascii_whitespace()));
+
+                if let Some(i) = comment {
+                    line.truncate(i);
+                }
+
+                Ok(Some(line))
+            }
+            Some(Err(e)) => Err(ObjError::IO(self.file.clone(), e)),
+            None => Ok(None),
+        }
+    }
+
+    fn error(&self, msg: &str) -> ObjError {
+        ObjError::Parse(self.file.clone(), self.lineno, msg.to_string())
+    }
+
+    fn floats<const N: usize>(&self, args: &[&str]) -> Result<[f32; N], ObjError> {
+        let mut result = [0.0; N];
+
+        if args.len() < N {
+            raise!(self.error(&format!("expecting {} numbers", N)));
+        }
+
+        for (x, arg) in result.iter_mut().zip(args) {
+            *x = arg
+                .parse()
+                .map_err(|_| self.error("failed to parse number"))?;
+        }
+
+        Ok(result)
+    }
+}
+
+#[derive(Default)]
+struct MaterialDesc {
+    kd: Option<Color>,
+    ks: Option<Color>,
+    ns: Option<f32>,
+    ni: Option<f32>,
+    d: Option<f32>,
+    map_kd: Option<String>,
+}
+
+impl MaterialDesc {
+    fn build(&self, dir: &Path) -> Result<Arc<dyn Material>, ObjError> {
+        let kd = self.kd.unwrap_or(COLOR_WHITE);
+        let ks = self.ks.unwrap_or(COLOR_BLACK);
+
+        if self.d.is_some_and(|d| d < 1.0) {
+            return Ok(Arc::new(Transparent(self.ni.unwrap_or(1.5))));
+        }
+
+        // The diffuse map is modulated by the diffuse color, as in the MTL specification.
+        let texture: Arc<dyn Texture> = match &self.map_kd {
+            Some(file) => {
+                let path = dir.join(file).to_string_lossy().to_string();
+                let img = Image::open(&path).map_err(|e| ObjError::Image(path, e))?;
+                Arc::new(Tinted(img, kd))
+            }
+            None => Arc::new(kd),
+        };
+
+        let specular = (ks[0] + ks[1] + ks[2]) / 3.0;
+
+        Ok(if specular > 0.0 {
+            let exponent = self.ns.unwrap_or(10.0).max(1.0);
+            Arc::new(Glossy(exponent, specular.min(1.0), texture))
+        } else {
+            Arc::new(Lambartian(texture))
+        })
+    }
+}
+
+fn load_mtl(
+    file: &Path,
+    materials: &mut HashMap<String, Arc<dyn Material>>,
+) -> Result<(), ObjError> {
+    let dir = file.parent().unwrap_or_else(|| Path::new(""));
+    let mut lines = Lines::open(file)?;
+    let mut name = None;
+    let mut desc = MaterialDesc::default();
+
+    while let Some(line) = lines.next()? {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        let (key, args) = match parts.split_first() {
+            Some((key, args)) => (*key, args),
+            None => continue,
+        };
+
+        if key == "newmtl" {
+            if let Some(name) = name.take() {
+                materials.insert(name, desc.build(dir)?);
+            }
+
+            name = Some(args.join(" "));
+            desc = MaterialDesc::default();
+            continue;
+        }
+
+        if name.is_none() {
+            raise!(lines.error("expecting 'newmtl'"));
+        }
+
+        match key {
+            "Kd" => desc.kd = Some(Vec3D::from_array(lines.floats(args)?)),
+            "Ks" => desc.ks = Some(Vec3D::from_array(lines.floats(args)?)),
+            "Ns" => desc.ns = Some(lines.floats::<1>(args)?[0]),
+            "Ni" => desc.ni = Some(lines.floats::<1>(args)?[0]),
+            "d" => desc.d = Some(lines.floats::<1>(args)?[0]),
+            "Tr" => desc.d = Some(1.0 - lines.floats::<1>(args)?[0]),
+            "map_Kd" => match args.last() {
+                Some(file) => desc.map_kd = Some(file.to_string()),
+                None => raise!(lines.error("expecting file name")),
+            },
+            _ => (),
+        }
+    }
+
+    if let Some(name) = name {
+        materials.insert(name, desc.build(dir)?);
+    }
+
+    Ok(())
+}
+
+// Faces of one group that share the same material, with their own vertex numbering.
+struct Part {
+    material: Option<String>,
+    mapping: HashMap<[usize; 3], u32>,
+    vertices: Vec<Vec3D>,
+    uvs: Vec<[f32; 2]>,
+    normals: Vec<Vec3D>,
+    has_uvs: bool,
+    has_normals: bool,
+    faces: Vec<[u32; 3]>,
+}
+
+impl Part {
+    fn new(material: Option<String>) -> Self {
+        Self {
+            material,
+            mapping: HashMap::new(),
+            vertices: vec![],
+            uvs: vec![],
+            normals: vec![],
+            has_uvs: true,
+            has_normals: true,
+            faces: vec![],
+        }
+    }
+
+    fn into_mesh(self) -> Mesh {
+        let attributes = VertexAttributes {
+            normals: iff!(self.has_normals, Some(self.normals), None),
+            uvs: iff!(self.has_uvs, Some(self.uvs), None),
+            colors: None,
+        };
+
+        Mesh::with_attributes(self.vertices, attributes, self.faces)
+    }
+}
+
+fn resolve_index(s: &str, count: usize) -> Option<usize> {
+    let index = s.parse::<isize>().ok()?;
+
+    // Indices start at one, negative indices count backwards from the last element.
+    let index = if index > 0 {
+        index as usize - 1
+    } else if index < 0 && index.unsigned_abs() <= count {
+        count - index.unsigned_abs()
+    } else {
+        return None;
+    };
+
+    iff!(index < count, Some(index), None)
+}
+
+pub fn load_obj(file: &str) -> Result<GeometryList<Object>, ObjError> {
+    let dir = Path::new(file).parent().unwrap_or_else(|| Path::new(""));
+    let mut lines = Lines::open(Path::new(file))?;
+
+    let mut materials = HashMap::new();
+    let mut positions = vec![];
+    let mut uvs = vec![];
+    let mut normals = vec![];
+    let mut parts = vec![];
+    let mut current = Part::new(None);
+    let mut indices = vec![];
+
+    while let Some(line) = lines.next()? {
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        let (key, args) = match tokens.split_first() {
+            Some((key, args)) => (*key, args),
+            None => continue,
+        };
+
+        match key {
+            "v" => positions.push(Vec3D::from_array(lines.floats(args)?)),
+            "vn" => normals.push(Vec3D::from_array(lines.floats(args)?)),
+            "vt" => {
+                let [u, v] = lines.floats(args)?;
+
+                // Images are stored top to bottom, texture coordinates start at the bottom.
+                uvs.push([u, 1.0 - v]);
+            }
+            "f" => {
+                if args.len() < 3 {
+                    raise!(lines.error("face must have at least 3 vertices"));
+                }
+
+                indices.clear();
+                for arg in args {
+                    let mut refs = arg.split('/');
+                    let v = refs.next().unwrap_or("");
+                    let vt = refs.next().unwrap_or("");
+                    let vn = refs.next().unwrap_or("");
+
+                    let v = resolve_index(v, positions.len())
+                        .ok_or_else(|| lines.error("invalid vertex index"))?;
+                    let vt = match vt {
+                        "" => MISSING,
+                        s => resolve_index(s, uvs.len())
+                            .ok_or_else(|| lines.error("invalid texture coordinate index"))?,
+                    };
+                    let vn = match vn {
+                        "" => MISSING,
+                        s => resolve_index(s, normals.len())
+                            .ok_or_else(|| lines.error("invalid normal index"))?,
+                    };
+
+                    let part = &mut current;
+                    let next = part.vertices.len() as u32;
+                    let index = *part.mapping.entry([v, vt, vn]).or_insert(next);
+
+                    if index == next {
+                        part.vertices.push(positions[v]);
+                        part.uvs.push(uvs.get(vt).cloned().unwrap_or([0.0, 0.0]));
+                        part.normals
+                            .push(normals.get(vn).cloned().unwrap_or_else(Vec3D::zero));
+                        part.has_uvs &= vt != MISSING;
+                        part.has_normals &= vn != MISSING;
+                    }
+
+                    indices.push(index);
+                }
+
+                for i in 2..indices.len() {
+                    current.faces.push([indices[0], indices[i - 1], indices[i]]);
+                }
+            }
+            "g" | "o" | "usemtl" => {
+                let material = match key {
+                    "usemtl" => Some(args.join(" ")),
+                    _ => current.material.clone(),
+                };
+
+                let part = std::mem::replace(&mut current, Part::new(material));
+                if !part.faces.is_empty() {
+                    parts.push(part);
+                }
+            }
+            "mtllib" => {
+                for name in args {
+                    load_mtl(&dir.join(name), &mut materials)?;
+                }
+            }
+            "s" | "l" | "p" => (),
+            x => eprintln!("WARN: ignoring OBJ statement {:?}", x),
+        }
+    }
+
+    if !current.faces.is_empty() {
+        parts.push(current);
+    }
+
+    let mut objects = GeometryList::new();
+
+    for part in parts {
+        let material = match &part.material {
+            Some(name) => match materials.get(name) {
+                Some(m) => Some(m.clone()),
+                None => {
+                    eprintln!("WARN: unknown material {:?}", name);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let mesh = part.into_mesh();
+        objects.push(match material {
+            Some(m) => Object::with_material(mesh, m),
+            None => Object::new(mesh),
+        });
+    }
+
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::{Geometry, HitResult};
+    use crate::math::Ray;
+    use std::f32::consts::PI;
+    use std::fs;
+    use std::path::PathBuf;
+
+    const OBJ: &str = "\
+# two unit quads next to each other and a triangle behind them
+mtllib scene.mtl
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+vn 0 0 1
+g left
+usemtl matte # painted
+f 1/1/1 2/2/1 3/3/1 4/4/1
+v 2 0 0
+v 3 0 0
+v 3 1 0
+v 2 1 0
+g right
+usemtl shiny
+f -4 -3 -2 -1
+v 1 0 -1
+v 2 0 -1
+v 1.5 1 -1
+usemtl glass
+f 9 10 11
+";
+
+    const MTL: &str = "\
+newmtl matte
+Kd 0.5 0.5 0.5
+map_Kd stripes#1.png
+
+newmtl shiny
+Kd 0.5 0.5 0.5
+Ks 0.4 0.4 0.4
+Ns 20
+
+newmtl glass
+d 0.5
+Ni 1.5
+";
+
+    fn write_fixture(name: &str, obj: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("obj-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("scene.obj"), obj).unwrap();
+        fs::write(dir.join("scene.mtl"), MTL).unwrap();
+
+        // Texture with a red left half and a blue right half.
+        let img = image::RgbImage::from_fn(2, 1, |x, _| match x {
+            0 => image::Rgb([255, 0, 0]),
+            _ => image::Rgb([0, 0, 255]),
+        });
+        img.save(dir.join("stripes#1.png")).unwrap();
+
+        dir.join("scene.obj")
+    }
+
+    fn trace(objects: &GeometryList<Object>, x: f32, y: f32) -> HitResult<'_> {
+        let ray = Ray::new(Vec3D::new(x, y, 1.0), Vec3D::new(0.0, 0.0, -1.0));
+        objects.hit(&ray, 10.0).expect("ray should hit")
+    }
+
+    #[test]
+    fn loads_faces_and_materials() {
+        let file = write_fixture("materials", OBJ);
+        let objects = load_obj(file.to_str().unwrap()).unwrap();
+        let z = Vec3D::z_axis();
+
+        let hit = trace(&objects, 0.25, 0.5);
+        assert_eq!(
+            hit.material.flags(),
+            BsdfFlags::DIFFUSE | BsdfFlags::REFLECTION
+        );
+        assert!((hit.norm - z).norm() < 1e-5);
+        assert!((hit.uv[0] - 0.25).abs() < 1e-5 && (hit.uv[1] - 0.5).abs() < 1e-5);
+
+        // The texture is looked up through the uvs of the face and multiplied by Kd.
+        let left = hit.material.eval(&hit, z, z) * PI;
+        let hit = trace(&objects, 0.75, 0.5);
+        let right = hit.material.eval(&hit, z, z) * PI;
+        assert!((left - Color::new(0.5, 0.0, 0.0)).norm() < 1e-5);
+        assert!((right - Color::new(0.0, 0.0, 0.5)).norm() < 1e-5);
+
+        let hit = trace(&objects, 2.5, 0.5);
+        assert!(hit.material.flags().contains(BsdfFlags::GLOSSY));
+
+        let hit = trace(&objects, 1.5, 0.1);
+        assert!(hit.material.flags().contains(BsdfFlags::TRANSMISSION));
+
+        fs::remove_dir_all(file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn reports_invalid_indices() {
+        let file = write_fixture("indices", "v 0 0 0\nv 1 0 0\nv 0 1 0\n\nf 1 2 4\n");
+
+        match load_obj(file.to_str().unwrap()) {
+            Err(ObjError::Parse(_, line, _)) => assert_eq!(line, 5),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("expected an error"),
+        }
+
+        fs::remove_dir_all(file.parent().unwrap()).unwrap();
+    }
+}
@@ -1,246 +1,538 @@
-use crate::geom::Mesh;
+use crate::geom::{Mesh, VertexAttributes};
 use crate::math::Vec3D;
 use failure::Fail;
-use std::fs::read_to_string;
-use std::io;
+use memmap::Mmap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::sync::Arc;
 
-#[derive(Fail, Debug)]
+#[derive(Debug)]
 pub enum LoadError {
-    #[fail(display = "Error while reading file")]
-    IO(#[cause] io::Error),
-
-    #[fail(display = "File not in PLY format")]
+    IO(io::Error),
     Format,
+    Parse(String, String),
+    InvalidFace(String),
+}
+
+// Display and Fail are implemented by hand, `#[derive(Fail)]` expands to impls that trip the
+// `non_local_definitions` lint.
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use LoadError::*;
+
+        match self {
+            IO(_) => write!(f, "Error while reading file"),
+            Format => write!(f, "File not in PLY format"),
+            Parse(at, msg) => write!(f, "Parse error at {}: {}", at, msg),
+            InvalidFace(face) => write!(f, "Invalid face {}", face),
+        }
+    }
+}
 
-    #[fail(display = "Parse error at line {}: {}", _0, _1)]
-    Parse(usize, String),
+impl Fail for LoadError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        use LoadError::*;
 
-    #[fail(display = "Invalid face {}", _0)]
-    InvalidFace(String),
+        match self {
+            IO(e) => Some(e),
+            Format | Parse(..) | InvalidFace(..) => None,
+        }
+    }
 }
 
-fn normalize_type(parts: &[&str]) -> Option<String> {
-    if let [typ] = parts {
-        Some(match *typ {
-            "char" | "int8" => "int8",
-            "uchar" | "uint8" => "uint8",
-            "short" | "int16" => "int16",
-            "ushort" | "uint16" => "uint16",
-            "int" | "int32" => "int32",
-            "uint" | "uint32" => "uint32",
-            "float" | "float32" => "float32",
-            "double" | "float64" => "float64",
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Scalar {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum PropType {
+    Scalar(Scalar),
+    List(Scalar, Scalar),
+}
+
+impl Scalar {
+    fn size(self) -> usize {
+        match self {
+            Scalar::Int8 | Scalar::UInt8 => 1,
+            Scalar::Int16 | Scalar::UInt16 => 2,
+            Scalar::Int32 | Scalar::UInt32 | Scalar::Float32 => 4,
+            Scalar::Float64 => 8,
+        }
+    }
+
+    fn is_integer(self) -> bool {
+        self != Scalar::Float32 && self != Scalar::Float64
+    }
+}
+
+fn normalize_type(parts: &[&str]) -> Option<PropType> {
+    fn scalar(typ: &str) -> Option<Scalar> {
+        Some(match typ {
+            "char" | "int8" => Scalar::Int8,
+            "uchar" | "uint8" => Scalar::UInt8,
+            "short" | "int16" => Scalar::Int16,
+            "ushort" | "uint16" => Scalar::UInt16,
+            "int" | "int32" => Scalar::Int32,
+            "uint" | "uint32" => Scalar::UInt32,
+            "float" | "float32" => Scalar::Float32,
+            "double" | "float64" => Scalar::Float64,
             _ => return None,
-        }.to_string())
+        })
+    }
+
+    if let [typ] = parts {
+        Some(PropType::Scalar(scalar(typ)?))
     } else if let ["list", a, b] = parts {
-        Some(format!("list {} {}",
-                normalize_type(&[a])?,
-                normalize_type(&[b])?))
+        let count = scalar(a)?;
+
+        if !count.is_integer() {
+            return None;
+        }
+
+        Some(PropType::List(count, scalar(b)?))
     } else {
         None
     }
 }
 
-struct Reader<'a> {
+// Values of a single element, lists are stored inline as their items.
+#[derive(Default)]
+struct Row {
+    values: Vec<f64>,
+    starts: Vec<usize>,
+}
+
+impl Row {
+    fn clear(&mut self) {
+        self.values.clear();
+        self.starts.clear();
+    }
+
+    fn scalar(&self, prop: usize) -> f64 {
+        self.values[self.starts[prop]]
+    }
+
+    fn list(&self, prop: usize) -> &[f64] {
+        let end = self
+            .starts
+            .get(prop + 1)
+            .cloned()
+            .unwrap_or(self.values.len());
+
+        &self.values[self.starts[prop]..end]
+    }
+}
+
+struct Reader<R> {
+    inner: R,
+    format: Format,
     lineno: usize,
-    buffer: &'a str,
-    current: Vec<&'a str>,
+    offset: usize,
+    line: String,
 }
 
-impl<'a> Reader<'a> {
-    fn new(buffer: &'a str) -> Self {
+impl<R: BufRead> Reader<R> {
+    fn new(inner: R) -> Self {
         Self {
+            inner,
+            format: Format::Ascii,
             lineno: 0,
-            buffer,
-            current: vec![],
+            offset: 0,
+            line: String::new(),
         }
     }
 
-    fn next(&mut self) -> &[&'a str] {
-        self.current.clear();
-
-        if let Some(i) = self.buffer.find('\n') {
-            self.current.extend(self.buffer[..i].split_whitespace());
-            self.buffer = &self.buffer[i + 1..];
-            self.lineno += 1;
-        } else {
-            self.buffer = &"";
+    fn location(&self) -> String {
+        match self.format {
+            Format::Ascii => format!("line {}", self.lineno),
+            _ => format!("byte {}", self.offset),
         }
+    }
 
-        self.current()
+    fn error(&self, msg: &str) -> LoadError {
+        LoadError::Parse(self.location(), msg.to_string())
     }
 
-    fn current(&self) -> &[&'a str] {
-        &*self.current
+    fn next_line(&mut self) -> Result<(), LoadError> {
+        self.line.clear();
+        let n = self
+            .inner
+            .read_line(&mut self.line)
+            .map_err(LoadError::IO)?;
+        self.lineno += 1;
+        self.offset += n;
+
+        if n == 0 {
+            raise!(self.error("unexpected end of file"));
+        }
+
+        Ok(())
     }
-}
 
-type Segment = (String, usize, Vec<(String, String)>);
+    fn tokens(&self) -> Vec<&str> {
+        self.line.split_whitespace().collect()
+    }
 
-fn parse_err(reader: &Reader, msg: &str) -> LoadError {
-    LoadError::Parse(reader.lineno, msg.to_string())
-}
+    fn read_row(&mut self, props: &[(String, PropType)], row: &mut Row) -> Result<(), LoadError> {
+        row.clear();
 
-fn parse_header<'a>(lines: &mut Reader<'a>) -> Result<Vec<Segment>, LoadError> {
-    if lines.next() != ["ply"] {
-        raise!(parse_err(lines, "expecting 'ply'"));
+        match self.format {
+            Format::Ascii => self.read_ascii_row(props, row),
+            _ => self.read_binary_row(props, row),
+        }
     }
 
-    if lines.next() != ["format", "ascii", "1.0"] {
-        raise!(parse_err(lines, "expected 'format ascii 1.0'"));
+    fn read_ascii_row(
+        &mut self,
+        props: &[(String, PropType)],
+        row: &mut Row,
+    ) -> Result<(), LoadError> {
+        self.next_line()?;
+        let mut tokens = self.line.split_whitespace();
+
+        for &(_, typ) in props {
+            row.starts.push(row.values.len());
+
+            match typ {
+                PropType::Scalar(s) => {
+                    let x = parse_token(tokens.next(), s).map_err(|e| self.error(e))?;
+                    row.values.push(x);
+                }
+                PropType::List(c, s) => {
+                    let n = parse_token(tokens.next(), c).map_err(|e| self.error(e))?;
+
+                    if n < 0.0 {
+                        raise!(self.error("negative list length"));
+                    }
+
+                    for _ in 0..n as usize {
+                        let x = parse_token(tokens.next(), s).map_err(|e| self.error(e))?;
+                        row.values.push(x);
+                    }
+                }
+            }
+        }
+
+        if tokens.next().is_some() {
+            raise!(self.error("too many values"));
+        }
+
+        Ok(())
     }
 
-    lines.next();
-    let mut segments = vec![];
+    fn read_scalar(&mut self, typ: Scalar) -> Result<f64, LoadError> {
+        let mut buf = [0; 8];
+        let n = typ.size();
 
-    loop {
-        let line = lines.current();
+        if let Err(e) = self.inner.read_exact(&mut buf[..n]) {
+            raise!(if e.kind() == io::ErrorKind::UnexpectedEof {
+                self.error("unexpected end of file")
+            } else {
+                LoadError::IO(e)
+            });
+        }
 
-        match line.get(0) {
-            Some(&"element") => (),
-            Some(&"comment") => {
-                lines.next();
-                continue;
-            }
-            _ => break,
+        self.offset += n;
+
+        if self.format == Format::BinaryBigEndian {
+            buf[..n].reverse();
         }
 
-        let name = line.get(0).unwrap_or(&"").to_string();
-        let num = line
-            .get(2)
-            .unwrap_or(&&"")
-            .parse::<usize>()
-            .map_err(|_| parse_err(lines, "failed to parse number"))?;
+        // Bytes are now in little-endian order.
+        Ok(match typ {
+            Scalar::Int8 => buf[0] as i8 as f64,
+            Scalar::UInt8 => buf[0] as f64,
+            Scalar::Int16 => i16::from_le_bytes([buf[0], buf[1]]) as f64,
+            Scalar::UInt16 => u16::from_le_bytes([buf[0], buf[1]]) as f64,
+            Scalar::Int32 => i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            Scalar::UInt32 => u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            Scalar::Float32 => f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            Scalar::Float64 => f64::from_le_bytes(buf),
+        })
+    }
+
+    fn read_binary_row(
+        &mut self,
+        props: &[(String, PropType)],
+        row: &mut Row,
+    ) -> Result<(), LoadError> {
+        for &(_, typ) in props {
+            row.starts.push(row.values.len());
+
+            match typ {
+                PropType::Scalar(s) => {
+                    let x = self.read_scalar(s)?;
+                    row.values.push(x);
+                }
+                PropType::List(c, s) => {
+                    let n = self.read_scalar(c)?;
+
+                    if n < 0.0 {
+                        raise!(self.error("negative list length"));
+                    }
+
+                    for _ in 0..n as usize {
+                        let x = self.read_scalar(s)?;
+                        row.values.push(x);
+                    }
+                }
+            }
+        }
 
-        let mut props = vec![];
+        Ok(())
+    }
 
+    fn is_at_end(&mut self) -> Result<bool, LoadError> {
         loop {
-            let line = lines.next();
-            let rest = match line.split_first() {
-                Some((&"property", rest)) => rest,
-                Some((&"comment", _)) => continue,
-                _ => break,
-            };
+            let buf = self.inner.fill_buf().map_err(LoadError::IO)?;
 
-            let (key, typ) = match rest.split_last() {
-                Some((k, r)) => (*k, r),
-                None => ("", &[] as _),
-            };
+            if buf.is_empty() {
+                return Ok(true);
+            }
+
+            if self.format != Format::Ascii {
+                return Ok(false);
+            }
 
-            let typ = normalize_type(typ)
-                .ok_or_else(|| parse_err(lines, "invalid type"))?;
+            // Trailing whitespace is allowed in ASCII files.
+            let n = buf.len();
+            if buf.iter().any(|c| !c.is_ascii_whitespace()) {
+                return Ok(false);
+            }
 
-            props.push((key.to_string(), typ));
+            self.inner.consume(n);
         }
+    }
+}
+
+fn parse_token(token: Option<&str>, typ: Scalar) -> Result<f64, &'static str> {
+    let x = token
+        .ok_or("unexpected end of line")?
+        .parse::<f64>()
+        .map_err(|_| "failed to parse number")?;
+
+    if typ.is_integer() && x.fract() != 0.0 {
+        return Err("expecting integer");
+    }
+
+    Ok(x)
+}
+
+pub type PlyData = (Vec<Vec3D>, VertexAttributes, Vec<[u32; 3]>);
+
+type Segment = (String, usize, Vec<(String, PropType)>);
 
-        segments.push((name, num, props));
+fn parse_header<R: BufRead>(reader: &mut Reader<R>) -> Result<Vec<Segment>, LoadError> {
+    reader.next_line()?;
+    if reader.tokens() != ["ply"] {
+        raise!(LoadError::Format);
     }
 
-    if lines.current() != ["end_header"] {
-        raise!(parse_err(lines, "expected 'end_header'"));
+    // The header is always text, so the format is only applied once it has been read.
+    reader.next_line()?;
+    let format = match &*reader.tokens() {
+        ["format", "ascii", "1.0"] => Format::Ascii,
+        ["format", "binary_little_endian", "1.0"] => Format::BinaryLittleEndian,
+        ["format", "binary_big_endian", "1.0"] => Format::BinaryBigEndian,
+        _ => raise!(reader.error("expected 'format ascii 1.0' or 'format binary_*_endian 1.0'")),
+    };
+
+    let mut segments: Vec<Segment> = vec![];
+
+    loop {
+        reader.next_line()?;
+        let line = reader.tokens();
+
+        match line.split_first() {
+            Some((&"comment", _)) | Some((&"obj_info", _)) | None => continue,
+            Some((&"end_header", [])) => break,
+            Some((&"element", [name, num])) => {
+                let num = num
+                    .parse::<usize>()
+                    .map_err(|_| reader.error("failed to parse number"))?;
+
+                segments.push((name.to_string(), num, vec![]));
+            }
+            Some((&"property", rest)) => {
+                let (key, typ) = match rest.split_last() {
+                    Some((k, r)) => (k.to_string(), normalize_type(r)),
+                    None => raise!(reader.error("invalid property")),
+                };
+
+                let typ = typ.ok_or_else(|| reader.error("invalid type"))?;
+
+                match segments.last_mut() {
+                    Some(segment) => segment.2.push((key, typ)),
+                    None => raise!(reader.error("property outside of element")),
+                }
+            }
+            _ => raise!(reader.error("expected 'end_header'")),
+        }
     }
 
+    reader.format = format;
     Ok(segments)
 }
 
-fn parse_vertices(
-    lines: &mut Reader,
+fn parse_vertices<R: BufRead>(
+    reader: &mut Reader<R>,
     size: usize,
-    props: &[(String, String)],
-) -> Result<Vec<Vec3D>, LoadError> {
+    props: &[(String, PropType)],
+) -> Result<(Vec<Vec3D>, VertexAttributes), LoadError> {
     const INVALID: usize = !0;
     let [mut xi, mut yi, mut zi] = [INVALID; 3];
-    let n = props.len();
-    let mut vertices = vec![];
+    let [mut nxi, mut nyi, mut nzi] = [INVALID; 3];
+    let [mut ui, mut vi] = [INVALID; 2];
+    let [mut ri, mut gi, mut bi] = [INVALID; 3];
+    let mut color_scale = 1.0;
+    let mut row = Row::default();
 
     for (index, (k, v)) in props.iter().enumerate() {
-        match (k.as_str(), v.as_str()) {
-            ("x", "float32") => xi = index,
-            ("y", "float32") => yi = index,
-            ("z", "float32") => zi = index,
-            (x, _) => {
+        let typ = match v {
+            PropType::Scalar(s) => *s,
+            PropType::List(_, _) => {
+                eprintln!("WARN: ignoring vertex property {:?}", k);
+                continue;
+            }
+        };
+
+        match k.as_str() {
+            "x" => xi = index,
+            "y" => yi = index,
+            "z" => zi = index,
+            "nx" => nxi = index,
+            "ny" => nyi = index,
+            "nz" => nzi = index,
+            "u" | "s" | "texture_u" => ui = index,
+            "v" | "t" | "texture_v" => vi = index,
+            "red" | "green" | "blue" => {
+                // Integer colors use the full range of their type.
+                color_scale = match typ {
+                    Scalar::UInt8 => 1.0 / 255.0,
+                    Scalar::UInt16 => 1.0 / 65535.0,
+                    _ => 1.0,
+                };
+
+                match k.as_str() {
+                    "red" => ri = index,
+                    "green" => gi = index,
+                    _ => bi = index,
+                }
+            }
+            x => {
                 eprintln!("WARN: ignoring vertex property {:?}", x);
             }
         }
     }
 
     match (xi, yi, zi) {
-        (INVALID, _, _) => raise!(parse_err(lines, "vertex has no x property")),
-        (_, INVALID, _) => raise!(parse_err(lines, "vertex has no y property")),
-        (_, _, INVALID) => raise!(parse_err(lines, "vertex has no z property")),
+        (INVALID, _, _) => raise!(reader.error("vertex has no x property")),
+        (_, INVALID, _) => raise!(reader.error("vertex has no y property")),
+        (_, _, INVALID) => raise!(reader.error("vertex has no z property")),
         _ => (),
     }
 
+    let has_normals = nxi != INVALID && nyi != INVALID && nzi != INVALID;
+    let has_uvs = ui != INVALID && vi != INVALID;
+    let has_colors = ri != INVALID && gi != INVALID && bi != INVALID;
+
+    let mut vertices = Vec::with_capacity(size);
+    let mut normals = Vec::with_capacity(iff!(has_normals, size, 0));
+    let mut uvs = Vec::with_capacity(iff!(has_uvs, size, 0));
+    let mut colors = Vec::with_capacity(iff!(has_colors, size, 0));
+
+    let vec3d = |row: &Row, [x, y, z]: [usize; 3]| {
+        Vec3D::new(
+            row.scalar(x) as f32,
+            row.scalar(y) as f32,
+            row.scalar(z) as f32,
+        )
+    };
+
     for _ in 0..size {
-        let line = lines.next();
+        reader.read_row(props, &mut row)?;
+        vertices.push(vec3d(&row, [xi, yi, zi]));
 
-        if line.len() != n {
-            raise!(parse_err(lines, &format!("expected {} elements", n)));
+        if has_normals {
+            // Shading expects unit normals, exporters do not always write them.
+            let n = vec3d(&row, [nxi, nyi, nzi]);
+            normals.push(n.normalize_safe().unwrap_or(n));
         }
 
-        let [x, y, z] = match (
-            line[xi].parse::<f32>().ok(),
-            line[yi].parse::<f32>().ok(),
-            line[zi].parse::<f32>().ok(),
-        ) {
-            (Some(x), Some(y), Some(z)) => [x, y, z],
-            (None, _, _) => raise!(parse_err(lines, "failed to parse x coordinate")),
-            (_, None, _) => raise!(parse_err(lines, "failed to parse y coordinate")),
-            (_, _, None) => raise!(parse_err(lines, "failed to parse z coordinate")),
-        };
+        if has_uvs {
+            // Images are stored top to bottom, texture coordinates start at the bottom.
+            uvs.push([row.scalar(ui) as f32, 1.0 - row.scalar(vi) as f32]);
+        }
 
-        vertices.push(Vec3D::new(x, y, z));
+        if has_colors {
+            colors.push(vec3d(&row, [ri, gi, bi]) * color_scale);
+        }
     }
 
-    Ok(vertices)
+    let attributes = VertexAttributes {
+        normals: iff!(has_normals, Some(normals), None),
+        uvs: iff!(has_uvs, Some(uvs), None),
+        colors: iff!(has_colors, Some(colors), None),
+    };
+
+    Ok((vertices, attributes))
 }
 
-fn parse_faces(
-    lines: &mut Reader,
+fn parse_faces<R: BufRead>(
+    reader: &mut Reader<R>,
     size: usize,
-    props: &[(String, String)],
+    props: &[(String, PropType)],
     num_vertices: usize,
 ) -> Result<Vec<[u32; 3]>, LoadError> {
     let index = props
         .iter()
         .position(|(a, b)| {
-            (a == "vertex_indices" || a == "vertex_index") &&
-                (b == "list uint8 int32")
+            (a == "vertex_indices" || a == "vertex_index")
+                && match b {
+                    PropType::List(_, s) => s.is_integer(),
+                    _ => false,
+                }
         })
-        .ok_or_else(|| parse_err(lines, "face has not vertex_indices property"))?;
+        .ok_or_else(|| reader.error("face has not vertex_indices property"))?;
 
-    let mut faces = vec![];
+    let mut faces = Vec::with_capacity(size);
     let mut indices = vec![];
+    let mut row = Row::default();
 
     for _ in 0..size {
-        lines.next();
-        let line = lines.current();
-        let n = line
-            .get(index)
-            .and_then(|s| s.parse::<usize>().ok())
-            .ok_or_else(|| parse_err(lines, "failed to parse integer"))?;
-
-        if n < 3 || index + n >= line.len() {
-            raise!(parse_err(lines, "invalid number of vertices"));
+        reader.read_row(props, &mut row)?;
+        let list = row.list(index);
+
+        if list.len() < 3 {
+            raise!(reader.error("invalid number of vertices"));
         }
 
         indices.clear();
-        for part in &line[index + 1..index + 1 + n] {
-            let x = part
-                .parse::<u32>()
-                .map_err(|_| parse_err(lines, "failed to parse integer"))?;
-
-            if x as usize >= num_vertices {
-                raise!(parse_err(
-                    lines,
-                    &format!(
-                        "invalid vertex identifier {}, only {} vertices",
-                        x, num_vertices
-                    )
-                ));
+        for &x in list {
+            if x < 0.0 || x as usize >= num_vertices {
+                raise!(reader.error(&format!(
+                    "invalid vertex identifier {}, only {} vertices",
+                    x, num_vertices
+                )));
             }
 
-            indices.push(x);
+            indices.push(x as u32);
         }
 
         for i in 2..indices.len() {
@@ -251,32 +543,292 @@ fn parse_faces(
     Ok(faces)
 }
 
-pub fn load_ply(file: &str) -> Result<(Vec<Vec3D>, Vec<[u32; 3]>), LoadError> {
-    let buffer = read_to_string(file).map_err(LoadError::IO)?;
-    let mut reader = Reader::new(&buffer);
+pub fn load_ply(file: &str) -> Result<PlyData, LoadError> {
+    let f = File::open(file).map_err(LoadError::IO)?;
+    read_ply(BufReader::new(f))
+}
+
+fn read_ply<R: BufRead>(inner: R) -> Result<PlyData, LoadError> {
+    let mut reader = Reader::new(inner);
 
     let segments = parse_header(&mut reader)?;
     let mut vertices = vec![];
+    let mut attributes = VertexAttributes::default();
     let mut faces = vec![];
 
     for (name, size, props) in segments {
         if name == "vertex" {
-            vertices.extend(parse_vertices(&mut reader, size, &props)?);
+            if !vertices.is_empty() {
+                raise!(reader.error("multiple vertex elements"));
+            }
+
+            let (v, a) = parse_vertices(&mut reader, size, &props)?;
+            vertices = v;
+            attributes = a;
         } else if name == "face" {
             faces.extend(parse_faces(&mut reader, size, &props, vertices.len())?);
         } else {
             eprintln!("WARN: ignoring element {:?}", name);
+
+            let mut row = Row::default();
+            for _ in 0..size {
+                reader.read_row(&props, &mut row)?;
+            }
         }
     }
 
-    if !reader.buffer.trim().is_empty() {
+    if !reader.is_at_end()? {
         eprintln!("WARN: file not read entirely");
     }
 
-    Ok((vertices, faces))
+    Ok((vertices, attributes, faces))
+}
+
+fn build_mesh(file: &str, spatial_splits: bool) -> Result<Mesh, LoadError> {
+    let (vertices, attributes, faces) = load_ply(file)?;
+
+    Ok(if spatial_splits {
+        Mesh::with_spatial_splits(vertices, attributes, faces)
+    } else {
+        Mesh::with_attributes(vertices, attributes, faces)
+    })
+}
+
+// With `use_cache`, the mesh is loaded from the binary cache next to the file if it was built from
+// the same file with the same options. The mesh then uses the arrays of the mapped cache in place,
+// which skips parsing the file and building the hierarchy. Otherwise the mesh is built from the
+// file and the cache is written again.
+pub fn load_ply_as_mesh(
+    file: &str,
+    spatial_splits: bool,
+    use_cache: bool,
+) -> Result<Mesh, LoadError> {
+    if !use_cache {
+        return build_mesh(file, spatial_splits);
+    }
+
+    let key = cache_key(file, spatial_splits).map_err(LoadError::IO)?;
+    let cache = format!("{}.cache", file);
+
+    if let Some(mesh) = read_cache(&cache, key) {
+        return Ok(mesh);
+    }
+
+    let mesh = build_mesh(file, spatial_splits)?;
+
+    if let Err(e) = write_cache(&cache, key, &mesh) {
+        eprintln!("WARN: failed to write cache {:?}: {}", cache, e);
+    }
+
+    Ok(mesh)
+}
+
+// FNV-1a hash of the contents of the file and the build options.
+fn cache_key(file: &str, spatial_splits: bool) -> io::Result<u64> {
+    fn hash(state: u64, bytes: &[u8]) -> u64 {
+        bytes
+            .iter()
+            .fold(state, |h, &b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+    }
+
+    let f = File::open(file)?;
+    let mut state = 0xcbf2_9ce4_8422_2325;
+
+    // Empty files cannot be mapped.
+    if f.metadata()?.len() > 0 {
+        let map = unsafe { Mmap::map(&f)? };
+        state = hash(state, &map);
+    }
+
+    Ok(hash(state, &[spatial_splits as u8]))
+}
+
+fn read_cache(path: &str, key: u64) -> Option<Mesh> {
+    let f = File::open(path).ok()?;
+
+    // Caches are only ever replaced by renaming, so the mapped file does not change underneath the
+    // mesh, which keeps referring to the mapping.
+    let map = unsafe { Mmap::map(&f).ok()? };
+    Mesh::read_cache(Arc::new(map), key)
 }
 
-pub fn load_ply_as_mesh(file: &str) -> Result<Mesh, LoadError> {
-    let (vertices, faces) = load_ply(file)?;
-    Ok(Mesh::from_vertices(vertices, faces))
+// The cache is written under a temporary name first, so that an interrupted write never leaves
+// a partial cache behind.
+fn write_cache(path: &str, key: u64, mesh: &Mesh) -> io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    mesh.write_cache(key, &mut w)?;
+    w.flush()?;
+    drop(w);
+
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::Geometry;
+    use crate::math::Ray;
+
+    const HEADER: &str = "\
+ply
+format {} 1.0
+comment a quad and a triangle with every scalar type
+element vertex 5
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+property double u
+property double v
+element face 2
+property list uchar int vertex_indices
+property ushort flags
+element edge 1
+property char a
+property short b
+property uint c
+end_header
+";
+
+    fn rows() -> Vec<Vec<(Scalar, f64)>> {
+        use Scalar::*;
+        let mut rows = vec![];
+
+        for (i, &(x, y)) in [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0), (2.0, 0.5)]
+            .iter()
+            .enumerate()
+        {
+            let c = 50.0 * i as f64;
+            rows.push(vec![
+                (Float32, x),
+                (Float32, y),
+                (Float32, -0.25),
+                (UInt8, c),
+                (UInt8, 255.0 - c),
+                (UInt8, 255.0),
+                (Float64, x * 0.5),
+                (Float64, y),
+            ]);
+        }
+
+        let face = |indices: &[f64], flags| {
+            let mut row = vec![(UInt8, indices.len() as f64)];
+            row.extend(indices.iter().map(|&i| (Int32, i)));
+            row.push((UInt16, flags));
+            row
+        };
+
+        rows.push(face(&[0.0, 1.0, 2.0, 3.0], 7.0));
+        rows.push(face(&[1.0, 4.0, 2.0], 65535.0));
+        rows.push(vec![(Int8, -3.0), (Int16, -1000.0), (UInt32, 4e9)]);
+        rows
+    }
+
+    fn encode(format: &str) -> Vec<u8> {
+        let mut out = HEADER.replace("{}", format).into_bytes();
+        let little = format == "binary_little_endian";
+
+        macro_rules! bytes {
+            ($x:expr, $t:ty) => {
+                iff!(
+                    little,
+                    ($x as $t).to_le_bytes().to_vec(),
+                    ($x as $t).to_be_bytes().to_vec()
+                )
+            };
+        }
+
+        for row in rows() {
+            for (typ, x) in row {
+                out.extend(match typ {
+                    _ if format == "ascii" => format!("{} ", x).into_bytes(),
+                    Scalar::Int8 => bytes!(x, i8),
+                    Scalar::UInt8 => bytes!(x, u8),
+                    Scalar::Int16 => bytes!(x, i16),
+                    Scalar::UInt16 => bytes!(x, u16),
+                    Scalar::Int32 => bytes!(x, i32),
+                    Scalar::UInt32 => bytes!(x, u32),
+                    Scalar::Float32 => bytes!(x, f32),
+                    Scalar::Float64 => bytes!(x, f64),
+                });
+            }
+
+            if format == "ascii" {
+                out.push(b'\n');
+            }
+        }
+
+        out
+    }
+
+    #[test]
+    fn binary_matches_ascii() {
+        let (vertices, attributes, faces) = read_ply(&encode("ascii")[..]).unwrap();
+
+        assert_eq!(vertices[2], Vec3D::new(1.0, 1.0, -0.25));
+        assert_eq!(attributes.uvs.as_ref().unwrap()[4], [1.0, 0.5]);
+        let color = attributes.colors.as_ref().unwrap()[1];
+        assert!((color - Vec3D::new(50.0, 205.0, 255.0) / 255.0).norm() < 1e-6);
+        assert_eq!(faces, vec![[0, 1, 2], [0, 2, 3], [1, 4, 2]]);
+
+        for format in &["binary_little_endian", "binary_big_endian"] {
+            let (v, a, f) = read_ply(&encode(format)[..]).unwrap();
+
+            assert_eq!(v, vertices, "{}", format);
+            assert_eq!(a.uvs, attributes.uvs, "{}", format);
+            assert_eq!(a.colors, attributes.colors, "{}", format);
+            assert_eq!(a.normals, None, "{}", format);
+            assert_eq!(f, faces, "{}", format);
+        }
+    }
+
+    #[test]
+    fn interpolates_vertex_attributes() {
+        let data = "\
+ply
+format ascii 1.0
+element vertex 3
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+property float s
+property float t
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 0 0 2 0 0 255 0 0
+1 0 0 0 0 3 1 0 0 255 0
+0 1 0 0 0 0.5 0 1 0 0 255
+3 0 1 2
+";
+
+        let (vertices, attributes, faces) = read_ply(data.as_bytes()).unwrap();
+        let mesh = Mesh::with_attributes(vertices, attributes, faces);
+
+        // Hit at barycentric coordinates (0.5, 0.25) of the second and third vertex.
+        let ray = Ray::new(Vec3D::new(0.5, 0.25, 1.0), Vec3D::new(0.0, 0.0, -1.0));
+        let hit = mesh.hit(&ray, 10.0).unwrap();
+
+        // Texture coordinates are flipped vertically, like those of OBJ files.
+        assert!((hit.uv[0] - 0.5).abs() < 1e-5 && (hit.uv[1] - 0.75).abs() < 1e-5);
+        assert!((hit.norm - Vec3D::new(0.0, 0.0, 1.0)).norm() < 1e-5);
+        assert!((hit.color - Vec3D::new(0.25, 0.5, 0.25)).norm() < 1e-5);
+    }
+
+    #[test]
+    fn reports_truncated_binary() {
+        let mut data = encode("binary_little_endian");
+        data.truncate(data.len() - 3);
+
+        assert!(read_ply(&data[..]).is_err());
+    }
 }
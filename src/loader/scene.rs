@@ -0,0 +1,945 @@
+use super::{load_obj, load_ply_as_mesh, LoadError, ObjError};
+use crate::geom::{
+    AABBTree, AffineTransform, AnimatedTransform, Cuboid, Geometry, GeometryList, Mesh, Object,
+    Sphere, Surface, TreeLayout, Triangle,
+};
+use crate::light::*;
+use crate::material::*;
+use crate::math::*;
+use crate::scene::{Camera, Scene};
+use crate::texture::*;
+use failure::Fail;
+use json::JsonValue;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::read_to_string;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Relative cost of intersecting an object compared to a bounding box, objects are typically
+// transformed meshes with their own hierarchy.
+const OBJECT_HIT_COST: f32 = 2.0;
+
+// The hierarchy of the previous frame is refit to the objects of the next frame until its SAH
+// cost has grown by this factor, after which it is rebuilt.
+const MAX_REFIT_RATIO: f32 = 1.5;
+
+#[derive(Debug)]
+pub enum SceneError {
+    IO(io::Error),
+    Json(json::Error),
+    Missing(String),
+    Invalid(String, String),
+    Mesh(String, LoadError),
+    Model(String, ObjError),
+    Image(String, image::ImageError),
+}
+
+// Implemented by hand for the same reason as `LoadError`.
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use SceneError::*;
+
+        match self {
+            IO(_) => write!(f, "Error while reading file"),
+            Json(_) => write!(f, "File not in JSON format"),
+            Missing(path) => write!(f, "Missing value at {}", path),
+            Invalid(path, msg) => write!(f, "Invalid value at {}: {}", path, msg),
+            Mesh(path, _) => write!(f, "Error while loading mesh at {}", path),
+            Model(path, _) => write!(f, "Error while loading model at {}", path),
+            Image(path, _) => write!(f, "Error while loading image at {}", path),
+        }
+    }
+}
+
+impl Fail for SceneError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        use SceneError::*;
+
+        match self {
+            IO(e) => Some(e),
+            Json(e) => Some(e),
+            Mesh(_, e) => Some(e),
+            Model(_, e) => Some(e),
+            Image(_, e) => Some(e),
+            Missing(..) | Invalid(..) => None,
+        }
+    }
+}
+
+struct Node<'a> {
+    value: &'a JsonValue,
+    path: String,
+    frame: f32,
+}
+
+impl<'a> Node<'a> {
+    fn invalid(&self, msg: &str) -> SceneError {
+        SceneError::Invalid(self.path.clone(), msg.to_string())
+    }
+
+    fn opt(&self, key: &str) -> Option<Node<'a>> {
+        let value = &self.value[key];
+
+        if value.is_null() {
+            None
+        } else {
+            Some(Node {
+                value,
+                path: format!("{}.{}", self.path, key),
+                frame: self.frame,
+            })
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Node<'a>, SceneError> {
+        if !self.value.is_object() {
+            raise!(self.invalid("expecting object"));
+        }
+
+        self.opt(key)
+            .ok_or_else(|| SceneError::Missing(format!("{}.{}", self.path, key)))
+    }
+
+    fn members(&self) -> Result<Vec<Node<'a>>, SceneError> {
+        if !self.value.is_array() {
+            raise!(self.invalid("expecting array"));
+        }
+
+        Ok(self
+            .value
+            .members()
+            .enumerate()
+            .map(|(index, value)| Node {
+                value,
+                path: format!("{}[{}]", self.path, index),
+                frame: self.frame,
+            })
+            .collect())
+    }
+
+    fn as_str(&self) -> Result<&'a str, SceneError> {
+        self.value
+            .as_str()
+            .ok_or_else(|| self.invalid("expecting string"))
+    }
+
+    // Numbers and vectors can be animated by replacing them with an object containing
+    // `"keyframes": [[frame, value], ...]` and optionally `"interpolation"`, which is either
+    // "linear" (the default) or "catmull_rom".
+    fn keyframed<F>(&self, parse: F) -> Result<Option<Vec3D>, SceneError>
+    where
+        F: Fn(&Node) -> Result<Vec3D, SceneError>,
+    {
+        if !self.value.is_object() {
+            return Ok(None);
+        }
+
+        let mut keys: Vec<(f32, Vec3D)> = vec![];
+        for key in self.get("keyframes")?.members()? {
+            let frame = match key.value[0].as_f32() {
+                Some(f) if key.value.is_array() && key.value.len() == 2 => f,
+                _ => raise!(key.invalid("expecting array of frame and value")),
+            };
+
+            if keys.last().is_some_and(|k| k.0 >= frame) {
+                raise!(key.invalid("keyframes must have increasing frame numbers"));
+            }
+
+            let value = Node {
+                value: &key.value[1],
+                path: format!("{}[1]", key.path),
+                frame: self.frame,
+            };
+            keys.push((frame, parse(&value)?));
+        }
+
+        if keys.is_empty() {
+            raise!(self.invalid("expecting at least one keyframe"));
+        }
+
+        let mode = match self.opt("interpolation") {
+            Some(n) => match n.as_str()? {
+                "linear" => Interpolation::Linear,
+                "catmull_rom" => Interpolation::CatmullRom,
+                _ => raise!(n.invalid("unknown interpolation")),
+            },
+            None => Interpolation::Linear,
+        };
+
+        Ok(Some(interpolate(&keys, self.frame, mode)))
+    }
+
+    fn as_f32(&self) -> Result<f32, SceneError> {
+        if let Some(v) = self.keyframed(|n| n.as_f32().map(Vec3D::fill))? {
+            return Ok(v[0]);
+        }
+
+        self.value
+            .as_f32()
+            .ok_or_else(|| self.invalid("expecting number"))
+    }
+
+    fn as_usize(&self) -> Result<usize, SceneError> {
+        self.value
+            .as_usize()
+            .ok_or_else(|| self.invalid("expecting non-negative integer"))
+    }
+
+    fn as_vec3d(&self) -> Result<Vec3D, SceneError> {
+        if let Some(v) = self.keyframed(|n| n.as_vec3d())? {
+            return Ok(v);
+        }
+
+        let v = self.value;
+
+        match (v.len(), v[0].as_f32(), v[1].as_f32(), v[2].as_f32()) {
+            (3, Some(x), Some(y), Some(z)) if v.is_array() => Ok(Vec3D::new(x, y, z)),
+            _ => raise!(self.invalid("expecting array of 3 numbers")),
+        }
+    }
+
+    // Array of two increasing numbers.
+    fn as_interval(&self) -> Result<[f32; 2], SceneError> {
+        let v = self.value;
+
+        match (v.len(), v[0].as_f32(), v[1].as_f32()) {
+            (2, Some(a), Some(b)) if v.is_array() && a <= b => Ok([a, b]),
+            _ => raise!(self.invalid("expecting array of 2 increasing numbers")),
+        }
+    }
+
+    fn as_type(&self) -> Result<&'a str, SceneError> {
+        self.get("type")?.as_str()
+    }
+
+    fn f32_or(&self, key: &str, default: f32) -> Result<f32, SceneError> {
+        self.opt(key).map_or(Ok(default), |n| n.as_f32())
+    }
+
+    fn vec3d_or(&self, key: &str, default: Vec3D) -> Result<Vec3D, SceneError> {
+        self.opt(key).map_or(Ok(default), |n| n.as_vec3d())
+    }
+}
+
+struct Loader {
+    dir: PathBuf,
+    meshes: HashMap<PathBuf, Arc<Mesh>>,
+    models: HashMap<PathBuf, Arc<GeometryList<Object>>>,
+    images: HashMap<PathBuf, Arc<dyn Texture>>,
+    spatial_splits: bool,
+    mesh_cache: bool,
+    print_stats: bool,
+    layout: Option<TreeLayout>,
+}
+
+impl Loader {
+    fn resolve(&self, node: &Node) -> Result<PathBuf, SceneError> {
+        Ok(self.dir.join(node.as_str()?))
+    }
+
+    fn parse_camera(&self, node: &Node) -> Result<Camera, SceneError> {
+        let width = node.get("width")?.as_usize()?;
+        let height = node.get("height")?.as_usize()?;
+
+        if width == 0 || height == 0 {
+            raise!(node.invalid("camera has empty resolution"));
+        }
+
+        let pos = node.vec3d_or("position", Vec3D::zero())?;
+        let up = node.vec3d_or("up", Vec3D::y_axis())?;
+
+        let aperture = node.f32_or("aperture", 0.0)?;
+        if aperture < 0.0 {
+            raise!(node.invalid("aperture cannot be negative"));
+        }
+
+        let blades = match node.opt("blades") {
+            Some(n) => n.as_usize()? as u32,
+            None => 0,
+        };
+        let blade_rotation = node.f32_or("blade_rotation", 0.0)?;
+
+        let [open, close] = match node.opt("shutter") {
+            Some(n) => n.as_interval()?,
+            None => [0.0, 0.0],
+        };
+
+        let camera = Camera::new(width, height)
+            .position(pos)
+            .shutter(open, close)
+            .aperture(aperture)
+            .blades(blades, blade_rotation);
+
+        // Focus on the point we look at, unless a distance is given explicitly.
+        let camera = if let Some(n) = node.opt("look_at") {
+            let target = n.as_vec3d()?;
+            camera.look_at(target, up).focus_on(target)
+        } else {
+            camera.look_towards(node.vec3d_or("direction", Vec3D::z_axis())?, up)
+        };
+
+        let camera = match node.opt("focus_distance") {
+            Some(n) => match n.as_f32()? {
+                d if d > 0.0 => camera.focus_distance(d),
+                _ => raise!(n.invalid("focus distance must be positive")),
+            },
+            None => camera,
+        };
+
+        let projection = match node.opt("projection") {
+            Some(n) => n.as_str()?,
+            None => "perspective",
+        };
+
+        Ok(match projection {
+            "perspective" => {
+                let fov = node.f32_or("fov", 60.0)?;
+
+                if !(fov > 0.0 && fov < 180.0) {
+                    raise!(node.invalid("fov must be between 0 and 180 degrees"));
+                }
+
+                camera.perspective(fov)
+            }
+            "orthographic" => {
+                let extent = node.f32_or("extent", 1.0)?;
+
+                if extent.is_nan() || extent <= 0.0 {
+                    raise!(node.invalid("extent must be positive"));
+                }
+
+                camera.orthographic(extent)
+            }
+            "fisheye" => {
+                let fov = node.f32_or("fov", 180.0)?;
+
+                if !(fov > 0.0 && fov <= 360.0) {
+                    raise!(node.invalid("fov must be between 0 and 360 degrees"));
+                }
+
+                camera.fisheye(fov)
+            }
+            "equirectangular" => camera.equirectangular(),
+            _ => raise!(node.invalid("unknown projection")),
+        })
+    }
+
+    fn parse_texture(&mut self, node: &Node) -> Result<Arc<dyn Texture>, SceneError> {
+        if node.value.is_array() {
+            return Ok(Arc::new(node.as_vec3d()?));
+        }
+
+        if node.value.is_string() {
+            return self.load_image(node);
+        }
+
+        Ok(match node.as_type()? {
+            "color" => Arc::new(node.get("color")?.as_vec3d()?),
+            "uv" => Arc::new(UVTexture),
+            "vertex_color" => Arc::new(VertexColor),
+            "checkerboard" => {
+                let repeats = node.opt("repeats").map_or(Ok(8), |n| n.as_usize())?;
+                Arc::new(Checkerboard::new(repeats as i32))
+            }
+            "image" => self.load_image(&node.get("file")?)?,
+            _ => raise!(node.invalid("unknown texture type")),
+        })
+    }
+
+    fn load_image(&mut self, node: &Node) -> Result<Arc<dyn Texture>, SceneError> {
+        let path = self.resolve(node)?;
+
+        if let Some(img) = self.images.get(&path) {
+            return Ok(img.clone());
+        }
+
+        let img = Image::open(&path.to_string_lossy())
+            .map_err(|e| SceneError::Image(node.path.clone(), e))?;
+        let img: Arc<dyn Texture> = Arc::new(img);
+        self.images.insert(path, img.clone());
+
+        Ok(img)
+    }
+
+    fn parse_material(&mut self, node: &Node) -> Result<Box<dyn Material>, SceneError> {
+        Ok(match node.as_type()? {
+            "lambertian" => {
+                let texture = self.parse_texture(&node.get("texture")?)?;
+                Box::new(Lambartian(texture))
+            }
+            "glossy" => {
+                let exponent = node.f32_or("exponent", 1e3)?;
+                let specular = node.f32_or("specular", 0.5)?;
+                let texture = self.parse_texture(&node.get("texture")?)?;
+
+                if !(0.0..=1.0).contains(&specular) {
+                    raise!(node.invalid("specular must be between 0 and 1"));
+                }
+
+                Box::new(Glossy(exponent, specular, texture))
+            }
+            "metal" => Box::new(Metal),
+            "glass" => Box::new(Glass),
+            "transparent" => {
+                let ior = node.get("ior")?.as_f32()?;
+
+                if ior <= 0.0 {
+                    raise!(node.invalid("ior must be positive"));
+                }
+
+                Box::new(Transparent(ior))
+            }
+            "emissive" => {
+                let (color, intensity) = self.parse_emission(node)?;
+                Box::new(Emissive(color, intensity))
+            }
+            _ => raise!(node.invalid("unknown material type")),
+        })
+    }
+
+    fn parse_emission(&self, node: &Node) -> Result<(Color, f32), SceneError> {
+        let color = node.vec3d_or("color", COLOR_WHITE)?;
+        let intensity = node.f32_or("intensity", 1.0)?;
+
+        if intensity < 0.0 {
+            raise!(node.invalid("intensity cannot be negative"));
+        }
+
+        Ok((color, intensity))
+    }
+
+    fn parse_light(&self, node: &Node) -> Result<Box<dyn Light>, SceneError> {
+        let color = node.vec3d_or("color", COLOR_WHITE)?;
+        let intensity = node.f32_or("intensity", 1.0)?;
+
+        Ok(match node.as_type()? {
+            "ambient" => Box::new(AmbientLight::new(color, intensity)),
+            "point" => {
+                let pos = node.get("position")?.as_vec3d()?;
+                let radius = node.f32_or("radius", 0.0)?;
+                Box::new(PointLight::new(pos, radius, color, intensity))
+            }
+            "directional" => {
+                let dir = node.get("direction")?.as_vec3d()?;
+                let spread = node.f32_or("spread", 0.0)?;
+                Box::new(DirectionLight::new(dir, spread, color, intensity))
+            }
+            "ambient_occlusion" => {
+                let dist = node.f32_or("distance", 1e12)?;
+                Box::new(AmbientOcclusion::new(dist, color, intensity))
+            }
+            _ => raise!(node.invalid("unknown light type")),
+        })
+    }
+
+    fn load_mesh(&mut self, node: &Node) -> Result<Arc<Mesh>, SceneError> {
+        let path = self.resolve(node)?;
+
+        if let Some(mesh) = self.meshes.get(&path) {
+            return Ok(mesh.clone());
+        }
+
+        let mesh = load_ply_as_mesh(
+            &path.to_string_lossy(),
+            self.spatial_splits,
+            self.mesh_cache,
+        )
+        .map_err(|e| SceneError::Mesh(node.path.clone(), e))?;
+        let mesh = Arc::new(mesh);
+
+        if self.print_stats {
+            println!("BVH statistics for {:?}: {}", path, mesh.stats());
+        }
+
+        self.meshes.insert(path, mesh.clone());
+
+        Ok(mesh)
+    }
+
+    fn load_model(&mut self, node: &Node) -> Result<Arc<GeometryList<Object>>, SceneError> {
+        let path = self.resolve(node)?;
+
+        if let Some(model) = self.models.get(&path) {
+            return Ok(model.clone());
+        }
+
+        let model = load_obj(&path.to_string_lossy())
+            .map_err(|e| SceneError::Model(node.path.clone(), e))?;
+        let model = Arc::new(model);
+        self.models.insert(path, model.clone());
+
+        Ok(model)
+    }
+
+    fn parse_shape(&mut self, node: &Node) -> Result<Box<dyn Surface>, SceneError> {
+        Ok(match node.as_type()? {
+            "sphere" => {
+                let center = node.vec3d_or("center", Vec3D::zero())?;
+                let radius = node.f32_or("radius", 1.0)?;
+
+                if radius <= 0.0 {
+                    raise!(node.invalid("radius must be positive"));
+                }
+
+                Box::new(Sphere::new(center, radius))
+            }
+            "cuboid" => {
+                let min = node.get("min")?.as_vec3d()?;
+                let max = node.get("max")?.as_vec3d()?;
+                Box::new(Cuboid::new(min, max))
+            }
+            "triangle" => {
+                let vertices = node.get("vertices")?;
+                let v = vertices
+                    .members()?
+                    .iter()
+                    .map(|n| n.as_vec3d())
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                if v.len() != 3 {
+                    raise!(vertices.invalid("triangle must have 3 vertices"));
+                }
+
+                Box::new(Triangle::new(v[0], v[1], v[2]))
+            }
+            "ply" => Box::new(self.load_mesh(&node.get("file")?)?),
+            _ => raise!(node.invalid("unknown object type")),
+        })
+    }
+
+    fn parse_matrix(&self, node: &Node) -> Result<Affine3D, SceneError> {
+        let rows = node.members()?;
+        let mut v = [0.0; 12];
+
+        if rows.len() != 3 {
+            raise!(node.invalid("expecting 3 rows of 4 numbers"));
+        }
+
+        for (i, row) in rows.iter().enumerate() {
+            let cols = row.members()?;
+
+            if cols.len() != 4 {
+                raise!(row.invalid("expecting array of 4 numbers"));
+            }
+
+            for (j, col) in cols.iter().enumerate() {
+                v[4 * i + j] = col.as_f32()?;
+            }
+        }
+
+        Ok(Affine3D::from_rows(v))
+    }
+
+    fn parse_affine(&self, node: &Node) -> Result<Affine3D, SceneError> {
+        let mut trans = Affine3D::identity();
+
+        for step in node.members()? {
+            let key = match step.value.entries().next() {
+                Some((key, _)) if step.value.len() == 1 => key,
+                _ => raise!(step.invalid("expecting object with single key")),
+            };
+
+            let n = step.get(key)?;
+            let m = match key {
+                "translate" => Affine3D::new_translation(n.as_vec3d()?),
+                "scale" => {
+                    let factor = match n.as_f32() {
+                        Ok(f) => Vec3D::fill(f),
+                        Err(_) => n.as_vec3d()?,
+                    };
+
+                    if factor[0] == 0.0 || factor[1] == 0.0 || factor[2] == 0.0 {
+                        raise!(n.invalid("scale cannot be zero"));
+                    }
+
+                    Affine3D::new_scaling(factor)
+                }
+                "shear" => Affine3D::new_shear(
+                    n.f32_or("xy", 0.0)?,
+                    n.f32_or("xz", 0.0)?,
+                    n.f32_or("yx", 0.0)?,
+                    n.f32_or("yz", 0.0)?,
+                    n.f32_or("zx", 0.0)?,
+                    n.f32_or("zy", 0.0)?,
+                ),
+                "rotate_x" => Affine3D::new_rotation(Vec3D::x_axis(), n.as_f32()?.to_radians()),
+                "rotate_y" => Affine3D::new_rotation(Vec3D::y_axis(), n.as_f32()?.to_radians()),
+                "rotate_z" => Affine3D::new_rotation(Vec3D::z_axis(), n.as_f32()?.to_radians()),
+                "rotate" => {
+                    let axis = n.get("axis")?.as_vec3d()?;
+                    let angle = n.get("angle")?.as_f32()?;
+                    Affine3D::new_rotation(axis, angle.to_radians())
+                }
+                "reflect" => Affine3D::new_reflection(n.as_vec3d()?),
+                "matrix" => self.parse_matrix(&n)?,
+                _ => raise!(step.invalid("unknown transformation")),
+            };
+
+            if m.inverse().is_none() {
+                raise!(step.invalid("transformation is not invertible"));
+            }
+
+            trans = trans.then(m);
+        }
+
+        if trans.inverse().is_none() {
+            raise!(node.invalid("transformation is not invertible"));
+        }
+
+        Ok(trans)
+    }
+
+    fn parse_transform<T>(&self, node: &Node, obj: T) -> Result<AffineTransform<T>, SceneError>
+    where
+        T: Geometry,
+    {
+        Ok(AffineTransform::with(obj, self.parse_affine(node)?))
+    }
+
+    fn parse_motion<T>(&self, node: &Node, obj: T) -> Result<AnimatedTransform<T>, SceneError>
+    where
+        T: Geometry,
+    {
+        let start = self.parse_affine(&node.get("start")?)?;
+        let end = self.parse_affine(&node.get("end")?)?;
+        let [t0, t1] = match node.opt("times") {
+            Some(n) => n.as_interval()?,
+            None => [0.0, 1.0],
+        };
+
+        Ok(AnimatedTransform::new(obj, start, end).times(t0, t1))
+    }
+
+    fn parse_object(
+        &mut self,
+        node: &Node,
+        lights: &mut Vec<Box<dyn Light>>,
+    ) -> Result<Object, SceneError> {
+        if node.as_type()? == "obj" {
+            let model = self.load_model(&node.get("file")?)?;
+            return self.place_model(node, model);
+        }
+
+        let geom = self.parse_shape(node)?;
+        self.place_surface(node, geom, node.opt("material"), lights)
+    }
+
+    // Places the same geometry many times, every instance has its own transformation and
+    // optionally its own material. The geometry itself is shared between the instances.
+    fn parse_instances(
+        &mut self,
+        node: &Node,
+        objects: &mut Vec<Object>,
+        lights: &mut Vec<Box<dyn Light>>,
+    ) -> Result<(), SceneError> {
+        let shape = node.get("object")?;
+
+        for key in &["transform", "motion", "material"] {
+            if let Some(n) = shape.opt(key) {
+                raise!(n.invalid("must be given per instance"));
+            }
+        }
+
+        let instances = node.get("instances")?.members()?;
+
+        if shape.as_type()? == "obj" {
+            let model = self.load_model(&shape.get("file")?)?;
+
+            if let Some(n) = node.opt("material") {
+                raise!(n.invalid("obj models use the materials from their MTL files"));
+            }
+
+            for m in &instances {
+                objects.push(self.place_model(m, model.clone())?);
+            }
+
+            return Ok(());
+        }
+
+        let geom: Arc<dyn Surface> = self.parse_shape(&shape)?.into();
+
+        for m in &instances {
+            let material = m.opt("material").or_else(|| node.opt("material"));
+            objects.push(self.place_surface(m, Box::new(geom.clone()), material, lights)?);
+        }
+
+        Ok(())
+    }
+
+    fn place_model(
+        &self,
+        node: &Node,
+        model: Arc<GeometryList<Object>>,
+    ) -> Result<Object, SceneError> {
+        // Models carry their own materials.
+        if let Some(n) = node.opt("material") {
+            raise!(n.invalid("obj models use the materials from their MTL files"));
+        }
+
+        let mut geom: Box<dyn Geometry> = Box::new(model);
+
+        if let Some(n) = node.opt("transform") {
+            geom = Box::new(self.parse_transform(&n, geom)?);
+        }
+
+        if let Some(n) = node.opt("motion") {
+            geom = Box::new(self.parse_motion(&n, geom)?);
+        }
+
+        Ok(Object::new(geom))
+    }
+
+    fn place_surface(
+        &mut self,
+        node: &Node,
+        mut geom: Box<dyn Surface>,
+        material: Option<Node>,
+        lights: &mut Vec<Box<dyn Light>>,
+    ) -> Result<Object, SceneError> {
+        if let Some(n) = node.opt("transform") {
+            geom = Box::new(self.parse_transform(&n, geom)?);
+        }
+
+        if let Some(n) = node.opt("motion") {
+            geom = Box::new(self.parse_motion(&n, geom)?);
+        }
+
+        let n = match material {
+            Some(n) => n,
+            None => return Ok(Object::new(geom)),
+        };
+
+        let material = self.parse_material(&n)?;
+        let geom: Arc<dyn Surface> = geom.into();
+
+        // Emissive objects are also added as area lights, sharing the same geometry.
+        if n.as_type()? == "emissive" {
+            let (color, intensity) = self.parse_emission(&n)?;
+            lights.push(Box::new(AreaLight::new(geom.clone(), color, intensity)));
+        }
+
+        Ok(Object::with_material(geom, material))
+    }
+
+    fn parse_scene(&mut self, node: &Node) -> Result<Scene, SceneError> {
+        let camera = self.parse_camera(&node.get("camera")?)?;
+        let skybox = match node.opt("skybox") {
+            Some(n) => self.parse_texture(&n)?,
+            None => Arc::new(COLOR_BLACK),
+        };
+
+        let mut lights = vec![];
+        if let Some(n) = node.opt("lights") {
+            for m in n.members()? {
+                lights.push(self.parse_light(&m)?);
+            }
+        }
+
+        let mut objects = vec![];
+        for m in node.get("objects")?.members()? {
+            if m.as_type()? == "instances" {
+                self.parse_instances(&m, &mut objects, &mut lights)?;
+            } else {
+                objects.push(self.parse_object(&m, &mut lights)?);
+            }
+        }
+
+        // Top-level hierarchy over the objects, meshes have their own hierarchy underneath. The
+        // frames of an animation have the same objects in different places, so the hierarchy of
+        // the previous frame can be refit instead of built again.
+        let (root, refit) = match self.layout.take() {
+            Some(prev) if prev.stats().objects == objects.len() => {
+                let root = prev.refit_with(objects);
+
+                if root.cost_ratio() > MAX_REFIT_RATIO {
+                    (root.rebuild(), false)
+                } else {
+                    (root, true)
+                }
+            }
+            _ => (AABBTree::new(objects, OBJECT_HIT_COST), false),
+        };
+
+        if self.print_stats {
+            if refit {
+                println!(
+                    "BVH for scene refit, SAH cost ratio {:.2}",
+                    root.cost_ratio()
+                );
+            } else {
+                println!("BVH statistics for scene: {}", root.stats());
+            }
+        }
+
+        self.layout = Some(root.layout());
+
+        Ok(Scene {
+            root: Arc::new(root),
+            skybox,
+            lights,
+            camera,
+        })
+    }
+}
+
+// Scene description that can be instantiated at any frame of its animation. Meshes, models and
+// images are loaded once and shared between the frames.
+pub struct SceneFile {
+    value: JsonValue,
+    loader: Loader,
+}
+
+impl SceneFile {
+    pub fn open(file: &str) -> Result<Self, SceneError> {
+        let buffer = read_to_string(file).map_err(SceneError::IO)?;
+        let value = json::parse(&buffer).map_err(SceneError::Json)?;
+
+        let dir = Path::new(file).parent().unwrap_or(Path::new(""));
+        let loader = Loader {
+            dir: dir.to_path_buf(),
+            meshes: HashMap::new(),
+            models: HashMap::new(),
+            images: HashMap::new(),
+            spatial_splits: false,
+            mesh_cache: true,
+            print_stats: false,
+            layout: None,
+        };
+
+        Ok(Self { value, loader })
+    }
+
+    // Builds the hierarchies of PLY meshes with spatial splits.
+    pub fn spatial_splits(mut self, enabled: bool) -> Self {
+        self.loader.spatial_splits = enabled;
+        self
+    }
+
+    // Reads and writes the binary caches next to PLY meshes, enabled by default.
+    pub fn mesh_cache(mut self, enabled: bool) -> Self {
+        self.loader.mesh_cache = enabled;
+        self
+    }
+
+    // Prints the statistics of every hierarchy that is built.
+    pub fn print_stats(mut self, enabled: bool) -> Self {
+        self.loader.print_stats = enabled;
+        self
+    }
+
+    pub fn scene_at(&mut self, frame: f32) -> Result<Scene, SceneError> {
+        self.loader.parse_scene(&Node {
+            value: &self.value,
+            path: "$".to_string(),
+            frame,
+        })
+    }
+}
+
+pub fn load_scene(file: &str) -> Result<Scene, SceneError> {
+    SceneFile::open(file)?.scene_at(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::{IndependentSampler, Sampler};
+    use rand::prelude::*;
+    use std::fs;
+
+    const CAMERA: &str = r#""camera": {"width": 4, "height": 4, "position": [0, 0, -10]}"#;
+
+    // Shape and placements of the objects, once as instances and once as separate objects.
+    const SHAPE: &str = r#"{"type": "cuboid", "min": [-0.5, -0.2, -0.3], "max": [0.4, 0.6, 0.3]}"#;
+    const PLACEMENTS: [&str; 4] = [
+        r#""transform": [{"translate": [-2, 0, 0]}]"#,
+        r#""transform": [{"rotate_z": 30}, {"scale": [1, 2, 0.5]}, {"translate": [0, 1, 1]}]"#,
+        r#""motion": {"start": [{"translate": [1, -1, 0]}], "end": [{"rotate_x": 45}]}"#,
+        r#""transform": [{"rotate_y": 60}], "material": {"type": "emissive", "intensity": 3}"#,
+    ];
+
+    fn load(name: &str, json: &str) -> Scene {
+        let file = std::env::temp_dir().join(format!("scene-{}-{}.json", name, std::process::id()));
+        fs::write(&file, json).unwrap();
+        let scene = load_scene(&file.to_string_lossy());
+        fs::remove_file(&file).unwrap();
+        scene.expect("failed to load scene")
+    }
+
+    #[test]
+    fn instances_match_separate_objects() {
+        let material = r#""material": {"type": "lambertian", "texture": [0.5, 0.5, 0.5]}"#;
+        let instances = PLACEMENTS
+            .iter()
+            .map(|p| format!("{{{}}}", p))
+            .collect::<Vec<_>>();
+        let node = format!(
+            r#"{{"type": "instances", "object": {}, {}, "instances": [{}]}}"#,
+            SHAPE,
+            material,
+            instances.join(", ")
+        );
+        let shared = load(
+            "instances",
+            &format!(r#"{{{}, "objects": [{}]}}"#, CAMERA, node),
+        );
+
+        let objects = PLACEMENTS
+            .iter()
+            .map(|p| {
+                let shape = SHAPE.trim_end_matches('}');
+                match p.contains("material") {
+                    true => format!("{}, {}}}", shape, p),
+                    false => format!("{}, {}, {}}}", shape, p, material),
+                }
+            })
+            .collect::<Vec<_>>();
+        let separate = load(
+            "objects",
+            &format!(r#"{{{}, "objects": [{}]}}"#, CAMERA, objects.join(", ")),
+        );
+
+        assert_eq!(shared.lights.len(), 1);
+        assert_eq!(separate.lights.len(), 1);
+
+        let mut rng = SmallRng::seed_from_u64(5);
+        let mut hits = 0;
+
+        for i in 0..2000 {
+            let pos = Vec3D::new(rng.gen_range(-4.0, 4.0), rng.gen_range(-3.0, 3.0), -10.0);
+            let target = Vec3D::new(rng.gen_range(-3.0, 3.0), rng.gen_range(-2.0, 3.0), 0.0);
+            let ray = Ray::with_time(pos, (target - pos).normalize(), rng.gen());
+
+            let (a, b) = (shared.root.hit(&ray, 1e12), separate.root.hit(&ray, 1e12));
+
+            match (&a, &b) {
+                (Some(a), Some(b)) => {
+                    assert!((a.t - b.t).abs() < 1e-5, "{} != {}", a.t, b.t);
+                    assert!((a.norm - b.norm).norm() < 1e-5);
+
+                    let wo = Vec3D::z_axis();
+                    assert_eq!(a.material.emission(a, wo), b.material.emission(b, wo));
+                    hits += 1;
+                }
+                (None, None) => {}
+                _ => panic!("{:?}: instance and separate object disagree", ray),
+            }
+
+            // The emissive instance is sampled the same as the emissive object.
+            let sample = |scene: &Scene| {
+                let mut sampler = IndependentSampler::new(1);
+                sampler.start_sample([0, 0], i);
+                scene.lights[0].sample_incidence(pos, ray.dir, ray.time, &mut sampler)
+            };
+            let (a, b) = (sample(&shared), sample(&separate));
+
+            assert!((a.0 - b.0).norm() < 1e-5);
+            assert!((a.1 - b.1).abs() < 1e-4);
+            assert!((a.2 - b.2).norm() < 1e-4);
+        }
+
+        assert!(hits > 100, "only {} rays hit", hits);
+    }
+}
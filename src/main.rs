@@ -8,95 +8,494 @@ mod light;
 mod loader;
 mod material;
 mod math;
+mod output;
 mod render;
+mod sampler;
 mod scene;
 mod texture;
+mod tonemap;
 
-use crate::geom::*;
-use crate::math::*;
-use crate::texture::*;
-use crate::material::*;
-use crate::light::*;
-use std::sync::Arc;
+use crate::integrator::*;
+use crate::sampler::SamplerKind;
+use crate::texture::Color;
+use crate::tonemap::{Operator, ToneMapping, Transfer};
+use clap::{value_t, App, Arg, ArgMatches};
+use failure::Fail;
+use std::fmt::Display;
+use std::fs::rename;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+use std::time::Duration;
+
+fn is_positive<T>(s: String) -> Result<(), String>
+where
+    T: FromStr + PartialOrd + Default,
+    T::Err: Display,
+{
+    match s.parse::<T>() {
+        Ok(x) if x > T::default() => Ok(()),
+        Ok(_) => Err(format!("expecting a positive number, found {:?}", s)),
+        Err(e) => Err(format!("{}: {:?}", e, s)),
+    }
+}
+
+fn is_number<T>(s: String) -> Result<(), String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match s.parse::<T>() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{}: {:?}", e, s)),
+    }
+}
+
+fn parse_resolution(s: &str) -> Option<(usize, usize)> {
+    let mut parts = s.splitn(2, 'x');
+    let width = parts.next()?.parse::<usize>().ok()?;
+    let height = parts.next()?.parse::<usize>().ok()?;
+
+    if width > 0 && height > 0 {
+        Some((width, height))
+    } else {
+        None
+    }
+}
+
+fn is_resolution(s: String) -> Result<(), String> {
+    match parse_resolution(&s) {
+        Some(_) => Ok(()),
+        None => Err(format!("expecting WIDTHxHEIGHT (e.g. 1920x1080), found {:?}", s)),
+    }
+}
+
+fn parse_frames(s: &str) -> Option<(u32, u32)> {
+    let mut parts = s.splitn(2, "..");
+    let start = parts.next()?.parse::<u32>().ok()?;
+    let end = parts.next()?.parse::<u32>().ok()?;
+
+    iff!(start <= end, Some((start, end)), None)
+}
+
+fn is_frames(s: String) -> Result<(), String> {
+    match parse_frames(&s) {
+        Some(_) => Ok(()),
+        None => Err(format!("expecting START..END (e.g. 1..120), found {:?}", s)),
+    }
+}
+
+// Replaces the first run of '#' characters by the zero-padded frame number, or appends the frame
+// number to the file name if there is no such run.
+fn frame_path(pattern: &str, frame: u32) -> PathBuf {
+    if let Some(start) = pattern.find('#') {
+        let (prefix, rest) = pattern.split_at(start);
+        let suffix = rest.trim_start_matches('#');
+        let width = rest.len() - suffix.len();
+
+        return PathBuf::from(format!("{}{:0w$}{}", prefix, frame, suffix, w = width));
+    }
+
+    let path = Path::new(pattern);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}_{:04}.{}", stem, frame, ext.to_string_lossy()),
+        None => format!("{}_{:04}", stem, frame),
+    };
+
+    path.with_file_name(name)
+}
+
+// Hidden file next to `path` with the same extension, frames are renamed once they are complete.
+fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}", name))
+}
+
+fn exit_with_error(msg: &str, e: &dyn Fail) -> ! {
+    eprint!("error: {}", msg);
+    for cause in <dyn Fail>::iter_chain(e) {
+        eprint!(": {}", cause);
+    }
+    eprintln!();
+    exit(1);
+}
+
+fn parse_args<'a>() -> ArgMatches<'a> {
+    App::new("raytracer")
+        .about("Toy raytracer written in Rust")
+        .arg(
+            Arg::with_name("scene")
+                .help("Scene description file (JSON)")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("output")
+                .help("Output image file (.exr, .hdr and .pfm store the linear colors)")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .default_value("output.png"),
+        )
+        .arg(
+            Arg::with_name("resolution")
+                .help("Override the resolution of the camera")
+                .short("r")
+                .long("resolution")
+                .value_name("WIDTHxHEIGHT")
+                .validator(is_resolution),
+        )
+        .arg(
+            Arg::with_name("integrator")
+                .help("Rendering algorithm")
+                .short("i")
+                .long("integrator")
+                .possible_values(&["whitted", "path", "ao", "normals", "depth"])
+                .default_value("whitted"),
+        )
+        .arg(
+            Arg::with_name("max_depth")
+                .help("Maximum recursion depth of rays")
+                .long("max-depth")
+                .default_value("10")
+                .validator(is_number::<u32>),
+        )
+        .arg(
+            Arg::with_name("shadow_rays")
+                .help("Number of shadow rays per light (samples per hit for 'ao')")
+                .long("shadow-rays")
+                .default_value("5")
+                .validator(is_positive::<u32>),
+        )
+        .arg(
+            Arg::with_name("scatter_rays")
+                .help("Number of scattered rays per hit")
+                .long("scatter-rays")
+                .default_value("1")
+                .validator(is_number::<u32>),
+        )
+        .arg(
+            Arg::with_name("antialiasing")
+                .help("Samples per pixel along each axis (N gives NxN samples)")
+                .short("a")
+                .long("antialiasing")
+                .default_value("1")
+                .validator(is_positive::<u32>),
+        )
+        .arg(
+            Arg::with_name("sampler")
+                .help("Sample generator (stratified uses the samples of each pass as strata)")
+                .long("sampler")
+                .possible_values(&["independent", "stratified", "halton", "sobol", "bluenoise"])
+                .default_value("stratified"),
+        )
+        .arg(
+            Arg::with_name("samples")
+                .help("Render progressively until each pixel has this many samples (--adaptive: 1024)")
+                .short("s")
+                .long("samples")
+                .takes_value(true)
+                .validator(is_positive::<u32>),
+        )
+        .arg(
+            Arg::with_name("time_limit")
+                .help("Render progressively for at most this many seconds")
+                .long("time-limit")
+                .value_name("SECONDS")
+                .validator(is_positive::<f64>),
+        )
+        .arg(
+            Arg::with_name("noise_threshold")
+                .help("Render progressively until the average relative error is below this value")
+                .long("noise-threshold")
+                .takes_value(true)
+                .validator(is_positive::<f32>),
+        )
+        .arg(
+            Arg::with_name("adaptive")
+                .help("Only keep sampling pixels whose relative error is above this threshold")
+                .long("adaptive")
+                .value_name("THRESHOLD")
+                .validator(is_positive::<f32>),
+        )
+        .arg(
+            Arg::with_name("min_samples")
+                .help("Samples per pixel before adaptive sampling may skip a pixel")
+                .long("min-samples")
+                .default_value("16")
+                .validator(is_positive::<u32>),
+        )
+        .arg(
+            Arg::with_name("sample_map")
+                .help("Write the number of samples taken per pixel to this image file")
+                .long("sample-map")
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("checkpoint")
+                .help("Write the intermediate image every this many seconds")
+                .long("checkpoint")
+                .value_name("SECONDS")
+                .validator(is_positive::<f64>),
+        )
+        .arg(
+            Arg::with_name("frames")
+                .help("Render a range of animation frames, '#' in the output name marks the frame number")
+                .long("frames")
+                .value_name("START..END")
+                .validator(is_frames),
+        )
+        .arg(
+            Arg::with_name("exposure")
+                .help("Exposure adjustment in stops")
+                .short("e")
+                .long("exposure")
+                .default_value("0")
+                .validator(is_number::<f32>),
+        )
+        .arg(
+            Arg::with_name("tonemap")
+                .help("Tone mapping operator")
+                .long("tonemap")
+                .possible_values(&["clamp", "reinhard", "aces", "hable"])
+                .default_value("clamp"),
+        )
+        .arg(
+            Arg::with_name("gamma")
+                .help("Use a plain gamma curve instead of the sRGB transfer function")
+                .short("g")
+                .long("gamma")
+                .takes_value(true)
+                .validator(is_positive::<f32>),
+        )
+        .arg(
+            Arg::with_name("dither")
+                .help("Dither the image before quantizing it to 8 bits")
+                .long("dither"),
+        )
+        .arg(
+            Arg::with_name("spatial_splits")
+                .help("Build the hierarchies of meshes with spatial splits (slower to build)")
+                .long("spatial-splits"),
+        )
+        .arg(
+            Arg::with_name("no_mesh_cache")
+                .help("Do not read or write the cache files next to PLY meshes")
+                .long("no-mesh-cache"),
+        )
+        .arg(
+            Arg::with_name("bvh_stats")
+                .help("Print statistics and build times of the hierarchies")
+                .long("bvh-stats"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .help("Number of render threads (default: number of cores)")
+                .short("j")
+                .long("threads")
+                .takes_value(true)
+                .validator(is_positive::<usize>),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .help("Seed for the random number generators")
+                .long("seed")
+                .default_value("0")
+                .validator(is_number::<u64>),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .help("Do not print progress or statistics")
+                .short("q")
+                .long("quiet"),
+        )
+        .get_matches()
+}
 
 pub fn main() {
-    let mut list: Vec<Object> = vec![];
-    let mesh = loader::load_ply_as_mesh("../data/bunny_big.ply").unwrap();
-    let mesh = Arc::new(Transform::new(mesh)
-        .scale(20.0)
-        .rotate_x(90f32.to_radians()));
-
-    let bb = mesh.bounding_box();
-    let cube = Cuboid::new(
-        vec3d(-5.0, -5.0, bb.min[2]),
-        vec3d(5.0, 5.0, bb.min[2] - 1.0),
-    );
-
-    let c = (bb.min + bb.max) / 2.0;
-    let r = 1.0;
-    //let mesh = Sphere::new(c, r);
-
-    list.push(Object::with_material(
-            mesh.clone(), 
-            Transparent(1.0 / 0.6)));
-
-    list.push(Object::with_material(
-            Translate::new(mesh.clone()).translate_y(-2.5),
-            Metal));
-
-    list.push(Object::with_material(
-            Translate::new(mesh.clone()).translate_y(2.5),
-            Lambartian(COLOR_WHITE)));
-
-    list.push(Object::with_material(cube, Glossy(1e3, 0.3, COLOR_WHITE)));
-
-    let obj_arc = Arc::new(geom::GeometryList::from_vec(list));
-    let mut integrator = integrator::WhittedIntegrator::new();
-    integrator.antialiasing = 4;
-    integrator.shadow_rays = 20;
-
-    let pos = Vec3D::new(-6.0, -6.0, 3.0);
-    let up = -Vec3D::z_axis();
-    let focus = c + Vec3D::new(0.0, 0.0, 0.1); //Vec3D::new(-0.0, -0.0, 0.0);
-    let cam = scene::Camera::new(1080, 960)
-        .position(pos)
-        .look_at(focus, up);
-
-    let skybox = texture::Image::open("../data/skybox.jpg").unwrap();
-
-    let lights: Vec<Box<dyn light::Light>> = vec![
-        Box::new(AmbientLight::new(Vec3D::one(), 0.1)),
-        Box::new(DirectionLight::new(
-            vec3d(-2.5, -3.0, -3.0),
-            0.01,
-            COLOR_WHITE,
-            0.4,
-        )),
-        Box::new(AmbientOcclusion::new(1e12, Vec3D::one(), 0.5)),
-    ];
-
-    let scene = scene::Scene {
-        root: obj_arc.clone(),
-        camera: cam,
-        skybox: Arc::new(skybox),
-        lights,
+    let args = parse_args();
+    let scene_file = args.value_of("scene").unwrap();
+    let output = args.value_of("output").unwrap();
+
+    let max_depth = value_t!(args, "max_depth", i32).unwrap_or_else(|e| e.exit());
+    let shadow_rays = value_t!(args, "shadow_rays", i32).unwrap_or_else(|e| e.exit());
+    let scatter_rays = value_t!(args, "scatter_rays", i32).unwrap_or_else(|e| e.exit());
+
+    let integrator: Box<dyn Integrator> = match args.value_of("integrator").unwrap() {
+        "whitted" => {
+            let mut integrator = WhittedIntegrator::new();
+            integrator.max_depth = max_depth;
+            integrator.shadow_rays = shadow_rays;
+            integrator.scatter_rays = scatter_rays;
+            Box::new(integrator)
+        }
+        "path" => {
+            let mut integrator = PathIntegrator::new();
+            integrator.max_depth = max_depth;
+            Box::new(integrator)
+        }
+        "ao" => {
+            let mut integrator = AmbientOcclusionIntegrator::new();
+            integrator.samples = shadow_rays;
+            Box::new(integrator)
+        }
+        "normals" => Box::new(NormalIntegrator),
+        "depth" => Box::new(DepthIntegrator),
+        _ => unreachable!(),
     };
 
+    let mut options = render::RenderOptions::new();
+    options.quiet = args.is_present("quiet");
+    options.tone_mapping.exposure = value_t!(args, "exposure", f32).unwrap_or_else(|e| e.exit());
+    options.tone_mapping.dither = args.is_present("dither");
+    options.tone_mapping.operator = match args.value_of("tonemap").unwrap() {
+        "clamp" => Operator::Clamp,
+        "reinhard" => Operator::Reinhard,
+        "aces" => Operator::Aces,
+        "hable" => Operator::Hable,
+        _ => unreachable!(),
+    };
+
+    if args.is_present("gamma") {
+        let gamma = value_t!(args, "gamma", f32).unwrap_or_else(|e| e.exit());
+        options.tone_mapping.transfer = Transfer::Gamma(gamma);
+    }
+    options.antialiasing = value_t!(args, "antialiasing", i32).unwrap_or_else(|e| e.exit());
+    options.seed = value_t!(args, "seed", u64).unwrap_or_else(|e| e.exit());
+    options.sampler = match args.value_of("sampler").unwrap() {
+        "independent" => SamplerKind::Independent,
+        "stratified" => SamplerKind::Stratified,
+        "halton" => SamplerKind::Halton,
+        "sobol" => SamplerKind::Sobol,
+        "bluenoise" => SamplerKind::BlueNoise,
+        _ => unreachable!(),
+    };
+
+    if args.is_present("samples") {
+        options.max_samples = Some(value_t!(args, "samples", u32).unwrap_or_else(|e| e.exit()));
+    }
+
+    if args.is_present("time_limit") {
+        let seconds = value_t!(args, "time_limit", f64).unwrap_or_else(|e| e.exit());
+        options.time_limit = Some(Duration::from_secs_f64(seconds));
+    }
+
+    if args.is_present("noise_threshold") {
+        let threshold = value_t!(args, "noise_threshold", f32).unwrap_or_else(|e| e.exit());
+        options.noise_threshold = Some(threshold);
+    }
+
+    if args.is_present("adaptive") {
+        let threshold = value_t!(args, "adaptive", f32).unwrap_or_else(|e| e.exit());
+        options.adaptive_threshold = Some(threshold);
+        options.max_samples = options.max_samples.or(Some(1024));
+    }
+    options.min_samples = value_t!(args, "min_samples", u32).unwrap_or_else(|e| e.exit());
+
+    if args.is_present("checkpoint") {
+        let seconds = value_t!(args, "checkpoint", f64).unwrap_or_else(|e| e.exit());
+        options.checkpoint_interval = Some(Duration::from_secs_f64(seconds));
+    }
+
+    if let Some(threads) = args.value_of("threads") {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.parse().unwrap())
+            .build_global()
+            .expect("failed to initialize thread pool");
+    }
+
+    let mut file = loader::SceneFile::open(scene_file)
+        .unwrap_or_else(|e| exit_with_error(&format!("failed to load {:?}", scene_file), &e))
+        .spatial_splits(args.is_present("spatial_splits"))
+        .mesh_cache(!args.is_present("no_mesh_cache"))
+        .print_stats(args.is_present("bvh_stats"));
+
+    if !options.quiet {
+        println!("{:?}", options);
+    }
+
+    // Frames which already exist are skipped, so an interrupted sequence can simply be restarted.
+    let frames = match args.value_of("frames").and_then(parse_frames) {
+        Some((start, end)) => (start..=end).map(Some).collect(),
+        None => vec![None],
+    };
+
+    for frame in frames {
+        let (output, target) = match frame {
+            Some(frame) => {
+                let path = frame_path(output, frame);
+
+                if path.exists() {
+                    if !options.quiet {
+                        println!("skipping frame {}: {:?} already exists", frame, path);
+                    }
+
+                    continue;
+                }
+
+                if !options.quiet {
+                    println!("rendering frame {} to {:?}", frame, path);
+                }
+
+                (partial_path(&path), Some(path))
+            }
+            None => (PathBuf::from(output), None),
+        };
+
+        let mut scene = file
+            .scene_at(frame.unwrap_or(0) as f32)
+            .unwrap_or_else(|e| exit_with_error(&format!("failed to load {:?}", scene_file), &e));
+
+        if let Some((width, height)) = args.value_of("resolution").and_then(parse_resolution) {
+            scene.camera = scene.camera.resolution(width, height);
+        }
+
+        let (width, height) = scene.camera.dimensions();
+        let save_as = |file: &Path, buffer: &[Color], tone_mapping: &ToneMapping| {
+            match output::HdrFormat::from_path(file) {
+                Some(format) => output::save_hdr(file, format, width, height, buffer),
+                None => tone_mapping.apply(width, height, buffer).save(file),
+            }
+        };
+        let save = |buffer: &[Color]| save_as(&output, buffer, &options.tone_mapping);
+
+        let acc = render::progressive_render(&scene, &*integrator, &options, |acc| {
+            if let Err(e) = save(&acc.mean()) {
+                eprintln!("WARN: failed to write {:?}: {}", output, e);
+            }
+        });
+        let result = save(&acc.mean());
+
+        if let Err(e) = result {
+            eprintln!("error: failed to write {:?}: {}", output, e);
+            exit(1);
+        }
+
+        if let Some(target) = target {
+            if let Err(e) = rename(&output, &target) {
+                eprintln!("error: failed to rename {:?}: {}", output, e);
+                exit(1);
+            }
+        }
+
+        // Sample counts are written as-is to HDR files and relative to the maximum otherwise.
+        if let Some(file) = args.value_of("sample_map") {
+            let file = match frame {
+                Some(frame) => frame_path(file, frame),
+                None => PathBuf::from(file),
+            };
+
+            let counts = acc.sample_counts();
+            let max_count = counts.iter().map(|c| c[0]).fold(1.0, f32::max);
+            let mut tone_mapping = ToneMapping::new();
+            tone_mapping.exposure = -max_count.log2();
+            tone_mapping.transfer = Transfer::Linear;
 
-    {
-        let i = std::env::args()
-            .nth(1)
-            .map(|x| x.parse::<u32>())
-            .unwrap_or(Ok(5))
-            .unwrap();
-
-        let mut fast_integrator = integrator.clone();
-        fast_integrator.shadow_rays = 1;
-        fast_integrator.scatter_rays = 1;
-        fast_integrator.antialiasing = i as i32;
-        fast_integrator.max_depth = 5;
-        println!("{:?}", fast_integrator);
-        let img = render::parallel_render_image(&scene, &fast_integrator);
-        img.save(&format!("test_{}.png", i)).unwrap();
+            if let Err(e) = save_as(&file, &counts, &tone_mapping) {
+                eprintln!("error: failed to write {:?}: {}", file, e);
+                exit(1);
+            }
+        }
     }
 }
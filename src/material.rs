@@ -1,141 +1,566 @@
-use crate::texture::{Texture, Color, COLOR_GREEN, COLOR_BLACK, COLOR_WHITE};
+use crate::geom::HitResult;
 use crate::math::*;
-use rand::prelude::*;
+use crate::texture::{Color, Texture, COLOR_BLACK, COLOR_GREEN, COLOR_WHITE};
+use std::f32::consts::PI;
+use std::ops::{BitOr, Deref};
 
 pub static DEFAULT_MATERIAL: NullMaterial = NullMaterial;
 
-pub trait Material: Send + Sync {
-    fn sample_at(&self, _u: f32, _v: f32) -> Color {
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BsdfFlags(u8);
+
+impl BsdfFlags {
+    pub const NONE: Self = BsdfFlags(0);
+    pub const DIFFUSE: Self = BsdfFlags(1);
+    pub const GLOSSY: Self = BsdfFlags(2);
+    pub const SPECULAR: Self = BsdfFlags(4);
+    pub const REFLECTION: Self = BsdfFlags(8);
+    pub const TRANSMISSION: Self = BsdfFlags(16);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn is_delta(self) -> bool {
+        self.contains(Self::SPECULAR)
+    }
+
+    pub fn has_non_delta(self) -> bool {
+        self.intersects(Self::DIFFUSE | Self::GLOSSY)
+    }
+}
+
+impl BitOr for BsdfFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        BsdfFlags(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BsdfSample {
+    pub wi: Vec3D,
+    pub f: Color,
+    pub pdf: f32,
+    pub flags: BsdfFlags,
+}
+
+// All directions are in the local shading frame (normal along z) and point away from
+// the surface. `wo` is the outgoing (towards the viewer) and `wi` the incident direction.
+pub trait Bsdf {
+    fn flags(&self) -> BsdfFlags;
+
+    fn eval(&self, _hit: &HitResult, _wo: Vec3D, _wi: Vec3D) -> Color {
         COLOR_BLACK
     }
 
-    fn scatter(&self, _norm: Vec3D, _in: Vec3D, _rng: &mut SmallRng) -> Option<(Vec3D, Color)> {
+    fn sample(&self, _hit: &HitResult, _wo: Vec3D, _u: [f32; 2]) -> Option<BsdfSample> {
         None
     }
+
+    fn pdf(&self, _hit: &HitResult, _wo: Vec3D, _wi: Vec3D) -> f32 {
+        0.0
+    }
+
+    // Samples only the lobes of the given kinds (`DIFFUSE`, `GLOSSY` or `SPECULAR`), `f` and
+    // `pdf` then also only describe those lobes. Materials whose lobes are all of the same kind
+    // can simply reject the samples of `sample`.
+    fn sample_lobes(
+        &self,
+        hit: &HitResult,
+        wo: Vec3D,
+        u: [f32; 2],
+        lobes: BsdfFlags,
+    ) -> Option<BsdfSample> {
+        self.sample(hit, wo, u)
+            .filter(|s| s.flags.intersects(lobes))
+    }
+}
+
+pub trait Material: Bsdf + Send + Sync {
+    fn emission(&self, _hit: &HitResult, _wo: Vec3D) -> Color {
+        COLOR_BLACK
+    }
+}
+
+impl<T> Bsdf for T
+where
+    T: Deref,
+    <T as Deref>::Target: Bsdf,
+{
+    fn flags(&self) -> BsdfFlags {
+        self.deref().flags()
+    }
+
+    fn eval(&self, hit: &HitResult, wo: Vec3D, wi: Vec3D) -> Color {
+        self.deref().eval(hit, wo, wi)
+    }
+
+    fn sample(&self, hit: &HitResult, wo: Vec3D, u: [f32; 2]) -> Option<BsdfSample> {
+        self.deref().sample(hit, wo, u)
+    }
+
+    fn pdf(&self, hit: &HitResult, wo: Vec3D, wi: Vec3D) -> f32 {
+        self.deref().pdf(hit, wo, wi)
+    }
+
+    fn sample_lobes(
+        &self,
+        hit: &HitResult,
+        wo: Vec3D,
+        u: [f32; 2],
+        lobes: BsdfFlags,
+    ) -> Option<BsdfSample> {
+        self.deref().sample_lobes(hit, wo, u, lobes)
+    }
+}
+
+impl<T> Material for T
+where
+    T: Deref + Send + Sync,
+    <T as Deref>::Target: Material,
+{
+    fn emission(&self, hit: &HitResult, wo: Vec3D) -> Color {
+        self.deref().emission(hit, wo)
+    }
+}
+
+#[inline(always)]
+fn same_hemisphere(a: Vec3D, b: Vec3D) -> bool {
+    a[2] * b[2] > 0.0
+}
+
+#[inline(always)]
+fn reflect(wo: Vec3D) -> Vec3D {
+    Vec3D::new(-wo[0], -wo[1], wo[2])
+}
+
+pub fn fresnel(cos_i: f32, eta_i: f32, eta_t: f32) -> f32 {
+    let sin_t = eta_i / eta_t * (1.0 - cos_i * cos_i).max(0.0).sqrt();
+
+    if sin_t < 1.0 {
+        let cos_t = (1.0 - sin_t * sin_t).sqrt();
+        let rs = (eta_i * cos_i - eta_t * cos_t) / (eta_i * cos_i + eta_t * cos_t);
+        let rp = (eta_t * cos_i - eta_i * cos_t) / (eta_t * cos_i + eta_i * cos_t);
+        (rs * rs + rp * rp) / 2.0
+    } else {
+        1.0
+    }
+}
+
+fn lambert_sample(wo: Vec3D, u: [f32; 2]) -> Option<Vec3D> {
+    if wo[2] == 0.0 {
+        return None;
+    }
+
+    let mut wi = sample::cosine_hemisphere(u);
+    wi[2] = wi[2].max(1e-6).copysign(wo[2]);
+    Some(wi)
+}
+
+fn lambert_pdf(wo: Vec3D, wi: Vec3D) -> f32 {
+    iff!(same_hemisphere(wo, wi), wi[2].abs() / PI, 0.0)
 }
 
 pub struct NullMaterial;
 
-impl Material for NullMaterial {
-    fn sample_at(&self, _u: f32, _v: f32) -> Color {
-        COLOR_GREEN
+impl Bsdf for NullMaterial {
+    fn flags(&self) -> BsdfFlags {
+        Lambartian(COLOR_GREEN).flags()
+    }
+
+    fn eval(&self, hit: &HitResult, wo: Vec3D, wi: Vec3D) -> Color {
+        Lambartian(COLOR_GREEN).eval(hit, wo, wi)
+    }
+
+    fn sample(&self, hit: &HitResult, wo: Vec3D, u: [f32; 2]) -> Option<BsdfSample> {
+        Lambartian(COLOR_GREEN).sample(hit, wo, u)
+    }
+
+    fn pdf(&self, hit: &HitResult, wo: Vec3D, wi: Vec3D) -> f32 {
+        Lambartian(COLOR_GREEN).pdf(hit, wo, wi)
     }
 }
 
+impl Material for NullMaterial {}
+
 pub struct Metal;
 
-impl Material for Metal {
-    fn sample_at(&self, _u: f32, _v: f32) -> Color {
-        COLOR_BLACK
+impl Bsdf for Metal {
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::SPECULAR | BsdfFlags::REFLECTION
     }
 
-    fn scatter(&self, n: Vec3D, i: Vec3D, _: &mut SmallRng) -> Option<(Vec3D, Color)> {
-        let out = i - 2.0 * n * Vec3D::dot(n, i);
-        Some((out, COLOR_WHITE))
+    fn sample(&self, _: &HitResult, wo: Vec3D, _: [f32; 2]) -> Option<BsdfSample> {
+        if wo[2] == 0.0 {
+            return None;
+        }
+
+        Some(BsdfSample {
+            wi: reflect(wo),
+            f: COLOR_WHITE / wo[2].abs(),
+            pdf: 1.0,
+            flags: self.flags(),
+        })
     }
 }
 
+impl Material for Metal {}
+
+// Mixture of a Lambertian lobe weighted by `1 - specular` and a normalized Phong lobe
+// weighted by `specular`.
 pub struct Glossy<T: Texture>(pub f32, pub f32, pub T);
 
-impl <T: Texture> Material for Glossy<T> {
-    fn sample_at(&self, u: f32, v: f32) -> Color {
-        self.2.color_at(u, v) * (1.0 - self.1)
+impl<T: Texture> Glossy<T> {
+    fn diffuse(&self, hit: &HitResult) -> Color {
+        self.2.color_at_hit(hit) * (1.0 - self.1)
     }
 
-    fn scatter(&self, n: Vec3D, i: Vec3D, rng: &mut SmallRng) -> Option<(Vec3D, Color)> {
-        let out = reflection(n, i);
-        let (a, b) = out.ortho_axes();
-        let side = iff!(Vec3D::dot(n, i) < 0.0, 1.0, -1.0);
+    fn specular_prob(&self, diffuse: Color) -> f32 {
+        iff!(diffuse.is_zero(), 1.0, self.1)
+    }
 
-        for _ in 0..10 {
-            let theta = rng.gen::<f32>() * 2.0 * std::f32::consts::PI;
-            let u = rng.gen::<f32>();
+    fn phong(&self, wo: Vec3D, wi: Vec3D) -> f32 {
+        let cos = Vec3D::dot(reflect(wo), wi);
+        iff!(cos > 0.0, cos.powf(self.0), 0.0)
+    }
 
-            let p = u.powf(2.0 / (1.0 + self.0));
-            let r = (1.0 - p).sqrt();
+    fn specular(&self, wo: Vec3D, wi: Vec3D) -> f32 {
+        self.1 * (self.0 + 2.0) / (2.0 * PI) * self.phong(wo, wi)
+    }
 
-            let x = r * theta.cos();
-            let y = r * theta.sin();
-            let z = p.sqrt();
+    fn specular_pdf(&self, wo: Vec3D, wi: Vec3D) -> f32 {
+        (self.0 + 1.0) / (2.0 * PI) * self.phong(wo, wi)
+    }
 
-            let o = x * a + y * b + z * out;
+    fn sample_phong(&self, wo: Vec3D, [u, v]: [f32; 2]) -> Vec3D {
+        let theta = u * 2.0 * PI;
+        let z = v.powf(1.0 / (self.0 + 1.0));
+        let r = (1.0 - z * z).max(0.0).sqrt();
 
-            if Vec3D::dot(n, out) * side > 0.0 {
-                return Some((o, COLOR_WHITE * self.1));
-            }
+        let local = Vec3D::new(r * theta.cos(), r * theta.sin(), z);
+        Frame::new(reflect(wo)).to_world(local)
+    }
+}
+
+impl<T: Texture> Bsdf for Glossy<T> {
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::DIFFUSE | BsdfFlags::GLOSSY | BsdfFlags::REFLECTION
+    }
+
+    fn eval(&self, hit: &HitResult, wo: Vec3D, wi: Vec3D) -> Color {
+        if !same_hemisphere(wo, wi) {
+            return COLOR_BLACK;
         }
 
-        None
+        self.diffuse(hit) / PI + Color::fill(self.specular(wo, wi))
+    }
+
+    fn sample(&self, hit: &HitResult, wo: Vec3D, [u, v]: [f32; 2]) -> Option<BsdfSample> {
+        let p = self.specular_prob(self.diffuse(hit));
+
+        let (wi, flags) = if u < p {
+            let wi = self.sample_phong(wo, [u / p, v]);
+            (wi, BsdfFlags::GLOSSY | BsdfFlags::REFLECTION)
+        } else {
+            let u = (u - p) / (1.0 - p);
+            let wi = lambert_sample(wo, [u, v])?;
+            (wi, BsdfFlags::DIFFUSE | BsdfFlags::REFLECTION)
+        };
+
+        if !same_hemisphere(wo, wi) {
+            return None;
+        }
+
+        Some(BsdfSample {
+            wi,
+            f: self.eval(hit, wo, wi),
+            pdf: self.pdf(hit, wo, wi),
+            flags,
+        })
+    }
+
+    fn pdf(&self, hit: &HitResult, wo: Vec3D, wi: Vec3D) -> f32 {
+        if !same_hemisphere(wo, wi) {
+            return 0.0;
+        }
+
+        let p = self.specular_prob(self.diffuse(hit));
+        p * self.specular_pdf(wo, wi) + (1.0 - p) * lambert_pdf(wo, wi)
+    }
+
+    fn sample_lobes(
+        &self,
+        hit: &HitResult,
+        wo: Vec3D,
+        u: [f32; 2],
+        lobes: BsdfFlags,
+    ) -> Option<BsdfSample> {
+        let diffuse = lobes.contains(BsdfFlags::DIFFUSE);
+        let glossy = lobes.contains(BsdfFlags::GLOSSY);
+
+        let sample = if diffuse && glossy {
+            return self.sample(hit, wo, u);
+        } else if glossy {
+            let wi = self.sample_phong(wo, u);
+
+            BsdfSample {
+                wi,
+                f: Color::fill(self.specular(wo, wi)),
+                pdf: self.specular_pdf(wo, wi),
+                flags: BsdfFlags::GLOSSY | BsdfFlags::REFLECTION,
+            }
+        } else if diffuse {
+            let wi = lambert_sample(wo, u)?;
+
+            BsdfSample {
+                wi,
+                f: self.diffuse(hit) / PI,
+                pdf: lambert_pdf(wo, wi),
+                flags: BsdfFlags::DIFFUSE | BsdfFlags::REFLECTION,
+            }
+        } else {
+            return None;
+        };
+
+        iff!(same_hemisphere(wo, sample.wi), Some(sample), None)
     }
 }
 
+impl<T: Texture> Material for Glossy<T> {}
+
 pub struct Glass;
 
-impl Material for Glass {
-    fn scatter(&self, n: Vec3D, i: Vec3D, rng: &mut SmallRng) -> Option<(Vec3D, Color)> {
-        Transparent(1.5).scatter(n, i, rng)
+impl Bsdf for Glass {
+    fn flags(&self) -> BsdfFlags {
+        Transparent(1.5).flags()
+    }
+
+    fn sample(&self, hit: &HitResult, wo: Vec3D, u: [f32; 2]) -> Option<BsdfSample> {
+        Transparent(1.5).sample(hit, wo, u)
     }
 }
 
+impl Material for Glass {}
 
 pub struct Transparent(pub f32);
 
-pub fn reflection(normal: Vec3D, i: Vec3D) -> Vec3D {
-    i - 2.0 * normal * Vec3D::dot(normal, i)
+impl Bsdf for Transparent {
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::SPECULAR | BsdfFlags::REFLECTION | BsdfFlags::TRANSMISSION
+    }
+
+    fn sample(&self, _: &HitResult, wo: Vec3D, [u, _]: [f32; 2]) -> Option<BsdfSample> {
+        let cos_i = wo[2];
+        if cos_i == 0.0 {
+            return None;
+        }
+
+        let (eta_i, eta_t) = iff!(cos_i > 0.0, (1.0, self.0), (self.0, 1.0));
+        let f = fresnel(cos_i.abs(), eta_i, eta_t);
+
+        if u < f {
+            return Some(BsdfSample {
+                wi: reflect(wo),
+                f: Color::fill(f / cos_i.abs()),
+                pdf: f,
+                flags: BsdfFlags::SPECULAR | BsdfFlags::REFLECTION,
+            });
+        }
+
+        let eta = eta_i / eta_t;
+        let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+        let cos_t = (1.0 - sin2_t).max(0.0).sqrt().copysign(-cos_i);
+        let wi = Vec3D::new(-eta * wo[0], -eta * wo[1], cos_t);
+
+        // Radiance is compressed when entering a denser medium, hence the eta^2 factor.
+        Some(BsdfSample {
+            wi,
+            f: Color::fill((1.0 - f) * eta * eta / cos_t.abs()),
+            pdf: 1.0 - f,
+            flags: BsdfFlags::SPECULAR | BsdfFlags::TRANSMISSION,
+        })
+    }
 }
 
-pub fn refraction(normal: Vec3D, i: Vec3D, ior: f32) -> Option<Vec3D> {
-    let side = Vec3D::dot(normal, i);
-    let (cosi, eta, n) = if side < 0.0 {
-        (-side, 1.0 / ior, normal)
-    } else {
-        (side, ior, -normal)
-    };
+impl Material for Transparent {}
 
-    let k = 1.0 - eta * eta * (1.0 - cosi * cosi);
-    if k > 0.0 {
-        Some(eta * i + (eta * cosi - k.sqrt()) * n)
-    } else {
-        None
+pub struct Lambartian<T: Texture>(pub T);
+
+impl<T: Texture> Bsdf for Lambartian<T> {
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::DIFFUSE | BsdfFlags::REFLECTION
+    }
+
+    fn eval(&self, hit: &HitResult, wo: Vec3D, wi: Vec3D) -> Color {
+        if !same_hemisphere(wo, wi) {
+            return COLOR_BLACK;
+        }
+
+        self.0.color_at_hit(hit) / PI
+    }
+
+    fn sample(&self, hit: &HitResult, wo: Vec3D, u: [f32; 2]) -> Option<BsdfSample> {
+        let wi = lambert_sample(wo, u)?;
+
+        Some(BsdfSample {
+            wi,
+            f: self.eval(hit, wo, wi),
+            pdf: lambert_pdf(wo, wi),
+            flags: self.flags(),
+        })
+    }
+
+    fn pdf(&self, _: &HitResult, wo: Vec3D, wi: Vec3D) -> f32 {
+        lambert_pdf(wo, wi)
     }
 }
 
-pub fn fresnel(normal: Vec3D, i: Vec3D, ior: f32) -> f32 {
-    let side = Vec3D::dot(normal, i);
-    let (cosi, etat, etai) = if side < 0.0 {
-        (-side, ior, 1.0)
-    } else {
-        (side, 1.0, ior)
-    };
+impl<T: Texture> Material for Lambartian<T> {}
 
-    let sint = etai / etat * (1.0 - cosi * cosi).sqrt();
+// Light emitted from the front side of the surface, does not reflect any light.
+pub struct Emissive<T: Texture>(pub T, pub f32);
 
-    if sint < 1.0 {
-        let cost = (1.0 - sint * sint).sqrt();
-        let rs = ((etat * cosi) - (etai * cost)) / ((etat * cosi) + (etai * cost));
-        let rp = ((etat * cosi) - (etai * cost)) / ((etai * cosi) + (etat * cost));
-        (rs * rs + rp * rp) / 2.0
-    } else {
-        1.0
+impl<T: Texture> Bsdf for Emissive<T> {
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::NONE
     }
 }
 
-impl Material for Transparent {
-    fn scatter(&self, normal: Vec3D, i: Vec3D, rng: &mut SmallRng) -> Option<(Vec3D, Color)> {
-        let o = if rng.gen::<f32>() < fresnel(normal, i, self.0) {
-            reflection(normal, i)
-        } else {
-            refraction(normal, i, self.0)?
-        };
+impl<T: Texture> Material for Emissive<T> {
+    fn emission(&self, hit: &HitResult, wo: Vec3D) -> Color {
+        if wo[2] <= 0.0 {
+            return COLOR_BLACK;
+        }
 
-        Some((o, COLOR_WHITE))
+        self.0.color_at_hit(hit) * self.1
     }
 }
 
-pub struct Lambartian<T: Texture>(pub T);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit() -> HitResult<'static> {
+        HitResult {
+            pos: Vec3D::zero(),
+            norm: Vec3D::z_axis(),
+            t: 1.0,
+            uv: [0.5, 0.5],
+            color: COLOR_WHITE,
+            material: &DEFAULT_MATERIAL,
+        }
+    }
+
+    // Directions in the upper hemisphere, down to grazing angles.
+    fn directions() -> Vec<Vec3D> {
+        let mut dirs = vec![];
+
+        for &z in &[1.0f32, 0.9, 0.5, 0.2, 0.05] {
+            for i in 0..5 {
+                let phi = i as f32 * 1.3;
+                let r = (1.0 - z * z).sqrt();
+                dirs.push(Vec3D::new(r * phi.cos(), r * phi.sin(), z));
+            }
+        }
+
+        dirs
+    }
+
+    fn reflective() -> Vec<Box<dyn Material>> {
+        let color = Color::new(0.2, 0.5, 0.9);
+
+        vec![
+            Box::new(Lambartian(color)),
+            Box::new(Lambartian(COLOR_WHITE)),
+            Box::new(Glossy(20.0, 0.4, color)),
+            Box::new(Glossy(1000.0, 0.3, COLOR_WHITE)),
+            Box::new(Glossy(5.0, 1.0, COLOR_WHITE)),
+        ]
+    }
+
+    // Monte Carlo estimate of the directional albedo using a stratified grid of samples.
+    fn albedo(sample: impl Fn([f32; 2]) -> Option<BsdfSample>) -> Color {
+        let n = 128;
+        let mut total = Color::zero();
+
+        for i in 0..n {
+            for j in 0..n {
+                let u = [(i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32];
+
+                if let Some(s) = sample(u) {
+                    if s.pdf > 0.0 {
+                        total += s.f * s.wi[2].abs() / s.pdf;
+                    }
+                }
+            }
+        }
+
+        total / (n * n) as f32
+    }
+
+    #[test]
+    fn reflection_is_reciprocal() {
+        let hit = hit();
+
+        for material in reflective() {
+            for &wo in &directions() {
+                for &wi in &directions() {
+                    let a = material.eval(&hit, wo, wi);
+                    let b = material.eval(&hit, wi, wo);
+                    assert!((a - b).norm() <= 1e-4 * a.norm(), "{:?} != {:?}", a, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reflection_conserves_energy() {
+        let hit = hit();
+
+        for material in reflective() {
+            for &wo in &directions() {
+                let total = albedo(|u| material.sample(&hit, wo, u));
+                assert!(max!(total[0], total[1], total[2]) <= 1.01, "{:?}", total);
 
-impl <T: Texture> Material for Lambartian<T> {
-    fn sample_at(&self, u: f32, v: f32) -> Color {
-        self.0.color_at(u, v)
+                let lobes = BsdfFlags::GLOSSY | BsdfFlags::SPECULAR;
+                let glossy = albedo(|u| material.sample_lobes(&hit, wo, u, lobes));
+                assert!(glossy[0] <= total[0] + 0.01, "{:?} > {:?}", glossy, total);
+            }
+        }
+
+        let white = albedo(|u| Lambartian(COLOR_WHITE).sample(&hit, Vec3D::z_axis(), u));
+        assert!((white - Color::one()).norm() < 1e-3, "{:?}", white);
+    }
+
+    #[test]
+    fn dielectric_is_reciprocal() {
+        let hit = hit();
+        let glass = Transparent(1.5);
+
+        for &wo in &directions() {
+            let transmit = |wo: Vec3D| {
+                let s = glass.sample(&hit, wo, [0.999_999, 0.0]).unwrap();
+                assert!(s.flags.contains(BsdfFlags::TRANSMISSION));
+                (s.wi, s.f[0] * s.wi[2].abs())
+            };
+
+            // Following the refracted direction back must return to `wo`, and the product of
+            // both transmittances is (1 - F)^2 once the radiance scaling by eta^2 cancels out.
+            let (wi, forward) = transmit(wo);
+            let (back, backward) = transmit(wi);
+            let f = fresnel(wo[2], 1.0, 1.5);
+
+            assert!((back - wo).norm() < 1e-4, "{:?} != {:?}", back, wo);
+            assert!((forward * backward - (1.0 - f) * (1.0 - f)).abs() < 1e-4);
+
+            let s = glass.sample(&hit, wo, [0.0, 0.0]).unwrap();
+            assert!((s.f[0] * s.wi[2].abs() + forward * 1.5 * 1.5 - 1.0).abs() < 1e-5);
+        }
     }
 }
@@ -2,6 +2,7 @@ use super::{Ray, Vec3D};
 use crunchy::unroll;
 use std::mem::swap;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct AABB {
     pub min: Vec3D,
@@ -11,8 +12,8 @@ pub struct AABB {
 impl AABB {
     pub fn new() -> Self {
         AABB {
-            min: Vec3D::fill(std::f32::INFINITY),
-            max: Vec3D::fill(std::f32::NEG_INFINITY),
+            min: Vec3D::fill(f32::INFINITY),
+            max: Vec3D::fill(f32::NEG_INFINITY),
         }
     }
 
@@ -39,6 +40,44 @@ impl AABB {
         self.union(Self::from_point(p))
     }
 
+    pub fn intersection(self, other: Self) -> Self {
+        AABB {
+            min: Vec3D::from_map(move |i| max!(self.min[i], other.min[i])),
+            max: Vec3D::from_map(move |i| min!(self.max[i], other.max[i])),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min[0] > self.max[0] || self.min[1] > self.max[1] || self.min[2] > self.max[2]
+    }
+
+    pub fn center(&self) -> Vec3D {
+        (self.min + self.max) * 0.5
+    }
+
+    // Bounding box of the eight corners after mapping them through `f`.
+    pub fn map_corners<F>(&self, f: F) -> Self
+    where
+        F: Fn(Vec3D) -> Vec3D,
+    {
+        let corners = [self.min, self.max];
+        let mut bbox = AABB::new();
+
+        unroll! {
+            for i in 0..8 {
+                let p = Vec3D::new(
+                    corners[i % 2][0],
+                    corners[(i / 2) % 2][1],
+                    corners[(i / 4) % 2][2],
+                );
+
+                bbox = bbox.union_point(f(p));
+            }
+        }
+
+        bbox
+    }
+
     pub fn surface_area(&self) -> f32 {
         let d = self.max - self.min;
         2.0 * (d[0] * d[1] + d[1] * d[2] + d[2] * d[0])
@@ -51,7 +90,7 @@ impl AABB {
         let t0 = max!(min!(a[0], b[0]), min!(a[1], b[1]), min!(a[2], b[2]));
         let t1 = min!(max!(a[0], b[0]), max!(a[1], b[1]), max!(a[2], b[2]));
 
-        if t0 < t1 {
+        if t0 <= t1 {
             Some((t0, t1))
         } else {
             None
@@ -79,7 +118,7 @@ impl AABB {
         let t0 = max!(a[0], a[1], a[2]);
         let t1 = min!(b[0], b[1], b[2]);
 
-        if t0 < t1 {
+        if t0 <= t1 {
             Some((t0, t1))
         } else {
             None
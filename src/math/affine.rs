@@ -0,0 +1,123 @@
+use crate::math::{Mat3D, Quaternion, Vec3D};
+
+// Affine map `p -> mat * p + offset`, i.e. a 3x4 matrix with an implicit last row.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Affine3D {
+    pub mat: Mat3D,
+    pub offset: Vec3D,
+}
+
+impl Affine3D {
+    pub fn new(mat: Mat3D, offset: Vec3D) -> Self {
+        Self { mat, offset }
+    }
+
+    #[rustfmt::skip]
+    pub fn from_rows(v: [f32; 12]) -> Self {
+        Self::new(
+            Mat3D::new([
+                v[0], v[1], v[2],
+                v[4], v[5], v[6],
+                v[8], v[9], v[10],
+            ]),
+            Vec3D::new(v[3], v[7], v[11]),
+        )
+    }
+
+    pub fn identity() -> Self {
+        Self::new(Mat3D::identity(), Vec3D::zero())
+    }
+
+    pub fn new_translation(offset: Vec3D) -> Self {
+        Self::new(Mat3D::identity(), offset)
+    }
+
+    pub fn new_scaling(factor: Vec3D) -> Self {
+        Self::from_linear(Mat3D::new_scaling(factor[0], factor[1], factor[2]))
+    }
+
+    pub fn new_rotation(axis: Vec3D, angle: f32) -> Self {
+        Self::from_linear(Mat3D::new_rotation(axis, angle))
+    }
+
+    pub fn new_reflection(axis: Vec3D) -> Self {
+        Self::from_linear(Mat3D::new_reflection(axis))
+    }
+
+    // Shear where `xy` is the amount `y` is added to `x`, `xz` the amount `z` is added to `x`, etc.
+    #[rustfmt::skip]
+    pub fn new_shear(xy: f32, xz: f32, yx: f32, yz: f32, zx: f32, zy: f32) -> Self {
+        Self::from_linear(Mat3D::new([
+            1.0, xy, xz,
+            yx, 1.0, yz,
+            zx, zy, 1.0,
+        ]))
+    }
+
+    pub fn from_linear(mat: Mat3D) -> Self {
+        Self::new(mat, Vec3D::zero())
+    }
+
+    pub fn det(&self) -> f32 {
+        self.mat.det()
+    }
+
+    pub fn inverse(&self) -> Option<Self> {
+        let inv = self.mat.inverse()?;
+        Some(Self::new(inv, -inv.apply(self.offset)))
+    }
+
+    // Returns the transformation that first applies `self` and then `other`.
+    pub fn then(self, other: Self) -> Self {
+        Self::new(
+            Mat3D::multiply(other.mat, self.mat),
+            other.apply_point(self.offset),
+        )
+    }
+
+    // Splits the transformation into a translation, a rotation and a remaining stretch such that
+    // `mat = rotation * stretch`, using the polar decomposition. Reflections end up in the stretch.
+    pub fn decompose(&self) -> Option<(Vec3D, Quaternion, Mat3D)> {
+        let mut r = self.mat;
+
+        // Averaging with the inverse transpose converges to the orthogonal factor.
+        for _ in 0..100 {
+            let inv_t = r.inverse()?.transpose();
+            let mut next = r;
+            let mut diff = 0.0f32;
+
+            for i in 0..3 {
+                for j in 0..3 {
+                    next[[i, j]] = 0.5 * (r[[i, j]] + inv_t[[i, j]]);
+                    diff = diff.max((next[[i, j]] - r[[i, j]]).abs());
+                }
+            }
+
+            r = next;
+            if diff < 1e-6 {
+                break;
+            }
+        }
+
+        if r.det() < 0.0 {
+            r = Mat3D::multiply(r, Mat3D::new_scaling(-1.0, -1.0, -1.0));
+        }
+
+        let stretch = Mat3D::multiply(r.transpose(), self.mat);
+        Some((self.offset, Quaternion::from_matrix(&r), stretch))
+    }
+
+    pub fn compose(translation: Vec3D, rotation: Quaternion, stretch: Mat3D) -> Self {
+        Self::new(Mat3D::multiply(rotation.to_matrix(), stretch), translation)
+    }
+
+    #[inline(always)]
+    pub fn apply_point(&self, p: Vec3D) -> Vec3D {
+        self.mat.apply(p) + self.offset
+    }
+
+    #[inline(always)]
+    pub fn apply_vector(&self, v: Vec3D) -> Vec3D {
+        self.mat.apply(v)
+    }
+}
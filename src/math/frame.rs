@@ -0,0 +1,33 @@
+use super::Vec3D;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Frame {
+    s: Vec3D,
+    t: Vec3D,
+    n: Vec3D,
+}
+
+impl Frame {
+    pub fn new(normal: Vec3D) -> Self {
+        let (s, t) = normal.ortho_axes();
+        Frame { s, t, n: normal }
+    }
+
+    pub fn normal(self) -> Vec3D {
+        self.n
+    }
+
+    #[inline(always)]
+    pub fn to_local(self, v: Vec3D) -> Vec3D {
+        Vec3D::new(
+            Vec3D::dot(v, self.s),
+            Vec3D::dot(v, self.t),
+            Vec3D::dot(v, self.n),
+        )
+    }
+
+    #[inline(always)]
+    pub fn to_world(self, v: Vec3D) -> Vec3D {
+        self.s * v[0] + self.t * v[1] + self.n * v[2]
+    }
+}
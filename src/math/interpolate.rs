@@ -0,0 +1,99 @@
+use super::Vec3D;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Interpolation {
+    Linear,
+    CatmullRom,
+}
+
+// Evaluates the keyframes `(time, value)` at time `t`, the keys must be sorted by strictly
+// increasing time. Outside of the keyframes, the first or last value is held.
+pub fn interpolate(keys: &[(f32, Vec3D)], t: f32, mode: Interpolation) -> Vec3D {
+    let last = keys.len() - 1;
+
+    if t <= keys[0].0 {
+        return keys[0].1;
+    }
+
+    if t >= keys[last].0 {
+        return keys[last].1;
+    }
+
+    let i = keys
+        .iter()
+        .rposition(|k| k.0 <= t)
+        .unwrap_or(0)
+        .min(last - 1);
+    let ((t1, p1), (t2, p2)) = (keys[i], keys[i + 1]);
+    let dt = t2 - t1;
+    let s = (t - t1) / dt;
+
+    match mode {
+        Interpolation::Linear => p1 + (p2 - p1) * s,
+        Interpolation::CatmullRom => {
+            // Cubic Hermite spline with Catmull-Rom tangents, which take the spacing of the keys
+            // into account. The end points use one-sided differences.
+            let tangent = |j: usize| {
+                let (a, b) = (j.saturating_sub(1), (j + 1).min(last));
+                (keys[b].1 - keys[a].1) / (keys[b].0 - keys[a].0)
+            };
+
+            let (m1, m2) = (tangent(i) * dt, tangent(i + 1) * dt);
+            let (s2, s3) = (s * s, s * s * s);
+
+            p1 * (2.0 * s3 - 3.0 * s2 + 1.0)
+                + m1 * (s3 - 2.0 * s2 + s)
+                + p2 * (3.0 * s2 - 2.0 * s3)
+                + m2 * (s3 - s2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [Interpolation; 2] = [Interpolation::Linear, Interpolation::CatmullRom];
+
+    #[test]
+    fn hits_keyframes_exactly() {
+        // Unevenly spaced keys, so the tangents of the spline differ from plain differences.
+        let keys = [
+            (-1.0, Vec3D::new(0.0, 1.0, 2.0)),
+            (0.5, Vec3D::new(3.0, -1.0, 0.25)),
+            (0.75, Vec3D::new(-2.0, 4.0, 1.0)),
+            (4.0, Vec3D::new(1.0, 1.0, -7.5)),
+        ];
+
+        for &mode in &MODES {
+            for &(t, value) in &keys {
+                assert_eq!(interpolate(&keys, t, mode), value, "{:?} at {}", mode, t);
+            }
+
+            // Outside of the keyframes, the first and last values are held.
+            assert_eq!(interpolate(&keys, -5.0, mode), keys[0].1);
+            assert_eq!(interpolate(&keys, 10.0, mode), keys[3].1);
+            assert_eq!(interpolate(&keys[..1], 0.0, mode), keys[0].1);
+        }
+    }
+
+    #[test]
+    fn follows_uniform_motion() {
+        // Evenly spaced keys on a line, which both modes reproduce.
+        let keys = (0..4)
+            .map(|i| (i as f32, Vec3D::new(1.0, 2.0, 3.0) * i as f32))
+            .collect::<Vec<_>>();
+
+        for &mode in &MODES {
+            for i in 0..=30 {
+                let t = i as f32 / 10.0;
+                let error = (interpolate(&keys, t, mode) - Vec3D::new(1.0, 2.0, 3.0) * t).norm();
+                assert!(error < 1e-5, "{:?} at {}: {}", mode, t, error);
+            }
+        }
+
+        // Halfway between the keys, linear interpolation gives the average.
+        let p = interpolate(&keys[1..3], 1.5, Interpolation::Linear);
+        assert_eq!(p, Vec3D::new(1.5, 3.0, 4.5));
+    }
+}
@@ -18,7 +18,7 @@ impl Mat3D {
         Self::from_rows(rows)
     }
 
-    #[cfg_attr(rustfmt, rustfmt_skip)]
+    #[rustfmt::skip]
     pub fn from_columns(cols: [Vec3D; 3]) -> Self {
         Mat3D::new([
             cols[0][0], cols[1][0], cols[2][0],
@@ -35,7 +35,7 @@ impl Mat3D {
         Mat3D::new_scaling(1.0, 1.0, 1.0)
     }
 
-    #[cfg_attr(rustfmt, rustfmt_skip)]
+    #[rustfmt::skip]
     pub fn new_scaling(fx: f32, fy: f32, fz: f32) -> Self {
         Mat3D::new([
             fx, 0.0, 0.0,
@@ -56,7 +56,7 @@ impl Mat3D {
             x * z * (1.0 - c) + y * s,
             y * x * (1.0 - c) + z * s,
             c + y * y * (1.0 - c),
-            y * z * (1.0 * c) - x * s,
+            y * z * (1.0 - c) - x * s,
             z * x * (1.0 - c) - y * s,
             z * y * (1.0 - c) + x * s,
             c + z * z * (1.0 - c),
@@ -75,12 +75,12 @@ impl Mat3D {
             1.0 - 2.0 * b * b,
             0.0 - 2.0 * b * c,
             0.0 - 2.0 * a * c,
-            0.0 * 2.0 * b * c,
+            0.0 - 2.0 * b * c,
             1.0 - 2.0 * c * c,
         ])
     }
 
-    #[cfg_attr(rustfmt, rustfmt_skip)]
+    #[rustfmt::skip]
     pub fn det(&self) -> f32 {
         let m = self;
 
@@ -184,3 +184,52 @@ impl IndexMut<[usize; 2]> for Mat3D {
         &mut self.rows[i][j]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vec3D, b: Vec3D) {
+        assert!((a - b).norm() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    fn vectors() -> [Vec3D; 4] {
+        [
+            Vec3D::x_axis(),
+            Vec3D::y_axis(),
+            Vec3D::z_axis(),
+            Vec3D::new(-0.3, 0.5, 2.0),
+        ]
+    }
+
+    #[test]
+    fn rotation_matches_rodrigues_formula() {
+        let axis = Vec3D::new(1.0, 2.0, 3.0).normalize();
+        let angle = 0.7f32;
+        let (c, s) = (angle.cos(), angle.sin());
+        let m = Mat3D::new_rotation(axis, angle);
+
+        for &v in &vectors() {
+            let expected =
+                v * c + Vec3D::cross(axis, v) * s + axis * Vec3D::dot(axis, v) * (1.0 - c);
+            assert_close(m.apply(v), expected);
+        }
+
+        assert!((m.det() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn reflection_mirrors_along_axis() {
+        let axis = Vec3D::new(1.0, 2.0, 3.0);
+        let n = axis.normalize();
+        let m = Mat3D::new_reflection(axis);
+
+        for &v in &vectors() {
+            assert_close(m.apply(v), v - n * (2.0 * Vec3D::dot(n, v)));
+        }
+
+        let v = Vec3D::new(-0.3, 0.5, 2.0);
+        assert_close(m.multiply(m).apply(v), v);
+        assert!((m.det() + 1.0).abs() < 1e-5);
+    }
+}
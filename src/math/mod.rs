@@ -1,10 +1,17 @@
 mod aabb;
+mod affine;
+mod frame;
+mod interpolate;
 mod mat3d;
 mod quaternion;
 mod ray;
+pub mod sample;
 mod vec3d;
 
 pub use self::aabb::AABB;
+pub use self::affine::Affine3D;
+pub use self::frame::Frame;
+pub use self::interpolate::{interpolate, Interpolation};
 pub use self::mat3d::Mat3D;
 pub use self::quaternion::Quaternion;
 pub use self::ray::Ray;
@@ -1,4 +1,4 @@
-use super::Vec3D;
+use super::{Mat3D, Vec3D};
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub struct Quaternion {
@@ -79,4 +79,93 @@ impl Quaternion {
     pub fn inverse_apply(&self, v: Vec3D) -> Vec3D {
         self.inverse().apply(v)
     }
+
+    // Rotation matrix to quaternion, `m` must be orthonormal with a positive determinant.
+    pub fn from_matrix(m: &Mat3D) -> Self {
+        let trace = m[[0, 0]] + m[[1, 1]] + m[[2, 2]];
+
+        let data = if trace > 0.0 {
+            let s = (trace + 1.0).sqrt() * 2.0;
+            [
+                s / 4.0,
+                (m[[2, 1]] - m[[1, 2]]) / s,
+                (m[[0, 2]] - m[[2, 0]]) / s,
+                (m[[1, 0]] - m[[0, 1]]) / s,
+            ]
+        } else if m[[0, 0]] > m[[1, 1]] && m[[0, 0]] > m[[2, 2]] {
+            let s = (1.0 + m[[0, 0]] - m[[1, 1]] - m[[2, 2]]).sqrt() * 2.0;
+            [
+                (m[[2, 1]] - m[[1, 2]]) / s,
+                s / 4.0,
+                (m[[0, 1]] + m[[1, 0]]) / s,
+                (m[[0, 2]] + m[[2, 0]]) / s,
+            ]
+        } else if m[[1, 1]] > m[[2, 2]] {
+            let s = (1.0 + m[[1, 1]] - m[[0, 0]] - m[[2, 2]]).sqrt() * 2.0;
+            [
+                (m[[0, 2]] - m[[2, 0]]) / s,
+                (m[[0, 1]] + m[[1, 0]]) / s,
+                s / 4.0,
+                (m[[1, 2]] + m[[2, 1]]) / s,
+            ]
+        } else {
+            let s = (1.0 + m[[2, 2]] - m[[0, 0]] - m[[1, 1]]).sqrt() * 2.0;
+            [
+                (m[[1, 0]] - m[[0, 1]]) / s,
+                (m[[0, 2]] + m[[2, 0]]) / s,
+                (m[[1, 2]] + m[[2, 1]]) / s,
+                s / 4.0,
+            ]
+        };
+
+        Quaternion { data }.normalize()
+    }
+
+    pub fn to_matrix(self) -> Mat3D {
+        Mat3D::from_columns([
+            self.apply(Vec3D::x_axis()),
+            self.apply(Vec3D::y_axis()),
+            self.apply(Vec3D::z_axis()),
+        ])
+    }
+
+    #[inline(always)]
+    pub fn dot(&self, other: &Self) -> f32 {
+        let [a, b] = [self.data, other.data];
+        a[0] * b[0] + a[1] * b[1] + a[2] * b[2] + a[3] * b[3]
+    }
+
+    pub fn normalize(&self) -> Self {
+        let norm = self.dot(self).sqrt();
+        Quaternion {
+            data: self.data.map(|v| v / norm),
+        }
+    }
+
+    // Spherical linear interpolation along the shortest arc from `self` (t=0) to `other` (t=1).
+    pub fn slerp(&self, other: &Self, t: f32) -> Self {
+        let mut cos = self.dot(other);
+        let mut other = other.data;
+
+        if cos < 0.0 {
+            cos = -cos;
+            other = other.map(|v| -v);
+        }
+
+        // Nearly parallel, fall back to linear interpolation to avoid dividing by zero.
+        let (a, b) = if cos > 0.9995 {
+            (1.0 - t, t)
+        } else {
+            let theta = cos.acos();
+            let sin = theta.sin();
+            (((1.0 - t) * theta).sin() / sin, (t * theta).sin() / sin)
+        };
+
+        let mut data = [0.0; 4];
+        for (i, x) in data.iter_mut().enumerate() {
+            *x = a * self.data[i] + b * other[i];
+        }
+
+        Quaternion { data }.normalize()
+    }
 }
@@ -5,14 +5,21 @@ pub struct Ray {
     pub pos: Vec3D,
     pub dir: Vec3D,
     pub inv_dir: Vec3D,
+    pub time: f32,
 }
 
 impl Ray {
     pub fn new(pos: Vec3D, dir: Vec3D) -> Self {
+        Self::with_time(pos, dir, 0.0)
+    }
+
+    // Ray at the given moment within the shutter interval, used for motion blur.
+    pub fn with_time(pos: Vec3D, dir: Vec3D, time: f32) -> Self {
         Self {
             pos,
             dir,
             inv_dir: 1.0 / dir,
+            time,
         }
     }
 
@@ -0,0 +1,67 @@
+use super::Vec3D;
+use std::f32::consts::PI;
+
+#[inline(always)]
+pub fn cosine_hemisphere([u, v]: [f32; 2]) -> Vec3D {
+    let theta = u * 2.0 * PI;
+    let r = v.sqrt();
+
+    let x = r * theta.cos();
+    let y = r * theta.sin();
+    let z = (1.0 - v).max(0.0).sqrt();
+
+    Vec3D::new(x, y, z)
+}
+
+#[inline(always)]
+pub fn uniform_sphere([u, v]: [f32; 2]) -> Vec3D {
+    let theta = u * 2.0 * PI;
+    let z = 1.0 - 2.0 * v;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+
+    Vec3D::new(r * theta.cos(), r * theta.sin(), z)
+}
+
+// Uniformly distributed point inside the unit ball.
+#[inline(always)]
+pub fn uniform_ball(u: [f32; 2], w: f32) -> Vec3D {
+    uniform_sphere(u) * w.cbrt()
+}
+
+// Shirley's concentric mapping from the unit square to the unit disk.
+pub fn concentric_disk([u, v]: [f32; 2]) -> [f32; 2] {
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+
+    if a == 0.0 && b == 0.0 {
+        return [0.0, 0.0];
+    }
+
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, PI / 4.0 * (b / a))
+    } else {
+        (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+
+    [r * theta.cos(), r * theta.sin()]
+}
+
+// Uniformly distributed point inside the regular polygon with `n` corners on the unit circle.
+pub fn regular_polygon(n: u32, rotation: f32, [u, v]: [f32; 2]) -> [f32; 2] {
+    // Pick one of the triangles between the center and two consecutive corners, then reuse
+    // the remainder of `u` to sample inside that triangle.
+    let k = ((u * n as f32) as u32).min(n - 1);
+    let u = u * n as f32 - k as f32;
+
+    let corner = |i: u32| {
+        let angle = rotation + 2.0 * PI * (i as f32) / (n as f32);
+        [angle.cos(), angle.sin()]
+    };
+
+    let (a, b) = (corner(k), corner(k + 1));
+    let su = u.sqrt();
+
+    [
+        su * ((1.0 - v) * a[0] + v * b[0]),
+        su * ((1.0 - v) * a[1] + v * b[1]),
+    ]
+}
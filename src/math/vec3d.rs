@@ -4,6 +4,7 @@ use std::ops::{
 };
 
 #[derive(Copy, Clone, PartialEq, PartialOrd)]
+#[repr(transparent)]
 pub struct Vec3D {
     data: [f32; 3],
 }
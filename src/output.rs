@@ -0,0 +1,329 @@
+use crate::texture::Color;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+// Formats that store the linear color buffer without clamping or quantizing it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HdrFormat {
+    Exr,
+    Radiance,
+    Pfm,
+}
+
+impl HdrFormat {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?.to_lowercase();
+
+        Some(match &*ext {
+            "exr" => HdrFormat::Exr,
+            "hdr" | "pic" => HdrFormat::Radiance,
+            "pfm" => HdrFormat::Pfm,
+            _ => return None,
+        })
+    }
+}
+
+pub fn save_hdr<P: AsRef<Path>>(
+    path: P,
+    format: HdrFormat,
+    width: usize,
+    height: usize,
+    buffer: &[Color],
+) -> io::Result<()> {
+    assert_eq!(buffer.len(), width * height, "dimensions mismatch");
+    let mut f = BufWriter::new(File::create(path)?);
+
+    match format {
+        HdrFormat::Exr => write_exr(&mut f, width, height, buffer)?,
+        HdrFormat::Radiance => write_radiance(&mut f, width, height, buffer)?,
+        HdrFormat::Pfm => write_pfm(&mut f, width, height, buffer)?,
+    }
+
+    f.flush()
+}
+
+fn exr_attribute(header: &mut Vec<u8>, name: &str, typ: &str, value: &[u8]) {
+    header.extend(name.as_bytes());
+    header.push(0);
+    header.extend(typ.as_bytes());
+    header.push(0);
+    header.extend(&(value.len() as i32).to_le_bytes());
+    header.extend(value);
+}
+
+// Single-part scanline OpenEXR file with uncompressed 32-bit float channels.
+pub fn write_exr<W: Write>(
+    f: &mut W,
+    width: usize,
+    height: usize,
+    buffer: &[Color],
+) -> io::Result<()> {
+    let int = |x: usize| (x as i32).to_le_bytes();
+    let float = |x: f32| x.to_le_bytes();
+
+    // Channels must be sorted by name, which is also their order within each scanline.
+    let mut channels = vec![];
+    for name in &["B", "G", "R"] {
+        channels.extend(name.as_bytes());
+        channels.push(0);
+        channels.extend(&int(2)); // pixel type FLOAT
+        channels.extend(&[0, 0, 0, 0]); // pLinear and reserved
+        channels.extend(&int(1)); // xSampling
+        channels.extend(&int(1)); // ySampling
+    }
+    channels.push(0);
+
+    let window = [int(0), int(0), int(width - 1), int(height - 1)].concat();
+
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+    exr_attribute(&mut header, "channels", "chlist", &channels);
+    exr_attribute(&mut header, "compression", "compression", &[0]);
+    exr_attribute(&mut header, "dataWindow", "box2i", &window);
+    exr_attribute(&mut header, "displayWindow", "box2i", &window);
+    exr_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    exr_attribute(&mut header, "pixelAspectRatio", "float", &float(1.0));
+    exr_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    exr_attribute(&mut header, "screenWindowWidth", "float", &float(1.0));
+    header.push(0);
+    f.write_all(&header)?;
+
+    // Offset table, followed by one chunk per scanline.
+    let chunk_size = 8 + 12 * width;
+    let start = header.len() + 8 * height;
+
+    for y in 0..height {
+        let offset = (start + y * chunk_size) as u64;
+        f.write_all(&offset.to_le_bytes())?;
+    }
+
+    for (y, row) in buffer.chunks(width).enumerate() {
+        f.write_all(&int(y))?;
+        f.write_all(&int(12 * width))?;
+
+        for c in (0..3).rev() {
+            for pixel in row {
+                f.write_all(&float(pixel[c]))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn rgbe(c: Color) -> [u8; 4] {
+    let v = max!(c[0], c[1], c[2]);
+
+    if v.is_nan() || v <= 1e-32 {
+        return [0, 0, 0, 0];
+    }
+
+    // Shared exponent such that the largest component has a mantissa in [0.5, 1).
+    let exp = v.log2().floor() as i32 + 1;
+    let scale = 256.0 / 2.0f32.powi(exp);
+    let byte = |x: f32| (x.max(0.0) * scale).min(255.0) as u8;
+
+    [
+        byte(c[0]),
+        byte(c[1]),
+        byte(c[2]),
+        (exp + 128).clamp(0, 255) as u8,
+    ]
+}
+
+// Radiance RGBE, scanlines use the run-length format but only with literal runs.
+pub fn write_radiance<W: Write>(
+    f: &mut W,
+    width: usize,
+    height: usize,
+    buffer: &[Color],
+) -> io::Result<()> {
+    write!(
+        f,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        height, width
+    )?;
+
+    let encoded = buffer.iter().map(|&c| rgbe(c)).collect::<Vec<_>>();
+    let run_length = (8..0x8000).contains(&width);
+    let mut channel = vec![];
+
+    for row in encoded.chunks(width) {
+        if !run_length {
+            for pixel in row {
+                f.write_all(pixel)?;
+            }
+
+            continue;
+        }
+
+        f.write_all(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8])?;
+
+        for c in 0..4 {
+            channel.clear();
+            channel.extend(row.iter().map(|p| p[c]));
+
+            for run in channel.chunks(128) {
+                f.write_all(&[run.len() as u8])?;
+                f.write_all(run)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Portable float map, a negative scale marks little-endian and rows go bottom to top.
+pub fn write_pfm<W: Write>(
+    f: &mut W,
+    width: usize,
+    height: usize,
+    buffer: &[Color],
+) -> io::Result<()> {
+    write!(f, "PF\n{} {}\n-1.0\n", width, height)?;
+
+    for row in buffer.chunks(width).rev() {
+        for pixel in row {
+            for c in 0..3 {
+                f.write_all(&pixel[c].to_le_bytes())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::convert::TryInto;
+
+    // Colors spanning several orders of magnitude, including black and a pixel above one.
+    fn image(width: usize, height: usize) -> Vec<Color> {
+        (0..width * height)
+            .map(|i| {
+                let x = i as f32;
+                Color::new(
+                    (x * 0.37).sin().abs() * 10f32.powi(i as i32 % 7 - 3),
+                    iff!(i % 5 == 0, 0.0, x / 3.0),
+                    1.0 / (1.0 + x),
+                )
+            })
+            .collect()
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_f32(data: &[u8], offset: usize) -> f32 {
+        f32::from_bits(read_u32(data, offset))
+    }
+
+    // Reads back the attributes and scanlines of an uncompressed float EXR.
+    fn read_exr(data: &[u8]) -> (usize, usize, Vec<Color>) {
+        assert_eq!(data[..8], [0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+        let mut pos = 8;
+        let mut attributes = HashMap::new();
+
+        let string = |pos: &mut usize| {
+            let end = *pos + data[*pos..].iter().position(|&b| b == 0).unwrap();
+            let s = String::from_utf8(data[*pos..end].to_vec()).unwrap();
+            *pos = end + 1;
+            s
+        };
+
+        loop {
+            let name = string(&mut pos);
+            if name.is_empty() {
+                break;
+            }
+
+            let typ = string(&mut pos);
+            let size = read_u32(data, pos) as usize;
+            attributes.insert(name, (typ, &data[pos + 4..pos + 4 + size]));
+            pos += 4 + size;
+        }
+
+        assert_eq!(attributes["compression"].1, [0]);
+        assert_eq!(attributes["channels"].0, "chlist");
+        let window = attributes["dataWindow"].1;
+        let width = read_u32(window, 8) as usize + 1;
+        let height = read_u32(window, 12) as usize + 1;
+        let mut buffer = vec![Color::zero(); width * height];
+
+        for i in 0..height {
+            let offset = u64::from_le_bytes(data[pos + 8 * i..][..8].try_into().unwrap());
+            let chunk = offset as usize;
+            let y = read_u32(data, chunk) as usize;
+            assert_eq!(read_u32(data, chunk + 4) as usize, 12 * width);
+
+            // Channels are stored as B, G and R.
+            for x in 0..width {
+                let channel = |c: usize| read_f32(data, chunk + 8 + 4 * (c * width + x));
+                buffer[y * width + x] = Color::new(channel(2), channel(1), channel(0));
+            }
+        }
+
+        (width, height, buffer)
+    }
+
+    #[test]
+    fn exr_round_trip() {
+        let (width, height) = (7, 4);
+        let buffer = image(width, height);
+        let mut data = vec![];
+        write_exr(&mut data, width, height, &buffer).unwrap();
+
+        assert_eq!(read_exr(&data), (width, height, buffer));
+    }
+
+    #[test]
+    fn pfm_round_trip() {
+        let (width, height) = (5, 3);
+        let buffer = image(width, height);
+        let mut data = vec![];
+        write_pfm(&mut data, width, height, &buffer).unwrap();
+
+        let header = format!("PF\n{} {}\n-1.0\n", width, height);
+        assert!(data.starts_with(header.as_bytes()));
+        assert_eq!(data.len(), header.len() + 12 * width * height);
+
+        // Rows are stored bottom to top.
+        for y in 0..height {
+            for x in 0..width {
+                let at = header.len() + 12 * ((height - 1 - y) * width + x);
+                let pixel = buffer[y * width + x];
+
+                for c in 0..3 {
+                    assert_eq!(read_f32(&data, at + 4 * c), pixel[c]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn radiance_round_trip() {
+        // Narrow images are written flat, wider ones use run-length scanlines.
+        for &(width, height) in &[(3, 2), (200, 3)] {
+            let buffer = image(width, height);
+            let mut data = vec![];
+            write_radiance(&mut data, width, height, &buffer).unwrap();
+
+            let decoder = image::hdr::HDRDecoder::new(&data[..]).unwrap();
+            let meta = decoder.metadata();
+            assert_eq!((meta.width, meta.height), (width as u32, height as u32));
+
+            let pixels = decoder.read_image_hdr().unwrap();
+            for (p, c) in pixels.iter().zip(&buffer) {
+                // The shared exponent leaves 8 bits of mantissa for the largest component.
+                let tolerance = max!(c[0], c[1], c[2]) / 128.0;
+
+                for i in 0..3 {
+                    assert!((p[i] - c[i]).abs() <= tolerance, "{:?} != {:?}", p, c);
+                }
+            }
+        }
+    }
+}
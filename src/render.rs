@@ -1,75 +1,618 @@
-use crate::integrator::WhittedIntegrator as Integrator;
+use crate::geom::PACKET_SIZE;
+use crate::integrator::Integrator;
+use crate::math::{Ray, Vec3D};
+use crate::sampler::{Sampler, SamplerKind};
 use crate::scene::Scene;
 use crate::texture::Color;
-use arrayvec::ArrayVec;
+use crate::tonemap::ToneMapping;
 use image::RgbImage;
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
-use std::time::SystemTime;
+use std::time::{Duration, Instant};
 
-pub fn parallel_render(scene: &Scene, integrator: &Integrator) -> Box<[Color]> {
-    let (width, height) = scene.camera.dimensions();
-    let mut buffer = vec![];
-
-    let style = ProgressStyle::default_bar()
-        .template("  {bar:50} {percent}%, {elapsed_precise} (eta: {eta_precise})")
-        .progress_chars("\u{2588}\u{2592}\u{2591}");
-    let progress = ProgressBar::new((width * height) as u64);
-    progress.set_style(style);
-    progress.enable_steady_tick(1000);
-    let progress_ref = &progress;
+#[derive(Clone, Debug)]
+pub struct RenderOptions {
+    pub quiet: bool,
+    pub tone_mapping: ToneMapping,
+    pub antialiasing: i32,
+    pub seed: u64,
+    pub sampler: SamplerKind,
+    pub max_samples: Option<u32>,
+    pub time_limit: Option<Duration>,
+    pub noise_threshold: Option<f32>,
+    pub adaptive_threshold: Option<f32>,
+    pub min_samples: u32,
+    pub checkpoint_interval: Option<Duration>,
+}
+
+impl RenderOptions {
+    pub fn new() -> Self {
+        Self {
+            quiet: false,
+            tone_mapping: ToneMapping::new(),
+            antialiasing: 1,
+            seed: 0,
+            sampler: SamplerKind::Stratified,
+            max_samples: None,
+            time_limit: None,
+            noise_threshold: None,
+            adaptive_threshold: None,
+            min_samples: 16,
+            checkpoint_interval: None,
+        }
+    }
+
+    pub fn samples_per_pass(&self) -> u32 {
+        (self.antialiasing * self.antialiasing) as u32
+    }
+
+    pub fn create_sampler(&self) -> Box<dyn Sampler> {
+        self.sampler.create(self.seed, self.samples_per_pass())
+    }
+
+    // Number of passes needed to reach `max_samples`. Without any stopping criteria, a single
+    // pass is rendered. Returns `None` if the number of passes is not known in advance.
+    pub fn max_passes(&self) -> Option<u32> {
+        let unbounded = self.time_limit.is_some()
+            || self.noise_threshold.is_some()
+            || self.adaptive_threshold.is_some();
+
+        match self.max_samples {
+            Some(n) => Some(n.div_ceil(self.samples_per_pass()).max(1)),
+            None if !unbounded => Some(1),
+            None => None,
+        }
+    }
+
+    // With adaptive sampling, pixels only receive more samples while their estimated error is
+    // above the threshold, once they have at least `min_samples` samples.
+    pub fn wants_samples(&self, stats: &PixelStats) -> bool {
+        if self.max_samples.is_some_and(|n| stats.count >= n) {
+            return false;
+        }
+
+        match self.adaptive_threshold {
+            Some(t) => stats.count < self.min_samples || stats.relative_error() > t,
+            None => true,
+        }
+    }
+}
+
+// Running statistics of the samples taken for a single pixel.
+#[derive(Copy, Clone, Debug)]
+pub struct PixelStats {
+    pub sum: Color,
+    pub sum_sq: Color,
+    pub count: u32,
+}
+
+// Pixels whose mean is darker than this are treated as if they had this brightness when
+// estimating their relative error, otherwise near-black pixels would never converge.
+const MIN_ERROR_BRIGHTNESS: f32 = 0.01;
+
+impl PixelStats {
+    pub fn new() -> Self {
+        Self {
+            sum: Color::zero(),
+            sum_sq: Color::zero(),
+            count: 0,
+        }
+    }
+
+    pub fn add(&mut self, c: Color) {
+        self.sum += c;
+        self.sum_sq += c * c;
+        self.count += 1;
+    }
+
+    pub fn mean(&self) -> Color {
+        if self.count == 0 {
+            return Color::zero();
+        }
+
+        self.sum / self.count as f32
+    }
+
+    // Standard error of the mean relative to the brightness of the pixel.
+    pub fn relative_error(&self) -> f32 {
+        if self.count < 2 {
+            return f32::INFINITY;
+        }
+
+        let n = self.count as f32;
+        let mean = self.sum / n;
+        let variance = (self.sum_sq - mean * self.sum) / (n - 1.0);
+        let variance = (variance[0] + variance[1] + variance[2]).max(0.0) / 3.0;
+        let brightness = (mean[0] + mean[1] + mean[2]) / 3.0;
+
+        (variance / n).sqrt() / brightness.max(MIN_ERROR_BRIGHTNESS)
+    }
+}
+
+// Accumulates the samples of several render passes.
+#[derive(Clone, Debug)]
+pub struct Accumulator {
+    width: usize,
+    height: usize,
+    passes: u32,
+    pixels: Vec<PixelStats>,
+}
+
+impl Accumulator {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            passes: 0,
+            pixels: vec![PixelStats::new(); width * height],
+        }
+    }
+
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
 
-    let before = SystemTime::now();
-    (0..width * height)
-        .into_par_iter()
-        .with_min_len(100)
-        .with_max_len(100)
-        .map(move |index| {
-            let x = index % width;
-            let y = index / width;
-
-            if x == 0 {
-                progress_ref.inc(width as u64);
+    pub fn passes(&self) -> u32 {
+        self.passes
+    }
+
+    pub fn pixels(&self) -> &[PixelStats] {
+        &self.pixels
+    }
+
+    pub fn mean(&self) -> Box<[Color]> {
+        self.pixels.iter().map(PixelStats::mean).collect()
+    }
+
+    pub fn samples_per_pixel(&self) -> f64 {
+        let total = self.pixels.iter().map(|p| p.count as f64).sum::<f64>();
+        total / self.pixels.len().max(1) as f64
+    }
+
+    // Number of samples taken per pixel, useful to visualize where adaptive sampling spent its
+    // samples.
+    pub fn sample_counts(&self) -> Box<[Color]> {
+        self.pixels
+            .iter()
+            .map(|p| Color::fill(p.count as f32))
+            .collect()
+    }
+
+    // Average relative error over the pixels with at least two samples, the error of the other
+    // pixels is unknown. Returns `None` if no pixel has enough samples yet.
+    pub fn noise(&self) -> Option<f32> {
+        let (total, count) = self
+            .pixels
+            .iter()
+            .filter(|p| p.count >= 2)
+            .fold((0.0, 0), |(total, count), p| {
+                (total + p.relative_error() as f64, count + 1)
+            });
+
+        if count == 0 {
+            return None;
+        }
+
+        Some((total / count as f64) as f32)
+    }
+}
+
+// Starts the next sample of the given pixel and generates its camera ray.
+fn camera_ray(scene: &Scene, sampler: &mut dyn Sampler, cx: usize, cy: usize, index: u32) -> Ray {
+    sampler.start_sample([cx, cy], index);
+
+    let [dx, dy] = sampler.next_2d();
+    let x = (cx as f32) + dx - 0.5;
+    let y = (cy as f32) + dy - 0.5;
+
+    let lens = sampler.next_2d();
+    let time = sampler.next_1d();
+    scene.camera.generate_ray(x, y, lens, time)
+}
+
+// Takes `samples_per_pass` more samples for the given pixel. The sample indices continue where
+// the previous pass stopped, so the sampler keeps its distribution over multiple passes.
+pub fn sample_pixel<I>(
+    scene: &Scene,
+    integrator: &I,
+    options: &RenderOptions,
+    sampler: &mut dyn Sampler,
+    cx: usize,
+    cy: usize,
+    stats: &mut PixelStats,
+) where
+    I: Integrator + ?Sized,
+{
+    for _ in 0..options.samples_per_pass() {
+        let ray = camera_ray(scene, sampler, cx, cy, stats.count);
+        stats.add(integrator.radiance(scene, &ray, sampler));
+    }
+}
+
+// Same as `sample_pixel` for up to `PACKET_SIZE` consecutive pixels starting at `index`, such that
+// their camera rays can be traced as a packet. Every pixel has its own sampler, so the result is
+// the same as sampling the pixels one by one.
+pub fn sample_pixels<I>(
+    scene: &Scene,
+    integrator: &I,
+    options: &RenderOptions,
+    samplers: &mut [Box<dyn Sampler>; PACKET_SIZE],
+    index: usize,
+    stats: &mut [PixelStats],
+) where
+    I: Integrator + ?Sized,
+{
+    let (width, _) = scene.camera.dimensions();
+    let mut active = [false; PACKET_SIZE];
+
+    for (lane, stats) in stats.iter().enumerate() {
+        active[lane] = options.wants_samples(stats);
+    }
+
+    if !active.contains(&true) {
+        return;
+    }
+
+    for _ in 0..options.samples_per_pass() {
+        let mut rays = [Ray::new(Vec3D::zero(), Vec3D::z_axis()); PACKET_SIZE];
+
+        for (lane, stats) in stats.iter().enumerate() {
+            if active[lane] {
+                let (cx, cy) = ((index + lane) % width, (index + lane) / width);
+                rays[lane] = camera_ray(scene, &mut *samplers[lane], cx, cy, stats.count);
             }
+        }
+
+        let colors = integrator.radiance_packet(scene, &rays, active, samplers);
+
+        for (lane, stats) in stats.iter_mut().enumerate() {
+            if active[lane] {
+                stats.add(colors[lane]);
+            }
+        }
+    }
+}
+
+pub fn calculate_pixel<I>(
+    scene: &Scene,
+    integrator: &I,
+    options: &RenderOptions,
+    cx: usize,
+    cy: usize,
+) -> Color
+where
+    I: Integrator + ?Sized,
+{
+    let mut stats = PixelStats::new();
+    let mut sampler = options.create_sampler();
+    sample_pixel(
+        scene,
+        integrator,
+        options,
+        &mut *sampler,
+        cx,
+        cy,
+        &mut stats,
+    );
+    stats.mean()
+}
 
-            integrator.calculate_pixel(scene, x, y)
-        })
-        .collect_into_vec(&mut buffer);
-    let elapsed = before.elapsed().unwrap();
-    let time = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64) * 1e-9;
+fn format_noise(noise: Option<f32>) -> String {
+    match noise {
+        Some(noise) => format!("{:.4}", noise),
+        None => "n/a".to_string(),
+    }
+}
 
+fn format_duration(time: Duration) -> String {
+    let time = time.as_secs_f64();
     let minutes = (time / 60.0).floor() as i32;
     let seconds = (time % 60.0).ceil() as i32;
+    format!("{:02}:{:02}", minutes, seconds)
+}
+
+// Renders the scene in passes of `antialiasing²` samples per pixel until one of the stopping
+// criteria in `options` is met. `checkpoint` is called with the intermediate result every
+// `checkpoint_interval`.
+pub fn progressive_render<I, F>(
+    scene: &Scene,
+    integrator: &I,
+    options: &RenderOptions,
+    mut checkpoint: F,
+) -> Accumulator
+where
+    I: Integrator + ?Sized,
+    F: FnMut(&Accumulator),
+{
+    let (width, height) = scene.camera.dimensions();
+    let mut acc = Accumulator::new(width, height);
+    let max_passes = options.max_passes();
+
+    let progress = if options.quiet {
+        ProgressBar::hidden()
+    } else {
+        let style = ProgressStyle::default_bar()
+            .template("  {bar:50} {percent}%, {elapsed_precise} (eta: {eta_precise}) {msg}")
+            .progress_chars("\u{2588}\u{2592}\u{2591}");
+        let length = width * height * max_passes.unwrap_or(1) as usize;
+        let progress = ProgressBar::new(length as u64);
+        progress.set_style(style);
+        progress.enable_steady_tick(1000);
+        progress
+    };
+    let progress_ref = &progress;
+
+    let before = Instant::now();
+    let mut last_checkpoint = before;
+
+    loop {
+        let pass_start = Instant::now();
+
+        // Without a fixed number of passes, the bar only shows the progress of the current pass.
+        if max_passes.is_none() {
+            progress.set_position(0);
+            progress.reset_eta();
+        }
+
+        // Pixels are sampled in groups of neighbouring pixels, which trace their rays as packets.
+        acc.pixels
+            .par_chunks_mut(PACKET_SIZE)
+            .enumerate()
+            .with_min_len(100 / PACKET_SIZE)
+            .with_max_len(100 / PACKET_SIZE)
+            .for_each_init(
+                || std::array::from_fn(|_| options.create_sampler()),
+                move |samplers, (chunk, stats)| {
+                    let index = chunk * PACKET_SIZE;
+                    let rows = (index..index + stats.len())
+                        .filter(|i| i % width == 0)
+                        .count();
+
+                    if rows > 0 {
+                        progress_ref.inc((rows * width) as u64);
+                    }
+
+                    sample_pixels(scene, integrator, options, samplers, index, stats);
+                },
+            );
+        acc.passes += 1;
+
+        let noise = acc.noise();
+        let active = acc
+            .pixels
+            .iter()
+            .filter(|p| options.wants_samples(p))
+            .count();
+        progress.set_message(&format!(
+            "pass {}, {:.1} spp, noise {}, {} active",
+            acc.passes,
+            acc.samples_per_pixel(),
+            format_noise(noise),
+            active
+        ));
 
+        if active == 0 || max_passes.is_some_and(|n| acc.passes >= n) {
+            break;
+        }
+
+        if options
+            .noise_threshold
+            .zip(noise)
+            .is_some_and(|(t, noise)| noise <= t)
+        {
+            break;
+        }
+
+        // Stop if the next pass would probably not finish within the time limit.
+        if let Some(limit) = options.time_limit {
+            if before.elapsed() + pass_start.elapsed() > limit {
+                break;
+            }
+        }
+
+        if let Some(interval) = options.checkpoint_interval {
+            if last_checkpoint.elapsed() >= interval {
+                checkpoint(&acc);
+                last_checkpoint = Instant::now();
+            }
+        }
+    }
+
+    let elapsed = before.elapsed();
     progress.finish_and_clear();
+
+    if options.quiet {
+        return acc;
+    }
+
     println!(
-        "Rendered {}x{}={} pixels in {:02}:{:02} ({:.3} sec/pixel)",
+        "Rendered {}x{}={} pixels in {} ({:.3} sec/pixel, {} passes, {:.1} spp, noise {})",
         width,
         height,
         width * height,
-        minutes,
-        seconds,
-        time / (width as f64 * height as f64)
+        format_duration(elapsed),
+        elapsed.as_secs_f64() / (width as f64 * height as f64),
+        acc.passes,
+        acc.samples_per_pixel(),
+        format_noise(acc.noise()),
     );
 
-    buffer.into_boxed_slice()
+    acc
+}
+
+pub fn parallel_render<I>(scene: &Scene, integrator: &I, options: &RenderOptions) -> Box<[Color]>
+where
+    I: Integrator + ?Sized,
+{
+    progressive_render(scene, integrator, options, |_| ()).mean()
 }
 
-pub fn parallel_render_image(scene: &Scene, integrator: &Integrator) -> RgbImage {
+pub fn parallel_render_image<I>(scene: &Scene, integrator: &I, options: &RenderOptions) -> RgbImage
+where
+    I: Integrator + ?Sized,
+{
     let (width, height) = scene.camera.dimensions();
-    let buffer = parallel_render(scene, integrator);
-
-    let pixels = buffer
-        .iter()
-        .flat_map(|c| -> ArrayVec<_> {
-            [
-                (c[0] * 256.0).floor().max(0.0).min(255.0) as u8,
-                (c[1] * 256.0).floor().max(0.0).min(255.0) as u8,
-                (c[2] * 256.0).floor().max(0.0).min(255.0) as u8,
-            ]
-            .into()
-        })
-        .collect::<Vec<_>>();
-
-    RgbImage::from_raw(width as u32, height as u32, pixels).expect("dimensions mismatch")
+    let buffer = parallel_render(scene, integrator, options);
+    options.tone_mapping.apply(width, height, &buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::{GeometryList, Object};
+    use crate::scene::Camera;
+    use crate::texture::COLOR_BLACK;
+    use std::sync::Arc;
+
+    // Ignores the scene and returns uniform noise of the given amplitude around a brightness of
+    // one, optionally sleeping to simulate an expensive integrator.
+    struct NoiseIntegrator {
+        amplitude: f32,
+        delay: Duration,
+    }
+
+    impl Integrator for NoiseIntegrator {
+        fn radiance(&self, _scene: &Scene, _ray: &Ray, sampler: &mut dyn Sampler) -> Color {
+            std::thread::sleep(self.delay);
+            Color::fill(1.0 + self.amplitude * (sampler.next_1d() - 0.5))
+        }
+    }
+
+    // Only the right half of the image is noisy.
+    struct HalfNoiseIntegrator;
+
+    impl Integrator for HalfNoiseIntegrator {
+        fn radiance(&self, _scene: &Scene, ray: &Ray, sampler: &mut dyn Sampler) -> Color {
+            let amplitude = iff!(ray.dir[0] > 0.0, 1.0, 0.0);
+            Color::fill(1.0 + amplitude * (sampler.next_1d() - 0.5))
+        }
+    }
+
+    fn empty_scene(width: usize, height: usize) -> Scene {
+        Scene {
+            root: Arc::new(GeometryList::<Object>::new()),
+            skybox: Arc::new(COLOR_BLACK),
+            lights: vec![],
+            camera: Camera::new(width, height),
+        }
+    }
+
+    fn quiet_options() -> RenderOptions {
+        let mut options = RenderOptions::new();
+        options.quiet = true;
+        options
+    }
+
+    #[test]
+    fn noise_ignores_pixels_without_enough_samples() {
+        let mut acc = Accumulator::new(2, 1);
+        assert_eq!(acc.noise(), None);
+
+        acc.pixels[0].add(Color::fill(1.0));
+        acc.pixels[1].add(Color::fill(1.0));
+        assert_eq!(acc.noise(), None);
+
+        acc.pixels[0].add(Color::fill(1.0));
+        assert_eq!(acc.noise(), Some(0.0));
+    }
+
+    #[test]
+    fn stops_at_sample_limit() {
+        let scene = empty_scene(4, 4);
+        let integrator = NoiseIntegrator {
+            amplitude: 1.0,
+            delay: Duration::from_secs(0),
+        };
+
+        let mut options = quiet_options();
+        options.antialiasing = 2;
+        options.max_samples = Some(10);
+
+        // Passes are never split, so the limit is rounded up to whole passes.
+        let acc = progressive_render(&scene, &integrator, &options, |_| ());
+        assert_eq!(acc.passes(), 3);
+        assert!(acc.pixels().iter().all(|p| p.count == 12));
+    }
+
+    #[test]
+    fn stops_at_time_limit() {
+        let scene = empty_scene(4, 4);
+        let integrator = NoiseIntegrator {
+            amplitude: 1.0,
+            delay: Duration::from_millis(1),
+        };
+
+        let mut options = quiet_options();
+        options.time_limit = Some(Duration::from_millis(100));
+
+        let before = Instant::now();
+        let acc = progressive_render(&scene, &integrator, &options, |_| ());
+        let elapsed = before.elapsed();
+
+        // The render stops before a pass would exceed the limit, the margin only allows for a
+        // slow machine.
+        assert!(acc.passes() > 1);
+        assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn stops_at_noise_limit() {
+        let scene = empty_scene(4, 4);
+        let integrator = NoiseIntegrator {
+            amplitude: 1.0,
+            delay: Duration::from_secs(0),
+        };
+
+        let mut options = quiet_options();
+        options.sampler = SamplerKind::Independent;
+        options.noise_threshold = Some(0.05);
+
+        // The relative error of uniform noise is sqrt(1 / (12 n)), which reaches 0.05 after
+        // about 33 samples. The first pass has a single sample, so its noise is unknown and must
+        // not stop the render.
+        let acc = progressive_render(&scene, &integrator, &options, |_| ());
+        let noise = acc.noise().unwrap();
+        assert!(noise <= 0.05, "noise {}", noise);
+        assert!(
+            acc.passes() > 20 && acc.passes() < 50,
+            "{} passes",
+            acc.passes()
+        );
+    }
+
+    #[test]
+    fn adaptive_sampling_follows_variance() {
+        // With an odd width, no pixel straddles the center of the image.
+        let scene = empty_scene(5, 3);
+
+        let mut options = quiet_options();
+        options.sampler = SamplerKind::Independent;
+        options.adaptive_threshold = Some(0.05);
+        options.min_samples = 4;
+        options.max_samples = Some(256);
+
+        let acc = progressive_render(&scene, &HalfNoiseIntegrator, &options, |_| ());
+        let counts = acc.sample_counts();
+        let mut noisy = 0;
+
+        for (i, stats) in acc.pixels().iter().enumerate() {
+            let (cx, cy) = (i % 5, i / 5);
+            let ray = scene
+                .camera
+                .generate_ray(cx as f32, cy as f32, [0.5, 0.5], 0.0);
+
+            // Constant pixels have no variance and stop as soon as they may, the noisy ones need
+            // about 33 samples to reach the threshold.
+            if ray.dir[0] > 0.0 {
+                noisy += 1;
+                assert!(stats.count > 16, "pixel {}: {} samples", i, stats.count);
+            } else {
+                assert_eq!(stats.count, options.min_samples, "pixel {}", i);
+            }
+
+            assert_eq!(counts[i], Color::fill(stats.count as f32));
+        }
+
+        assert!(noisy > 0 && noisy < 15);
+    }
 }
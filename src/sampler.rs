@@ -0,0 +1,603 @@
+use lazy_static::lazy_static;
+use rand::prelude::*;
+
+// Source of the random numbers used while rendering. Every pixel sample is a separate stream
+// and each call to `next_1d` or `next_2d` advances to the next dimension of that stream, so the
+// numbers only depend on the pixel, the sample index and the dimension.
+pub trait Sampler: Send {
+    fn start_sample(&mut self, pixel: [usize; 2], index: u32);
+    fn next_1d(&mut self) -> f32;
+    fn next_2d(&mut self) -> [f32; 2];
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+    BlueNoise,
+}
+
+impl SamplerKind {
+    // `strata` is the number of samples a stratified sampler should stratify over.
+    pub fn create(self, seed: u64, strata: u32) -> Box<dyn Sampler> {
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler::new(seed)),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::new(seed, strata)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => Box::new(SobolSampler::new(seed)),
+            SamplerKind::BlueNoise => Box::new(BlueNoiseSampler::new(seed)),
+        }
+    }
+}
+
+// Finalizer of SplitMix64.
+fn mix(mut h: u64) -> u64 {
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^= h >> 31;
+    h
+}
+
+pub fn hash(values: &[u64]) -> u64 {
+    values
+        .iter()
+        .fold(0x6a09_e667_f3bc_c908, |h, &v| mix(h ^ mix(v)))
+}
+
+fn hash_float(values: &[u64]) -> f32 {
+    (hash(values) >> 40) as f32 / (1 << 24) as f32
+}
+
+// Largest float below one.
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+fn fract(x: f64) -> f32 {
+    ((x - x.floor()) as f32).min(ONE_MINUS_EPSILON)
+}
+
+fn u32_to_float(x: u32) -> f32 {
+    (x >> 8) as f32 / (1 << 24) as f32
+}
+
+fn pixel_seed(seed: u64, [x, y]: [usize; 2]) -> u64 {
+    hash(&[seed, x as u64, y as u64])
+}
+
+pub struct IndependentSampler {
+    seed: u64,
+    rng: SmallRng,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: SmallRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_sample(&mut self, pixel: [usize; 2], index: u32) {
+        let seed = hash(&[pixel_seed(self.seed, pixel), index as u64]);
+        self.rng = SmallRng::seed_from_u64(seed);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        self.rng.gen()
+    }
+
+    fn next_2d(&mut self) -> [f32; 2] {
+        [self.rng.gen(), self.rng.gen()]
+    }
+}
+
+// Random permutation of `0..len` indexed by `i`, from Kensler's "Correlated Multi-Jittered
+// Sampling". It avoids having to store the permutation.
+fn permute(mut i: u32, len: u32, p: u32) -> u32 {
+    let mut w = len - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+
+        if i < len {
+            break;
+        }
+    }
+
+    i.wrapping_add(p) % len
+}
+
+// Every consecutive block of `strata` samples is jittered over `strata` strata in each
+// dimension, using a different permutation of the strata per dimension. Two-dimensional samples
+// use a grid if `strata` is a square.
+pub struct StratifiedSampler {
+    seed: u64,
+    strata: u32,
+    grid: Option<u32>,
+    pixel: u64,
+    index: u32,
+    dim: u64,
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u64, strata: u32) -> Self {
+        let strata = strata.max(1);
+        let n = (strata as f64).sqrt().round() as u32;
+
+        Self {
+            seed,
+            strata,
+            grid: iff!(n * n == strata, Some(n), None),
+            pixel: 0,
+            index: 0,
+            dim: 0,
+        }
+    }
+
+    fn next_stratum(&mut self) -> (u32, u64) {
+        let (round, i) = (self.index / self.strata, self.index % self.strata);
+        let seed = hash(&[self.pixel, self.dim, round as u64]);
+        self.dim += 1;
+
+        (permute(i, self.strata, seed as u32), seed)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, pixel: [usize; 2], index: u32) {
+        self.pixel = pixel_seed(self.seed, pixel);
+        self.index = index;
+        self.dim = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let (stratum, seed) = self.next_stratum();
+        let jitter = hash_float(&[seed, self.index as u64]);
+
+        ((stratum as f32 + jitter) / self.strata as f32).min(ONE_MINUS_EPSILON)
+    }
+
+    fn next_2d(&mut self) -> [f32; 2] {
+        let n = match self.grid {
+            Some(n) => n,
+            None => return [self.next_1d(), self.next_1d()],
+        };
+
+        let (stratum, seed) = self.next_stratum();
+        let jx = hash_float(&[seed, self.index as u64, 0]);
+        let jy = hash_float(&[seed, self.index as u64, 1]);
+
+        [
+            (((stratum % n) as f32 + jx) / n as f32).min(ONE_MINUS_EPSILON),
+            (((stratum / n) as f32 + jy) / n as f32).min(ONE_MINUS_EPSILON),
+        ]
+    }
+}
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+fn radical_inverse(mut index: u32, base: u32) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut factor = inv_base;
+    let mut result = 0.0;
+
+    while index > 0 {
+        result += (index % base) as f64 * factor;
+        index /= base;
+        factor *= inv_base;
+    }
+
+    result
+}
+
+// Halton sequence, randomized per pixel and dimension using a Cranley-Patterson rotation.
+// Dimensions beyond the table of primes fall back to independent random numbers.
+pub struct HaltonSampler {
+    seed: u64,
+    pixel: u64,
+    index: u32,
+    dim: usize,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pixel: 0,
+            index: 0,
+            dim: 0,
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, pixel: [usize; 2], index: u32) {
+        self.pixel = pixel_seed(self.seed, pixel);
+        self.index = index;
+        self.dim = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let dim = self.dim;
+        self.dim += 1;
+
+        let base = match PRIMES.get(dim) {
+            Some(&base) => base,
+            None => return hash_float(&[self.pixel, dim as u64, self.index as u64]),
+        };
+
+        let offset = hash_float(&[self.pixel, dim as u64]) as f64;
+        fract(radical_inverse(self.index, base) + offset)
+    }
+
+    fn next_2d(&mut self) -> [f32; 2] {
+        [self.next_1d(), self.next_1d()]
+    }
+}
+
+// Direction numbers of the second Sobol dimension, the first is simply the reversed index.
+const SOBOL_DIRECTIONS: [u32; 32] = {
+    let mut v = [0; 32];
+    let mut i = 1;
+    v[0] = 1 << 31;
+
+    while i < 32 {
+        v[i] = v[i - 1] ^ (v[i - 1] >> 1);
+        i += 1;
+    }
+
+    v
+};
+
+fn sobol_2d(index: u32) -> [u32; 2] {
+    let mut y = 0;
+
+    for (bit, &v) in SOBOL_DIRECTIONS.iter().enumerate() {
+        if index & (1 << bit) != 0 {
+            y ^= v;
+        }
+    }
+
+    [index.reverse_bits(), y]
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// Owen-scrambled Sobol following Burley's "Practical Hash-based Owen Scrambling". Every pair of
+// dimensions uses the first two Sobol dimensions with an independently shuffled index and
+// independently scrambled values.
+pub struct SobolSampler {
+    seed: u64,
+    pixel: u64,
+    index: u32,
+    dim: u64,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pixel: 0,
+            index: 0,
+            dim: 0,
+        }
+    }
+
+    fn next_point(&mut self) -> [f32; 2] {
+        let seed = hash(&[self.pixel, self.dim]);
+        self.dim += 1;
+
+        let index = nested_uniform_scramble(self.index, seed as u32);
+        let [x, y] = sobol_2d(index);
+
+        [
+            u32_to_float(nested_uniform_scramble(x, (seed >> 32) as u32)),
+            u32_to_float(nested_uniform_scramble(y, mix(seed) as u32)),
+        ]
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_sample(&mut self, pixel: [usize; 2], index: u32) {
+        self.pixel = pixel_seed(self.seed, pixel);
+        self.index = index;
+        self.dim = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        self.next_point()[0]
+    }
+
+    fn next_2d(&mut self) -> [f32; 2] {
+        self.next_point()
+    }
+}
+
+const BLUE_NOISE_SIZE: usize = 64;
+const BLUE_NOISE_SIGMA: f32 = 1.5;
+
+lazy_static! {
+    static ref BLUE_NOISE: Vec<u32> = void_and_cluster(BLUE_NOISE_SIZE, BLUE_NOISE_SIGMA);
+}
+
+// Ulichney's void-and-cluster method: returns a `size`x`size` mask containing the ranks
+// `0..size²` such that every threshold of the mask is a blue noise pattern.
+fn void_and_cluster(size: usize, sigma: f32) -> Vec<u32> {
+    let n = size * size;
+
+    // Gaussian energy for every toroidal offset.
+    let kernel = (0..n)
+        .map(|i| {
+            let d = |x: usize| x.min(size - x) as f32;
+            let (dx, dy) = (d(i % size), d(i / size));
+            (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect::<Vec<_>>();
+
+    let mut energy = vec![0.0; n];
+    let mut pattern = vec![false; n];
+
+    let toggle = |pattern: &mut [bool], energy: &mut [f32], p: usize| {
+        let sign = iff!(pattern[p], -1.0, 1.0);
+        pattern[p] = !pattern[p];
+
+        let (px, py) = (p % size, p / size);
+        for (q, e) in energy.iter_mut().enumerate() {
+            let dx = (q % size + size - px) % size;
+            let dy = (q / size + size - py) % size;
+            *e += sign * kernel[dx + dy * size];
+        }
+    };
+
+    // Tightest cluster is the set pixel with the highest energy, the largest void the unset
+    // pixel with the lowest energy.
+    let tightest_cluster = |pattern: &[bool], energy: &[f32]| {
+        (0..n)
+            .filter(|&i| pattern[i])
+            .max_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap())
+            .unwrap()
+    };
+    let largest_void = |pattern: &[bool], energy: &[f32]| {
+        (0..n)
+            .filter(|&i| !pattern[i])
+            .min_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap())
+            .unwrap()
+    };
+
+    // Initial pattern: random points, relaxed by moving the tightest cluster into the largest
+    // void until that no longer changes anything.
+    let mut rng = SmallRng::seed_from_u64(0);
+    let initial = n / 10;
+    let mut count = 0;
+
+    while count < initial {
+        let p = rng.gen_range(0, n);
+
+        if !pattern[p] {
+            toggle(&mut pattern, &mut energy, p);
+            count += 1;
+        }
+    }
+
+    loop {
+        let cluster = tightest_cluster(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, cluster);
+        let void = largest_void(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, void);
+
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; n];
+
+    // Rank the initial points by repeatedly removing the tightest cluster.
+    let (mut p2, mut e2) = (pattern.clone(), energy.clone());
+    for rank in (0..initial).rev() {
+        let cluster = tightest_cluster(&p2, &e2);
+        toggle(&mut p2, &mut e2, cluster);
+        ranks[cluster] = rank as u32;
+    }
+
+    // Rank the remaining pixels by repeatedly filling the largest void. Once more than half of
+    // the pixels are set this is the same as removing the tightest cluster of unset pixels.
+    for rank in initial..n {
+        let void = largest_void(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, void);
+        ranks[void] = rank as u32;
+    }
+
+    ranks
+}
+
+// Blue noise mask that is shifted by a random offset per dimension, successive samples of a
+// pixel are decorrelated by adding multiples of the golden ratio.
+pub struct BlueNoiseSampler {
+    seed: u64,
+    pixel: [usize; 2],
+    index: u32,
+    dim: u64,
+}
+
+impl BlueNoiseSampler {
+    pub fn new(seed: u64) -> Self {
+        lazy_static::initialize(&BLUE_NOISE);
+
+        Self {
+            seed,
+            pixel: [0, 0],
+            index: 0,
+            dim: 0,
+        }
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn start_sample(&mut self, pixel: [usize; 2], index: u32) {
+        self.pixel = pixel;
+        self.index = index;
+        self.dim = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let offset = hash(&[self.seed, self.dim]);
+        self.dim += 1;
+
+        let size = BLUE_NOISE_SIZE;
+        let x = (self.pixel[0] + offset as usize % size) % size;
+        let y = (self.pixel[1] + (offset >> 32) as usize % size) % size;
+        let rank = BLUE_NOISE[x + y * size];
+
+        let golden = 0.618_034_f64;
+        fract((rank as f64 + 0.5) / (size * size) as f64 + self.index as f64 * golden)
+    }
+
+    fn next_2d(&mut self) -> [f32; 2] {
+        [self.next_1d(), self.next_1d()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [SamplerKind; 5] = [
+        SamplerKind::Independent,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+        SamplerKind::BlueNoise,
+    ];
+
+    #[test]
+    fn samples_are_in_unit_interval() {
+        for &kind in &KINDS {
+            let mut sampler = kind.create(3, 16);
+
+            for pixel in [[0, 0], [1, 0], [17, 5], [300, 200]] {
+                for index in (0..64).chain([1 << 16, u32::MAX - 1]) {
+                    sampler.start_sample(pixel, index);
+
+                    // Enough dimensions to run past the table of Halton primes.
+                    for _ in 0..40 {
+                        let [x, y] = sampler.next_2d();
+
+                        for v in [sampler.next_1d(), x, y] {
+                            assert!((0.0..1.0).contains(&v), "{:?}: {}", kind, v);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn samples_depend_only_on_pixel_index_and_dimension() {
+        for &kind in &KINDS {
+            let mut a = kind.create(3, 4);
+            let mut b = kind.create(3, 4);
+
+            b.start_sample([2, 3], 1);
+            b.next_2d();
+
+            a.start_sample([2, 3], 5);
+            b.start_sample([2, 3], 5);
+
+            for _ in 0..8 {
+                assert_eq!(a.next_1d(), b.next_1d(), "{:?}", kind);
+                assert_eq!(a.next_2d(), b.next_2d(), "{:?}", kind);
+            }
+        }
+    }
+
+    // Draws a one-dimensional and a two-dimensional sample for every index in the given block.
+    fn draw_block(sampler: &mut StratifiedSampler, block: u32) -> Vec<(f32, [f32; 2])> {
+        let strata = sampler.strata;
+
+        (block * strata..(block + 1) * strata)
+            .map(|index| {
+                sampler.start_sample([4, 7], index);
+                (sampler.next_1d(), sampler.next_2d())
+            })
+            .collect()
+    }
+
+    // Whether `cells` contains every value in `0..n`.
+    fn covers(cells: impl Iterator<Item = usize>, n: usize) -> bool {
+        let mut hit = vec![false; n];
+        cells.for_each(|c| hit[c] = true);
+        hit.iter().all(|&h| h)
+    }
+
+    #[test]
+    fn stratified_covers_every_stratum_per_block() {
+        for strata in [1, 5, 8, 16] {
+            let mut sampler = StratifiedSampler::new(11, strata);
+            let n = strata as usize;
+            let cell = |v: f32, n: usize| (v * n as f32) as usize;
+
+            for block in 0..4 {
+                let samples = draw_block(&mut sampler, block);
+                assert!(covers(samples.iter().map(|s| cell(s.0, n)), n));
+
+                // A square number of strata uses a grid, otherwise both axes are stratified
+                // separately.
+                let xs = samples.iter().map(|s| s.1[0]);
+                let ys = samples.iter().map(|s| s.1[1]);
+
+                if let Some(m) = sampler.grid {
+                    let m = m as usize;
+                    let cells = xs.zip(ys).map(|(x, y)| cell(x, m) + cell(y, m) * m);
+                    assert!(covers(cells, n), "{} strata, block {}", strata, block);
+                } else {
+                    assert!(covers(xs.map(|x| cell(x, n)), n));
+                    assert!(covers(ys.map(|y| cell(y, n)), n));
+                }
+            }
+        }
+    }
+}
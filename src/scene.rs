@@ -21,6 +21,16 @@ impl Scene {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Projection {
+    Perspective,
+    Orthographic,
+    // Equidistant fisheye, the field of view (in radians) spans the width of the image.
+    Fisheye(f32),
+    // Full 360 degree panorama using the same mapping as the skybox.
+    Equirectangular,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Camera {
     width: usize,
@@ -29,6 +39,12 @@ pub struct Camera {
     dir: Vec3D,
     horizontal: Vec3D,
     vertical: Vec3D,
+    projection: Projection,
+    aperture: f32,
+    focus_distance: f32,
+    blades: u32,
+    blade_rotation: f32,
+    shutter: [f32; 2],
 }
 
 impl Camera {
@@ -40,6 +56,12 @@ impl Camera {
             dir: Vec3D::z_axis(),
             horizontal: Vec3D::x_axis(),
             vertical: Vec3D::y_axis(),
+            projection: Projection::Perspective,
+            aperture: 0.0,
+            focus_distance: 1.0,
+            blades: 0,
+            blade_rotation: 0.0,
+            shutter: [0.0, 0.0],
         };
 
         camera
@@ -74,6 +96,70 @@ impl Camera {
 
         self.horizontal *= fac / self.horizontal.norm();
         self.vertical *= fac / self.vertical.norm() * aspect;
+        self.projection = Projection::Perspective;
+        self
+    }
+
+    // Parallel projection where `extent` is the width of the view in world units.
+    pub fn orthographic(mut self, extent: f32) -> Self {
+        let fac = extent / 2.0;
+        let aspect = (self.height as f32) / (self.width as f32);
+
+        self.horizontal *= fac / self.horizontal.norm();
+        self.vertical *= fac / self.vertical.norm() * aspect;
+        self.projection = Projection::Orthographic;
+        self
+    }
+
+    pub fn fisheye(mut self, fov: f32) -> Self {
+        self.projection = Projection::Fisheye(fov.to_radians());
+        self
+    }
+
+    // The orientation of the camera is ignored, so the result can be used as a skybox.
+    pub fn equirectangular(mut self) -> Self {
+        self.projection = Projection::Equirectangular;
+        self
+    }
+
+    // Radius of the lens, zero gives a pinhole camera without depth of field.
+    pub fn aperture(mut self, radius: f32) -> Self {
+        self.aperture = radius.max(0.0);
+        self
+    }
+
+    // Distance along the view direction of the plane that is in focus.
+    pub fn focus_distance(mut self, dist: f32) -> Self {
+        self.focus_distance = dist;
+        self
+    }
+
+    pub fn focus_on(self, point: Vec3D) -> Self {
+        let dist = Vec3D::dot(point - self.pos, self.dir);
+        self.focus_distance(dist)
+    }
+
+    // Shape the aperture as a regular polygon with the given number of blades, which changes the
+    // shape of out-of-focus highlights. Less than three blades gives a round aperture.
+    pub fn blades(mut self, blades: u32, rotation: f32) -> Self {
+        self.blades = iff!(blades >= 3, blades, 0);
+        self.blade_rotation = rotation.to_radians();
+        self
+    }
+
+    // Interval during which the shutter is open, rays are spread uniformly over this interval.
+    pub fn shutter(mut self, open: f32, close: f32) -> Self {
+        self.shutter = [open, close];
+        self
+    }
+
+    pub fn resolution(mut self, width: usize, height: usize) -> Self {
+        let old_aspect = (self.height as f32) / (self.width as f32);
+        let new_aspect = (height as f32) / (width as f32);
+
+        self.vertical *= new_aspect / old_aspect;
+        self.width = width;
+        self.height = height;
         self
     }
 
@@ -81,11 +167,214 @@ impl Camera {
         (self.width, self.height)
     }
 
-    pub fn generate_ray(&self, x: f32, y: f32) -> Ray {
+    // Ray through pixel position (`x`, `y`), `lens` selects the point on the aperture and `t`
+    // the moment within the shutter interval. Depth of field is only supported by the
+    // perspective and orthographic projections.
+    pub fn generate_ray(&self, x: f32, y: f32, lens: [f32; 2], t: f32) -> Ray {
+        let [open, close] = self.shutter;
+        let time = open + t * (close - open);
+
         let u = 2.0 * (x / self.width as f32) - 1.0;
         let v = 2.0 * (y / self.height as f32) - 1.0;
-        let dir = self.dir + u * self.horizontal + v * self.vertical;
 
-        Ray::new(self.pos, dir.normalize())
+        // In both cases, `dir` has unit length along the view direction.
+        let (pos, dir) = match self.projection {
+            Projection::Perspective => {
+                (self.pos, self.dir + u * self.horizontal + v * self.vertical)
+            }
+            Projection::Orthographic => {
+                (self.pos + u * self.horizontal + v * self.vertical, self.dir)
+            }
+            Projection::Fisheye(fov) => {
+                let aspect = (self.height as f32) / (self.width as f32);
+                let dir = self.fisheye_direction(u, v * aspect, fov);
+                return Ray::with_time(self.pos, dir, time);
+            }
+            Projection::Equirectangular => {
+                let u = x / self.width as f32;
+                let v = y / self.height as f32;
+                let dir = equirectangular_direction(u, v);
+                return Ray::with_time(self.pos, dir, time);
+            }
+        };
+
+        if self.aperture <= 0.0 {
+            return Ray::with_time(pos, dir.normalize(), time);
+        }
+
+        let [lx, ly] = match self.blades {
+            0 => sample::concentric_disk(lens),
+            n => sample::regular_polygon(n, self.blade_rotation, lens),
+        };
+
+        // All rays through the same pixel meet again on the plane in focus.
+        let focus = pos + dir * self.focus_distance;
+        let offset = self.horizontal.normalize() * lx + self.vertical.normalize() * ly;
+        let origin = pos + offset * self.aperture;
+
+        Ray::with_time(origin, (focus - origin).normalize(), time)
+    }
+
+    // The angle to the view direction is proportional to the distance from the image center.
+    fn fisheye_direction(&self, a: f32, b: f32, fov: f32) -> Vec3D {
+        let r = (a * a + b * b).sqrt();
+
+        if r == 0.0 {
+            return self.dir;
+        }
+
+        let theta = (r * fov / 2.0).min(PI);
+        let side = self.horizontal.normalize() * a + self.vertical.normalize() * b;
+
+        self.dir * theta.cos() + side * (theta.sin() / r)
+    }
+}
+
+// Inverse of the mapping in `Scene::calculate_background`.
+fn equirectangular_direction(u: f32, v: f32) -> Vec3D {
+    let phi = (u - 0.5) * 2.0 * PI;
+    let theta = v * PI;
+
+    Vec3D::new(
+        theta.sin() * phi.sin(),
+        theta.sin() * phi.cos(),
+        theta.cos(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::{GeometryList, Object};
+    use crate::texture::UVTexture;
+
+    const LENS: [[f32; 2]; 5] = [[0.5, 0.5], [0.0, 0.0], [0.99, 0.1], [0.2, 0.8], [0.7, 0.99]];
+
+    // Point where the ray crosses the plane at distance `depth` along the view direction.
+    fn point_at_depth(camera: &Camera, ray: &Ray, depth: f32) -> Vec3D {
+        let t = (depth - Vec3D::dot(ray.pos - camera.pos, camera.dir)) / ray.dir.dot(camera.dir);
+        ray.pos + ray.dir * t
+    }
+
+    #[test]
+    fn thin_lens_focuses_on_plane() {
+        let pinhole = Camera::new(8, 6)
+            .position(Vec3D::new(1.0, 2.0, 3.0))
+            .look_at(Vec3D::new(0.0, 5.0, -4.0), Vec3D::y_axis());
+
+        for blades in [0, 6] {
+            let camera = Camera::new(8, 6)
+                .position(Vec3D::new(1.0, 2.0, 3.0))
+                .look_at(Vec3D::new(0.0, 5.0, -4.0), Vec3D::y_axis())
+                .aperture(0.25)
+                .focus_on(Vec3D::new(0.0, 5.0, -4.0))
+                .blades(blades, 10.0);
+            let depth = camera.focus_distance;
+
+            for &(x, y) in &[(4.0, 3.0), (0.0, 0.0), (7.5, 1.25)] {
+                let expected =
+                    point_at_depth(&pinhole, &pinhole.generate_ray(x, y, LENS[0], 0.0), depth);
+
+                for &lens in &LENS {
+                    let ray = camera.generate_ray(x, y, lens, 0.0);
+                    let offset = ray.pos - camera.pos;
+
+                    // Rays start on the lens and meet the pinhole ray on the plane in focus.
+                    assert!(offset.dot(camera.dir).abs() < 1e-5);
+                    assert!(offset.norm() <= 0.25 + 1e-5);
+                    assert!((ray.dir.norm() - 1.0).abs() < 1e-5);
+
+                    let error = (point_at_depth(&camera, &ray, depth) - expected).norm();
+                    assert!(
+                        error < 1e-4,
+                        "blades {}, lens {:?}: {}",
+                        blades,
+                        lens,
+                        error
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pinhole_ignores_lens_sample() {
+        let camera = Camera::new(8, 6).focus_distance(3.0);
+
+        for &lens in &LENS {
+            let ray = camera.generate_ray(2.0, 5.0, lens, 0.0);
+            assert_eq!(ray.pos, camera.pos);
+            assert_eq!(ray.dir, camera.generate_ray(2.0, 5.0, LENS[0], 0.0).dir);
+        }
+    }
+
+    fn angle_to_view(camera: &Camera, x: f32, y: f32) -> f32 {
+        let ray = camera.generate_ray(x, y, LENS[0], 0.0);
+        ray.dir.dot(camera.dir).min(1.0).acos().to_degrees()
+    }
+
+    #[test]
+    fn fisheye_spans_field_of_view() {
+        for fov in [90.0, 180.0, 270.0] {
+            let camera = Camera::new(8, 4)
+                .look_towards(Vec3D::new(1.0, 1.0, 0.0), Vec3D::z_axis())
+                .fisheye(fov);
+
+            // The field of view spans the width, the height is cropped by the aspect ratio.
+            assert!(angle_to_view(&camera, 4.0, 2.0) < 0.05);
+            assert!((angle_to_view(&camera, 0.0, 2.0) - fov / 2.0).abs() < 0.05);
+            assert!((angle_to_view(&camera, 8.0, 2.0) - fov / 2.0).abs() < 0.05);
+            assert!((angle_to_view(&camera, 4.0, 0.0) - fov / 4.0).abs() < 0.05);
+            assert!((angle_to_view(&camera, 6.0, 2.0) - fov / 4.0).abs() < 0.05);
+        }
+
+        // Beyond 360 degrees, the angle stops at the direction opposite to the view.
+        let camera = Camera::new(8, 8).fisheye(360.0);
+        assert!((angle_to_view(&camera, 8.0, 8.0) - 180.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn orthographic_rays_are_parallel() {
+        let camera = Camera::new(8, 4).orthographic(6.0);
+
+        let left = camera.generate_ray(0.0, 2.0, LENS[0], 0.0);
+        let right = camera.generate_ray(8.0, 2.0, LENS[0], 0.0);
+        let top = camera.generate_ray(4.0, 0.0, LENS[0], 0.0);
+
+        assert_eq!(left.dir, camera.dir);
+        assert_eq!(right.dir, camera.dir);
+        assert!(((right.pos - left.pos).norm() - 6.0).abs() < 1e-5);
+        assert!(((top.pos - camera.pos).norm() - 1.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn equirectangular_matches_background() {
+        let scene = Scene {
+            root: Arc::new(GeometryList::<Object>::new()),
+            skybox: Arc::new(UVTexture),
+            lights: vec![],
+            camera: Camera::new(16, 8)
+                .look_towards(Vec3D::new(1.0, 2.0, 3.0), Vec3D::x_axis())
+                .equirectangular(),
+        };
+
+        // Every pixel looks up its own position in the skybox, whatever the orientation of the
+        // camera. The poles and the seam are left out, the mapping is singular there.
+        for y in 1..8 {
+            for x in 1..16 {
+                let (x, y) = (x as f32 + 0.25, y as f32 - 0.25);
+                let ray = scene.camera.generate_ray(x, y, LENS[0], 0.0);
+                let color = scene.calculate_background(&ray);
+                let expected = Color::new(x / 16.0, y / 8.0, 0.0);
+
+                assert!(
+                    (color - expected).norm() < 1e-5,
+                    "({}, {}): {:?}",
+                    x,
+                    y,
+                    color
+                );
+            }
+        }
     }
 }
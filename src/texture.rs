@@ -1,4 +1,6 @@
+use crate::geom::HitResult;
 use crate::math::Vec3D;
+use std::ops::Deref;
 
 pub type Color = Vec3D;
 pub const COLOR_WHITE: Color = Color::new(1.0, 1.0, 1.0);
@@ -9,6 +11,25 @@ pub const COLOR_BLUE: Color = Color::new(0.0, 0.0, 1.0);
 
 pub trait Texture: Send + Sync + 'static {
     fn color_at(&self, u: f32, v: f32) -> Color;
+
+    fn color_at_hit(&self, hit: &HitResult) -> Color {
+        let [u, v] = hit.uv;
+        self.color_at(u, v)
+    }
+}
+
+impl<T> Texture for T
+where
+    T: Deref + Send + Sync + 'static,
+    <T as Deref>::Target: Texture,
+{
+    fn color_at(&self, u: f32, v: f32) -> Color {
+        self.deref().color_at(u, v)
+    }
+
+    fn color_at_hit(&self, hit: &HitResult) -> Color {
+        self.deref().color_at_hit(hit)
+    }
 }
 
 impl Texture for Color {
@@ -17,6 +38,19 @@ impl Texture for Color {
     }
 }
 
+// Texture multiplied by a constant color, such as a diffuse map and the color of its material.
+pub struct Tinted<T>(pub T, pub Color);
+
+impl<T: Texture> Texture for Tinted<T> {
+    fn color_at(&self, u: f32, v: f32) -> Color {
+        self.0.color_at(u, v) * self.1
+    }
+
+    fn color_at_hit(&self, hit: &HitResult) -> Color {
+        self.0.color_at_hit(hit) * self.1
+    }
+}
+
 pub struct UVTexture;
 
 impl Texture for UVTexture {
@@ -25,6 +59,19 @@ impl Texture for UVTexture {
     }
 }
 
+// Color interpolated from the vertices of the mesh that was hit, white elsewhere.
+pub struct VertexColor;
+
+impl Texture for VertexColor {
+    fn color_at(&self, _: f32, _: f32) -> Color {
+        COLOR_WHITE
+    }
+
+    fn color_at_hit(&self, hit: &HitResult) -> Color {
+        hit.color
+    }
+}
+
 pub struct Checkerboard(i32);
 
 impl Checkerboard {
@@ -0,0 +1,196 @@
+use crate::texture::Color;
+use image::RgbImage;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Operator {
+    Clamp,
+    Reinhard,
+    Aces,
+    Hable,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Transfer {
+    Linear,
+    Srgb,
+    Gamma(f32),
+}
+
+// Converts the linear color buffer into a displayable 8-bit image: exposure, tone mapping
+// operator, transfer curve and finally quantization.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToneMapping {
+    pub exposure: f32,
+    pub operator: Operator,
+    pub transfer: Transfer,
+    pub dither: bool,
+}
+
+impl ToneMapping {
+    pub fn new() -> Self {
+        Self {
+            exposure: 0.0,
+            operator: Operator::Clamp,
+            transfer: Transfer::Srgb,
+            dither: false,
+        }
+    }
+
+    pub fn map_color(&self, c: Color) -> Color {
+        let scale = self.exposure.exp2();
+
+        c.map(|x| {
+            let x = (x * scale).max(0.0);
+            let y = match self.operator {
+                Operator::Clamp => x,
+                Operator::Reinhard => x / (1.0 + x),
+                Operator::Aces => aces(x),
+                Operator::Hable => hable(2.0 * x) / hable(HABLE_WHITE),
+            };
+
+            let y = y.min(1.0);
+            match self.transfer {
+                Transfer::Linear => y,
+                Transfer::Srgb => srgb(y),
+                Transfer::Gamma(g) => y.powf(1.0 / g),
+            }
+        })
+    }
+
+    pub fn apply(&self, width: usize, height: usize, buffer: &[Color]) -> RgbImage {
+        assert_eq!(buffer.len(), width * height, "dimensions mismatch");
+        let mut img = RgbImage::new(width as u32, height as u32);
+
+        for (index, &c) in buffer.iter().enumerate() {
+            let (x, y) = (index % width, index / width);
+            let c = self.map_color(c);
+            let mut p = [0; 3];
+
+            for i in 0..3 {
+                let noise = iff!(self.dither, dither_noise(x, y, i), 0.0);
+                p[i] = (c[i] * 255.0 + noise).round().clamp(0.0, 255.0) as u8;
+            }
+
+            img.put_pixel(x as u32, y as u32, image::Rgb(p));
+        }
+
+        img
+    }
+}
+
+// Narkowicz's fit of the ACES reference rendering transform.
+fn aces(x: f32) -> f32 {
+    let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+    (x * (a * x + b)) / (x * (c * x + d) + e)
+}
+
+// Hable's filmic curve from Uncharted 2, normalized so that `HABLE_WHITE` maps to white.
+const HABLE_WHITE: f32 = 11.2;
+
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+fn srgb(x: f32) -> f32 {
+    if x <= 0.003_130_8 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Triangular noise in (-1, 1) from a hash of the pixel, so dithering is deterministic.
+fn dither_noise(x: usize, y: usize, channel: usize) -> f32 {
+    let hash = |mut h: u32| {
+        h ^= h >> 16;
+        h = h.wrapping_mul(0x7feb_352d);
+        h ^= h >> 15;
+        h = h.wrapping_mul(0x846c_a68b);
+        h ^= h >> 16;
+        h
+    };
+
+    let seed = (x as u32).wrapping_mul(0x9e37_79b9) ^ (y as u32).wrapping_mul(0x85eb_ca6b);
+    let a = hash(seed ^ (channel as u32));
+    let b = hash(a);
+
+    let to_unit = |h: u32| (h >> 8) as f32 / (1 << 24) as f32;
+    to_unit(a) - to_unit(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(operator: Operator, transfer: Transfer, x: f32) -> f32 {
+        let tm = ToneMapping {
+            operator,
+            transfer,
+            ..ToneMapping::new()
+        };
+
+        tm.map_color(Color::fill(x))[0]
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn srgb_transfer() {
+        assert_eq!(srgb(0.0), 0.0);
+        assert_close(srgb(1.0), 1.0);
+
+        // Both pieces of the curve meet at the threshold.
+        assert_close(srgb(0.003_130_8), 0.040_45);
+        assert_close(srgb(0.003_130_9), 0.040_45);
+        assert_close(srgb(0.5), 0.735_36);
+    }
+
+    #[test]
+    fn operators() {
+        use Operator::*;
+        let linear = Transfer::Linear;
+
+        for &op in &[Clamp, Reinhard, Aces, Hable] {
+            assert_close(map(op, linear, 0.0), 0.0);
+            assert!(map(op, linear, 1e6) <= 1.0);
+            assert!(map(op, linear, 0.3) < map(op, linear, 0.6));
+        }
+
+        assert_close(map(Clamp, linear, 0.25), 0.25);
+        assert_close(map(Clamp, linear, 4.0), 1.0);
+        assert_close(map(Reinhard, linear, 1.0), 0.5);
+        assert_close(map(Aces, linear, 1.0), 2.54 / 3.16);
+        assert_close(map(Hable, linear, HABLE_WHITE / 2.0), 1.0);
+
+        assert_close(map(Clamp, Transfer::Gamma(2.0), 0.25), 0.5);
+        assert_close(map(Clamp, Transfer::Srgb, 1.0), 1.0);
+    }
+
+    #[test]
+    fn exposure_and_quantization() {
+        let mut tm = ToneMapping {
+            exposure: 1.0,
+            transfer: Transfer::Linear,
+            ..ToneMapping::new()
+        };
+        assert_close(tm.map_color(Color::fill(0.25))[0], 0.5);
+
+        let buffer = (0..64)
+            .map(|i| Color::fill(i as f32 / 63.0))
+            .collect::<Vec<_>>();
+        tm.exposure = 0.0;
+        let plain = tm.apply(8, 8, &buffer);
+        assert_eq!(plain.get_pixel(0, 0)[0], 0);
+        assert_eq!(plain.get_pixel(7, 7)[0], 255);
+
+        // Dithering moves a value by less than one step.
+        tm.dither = true;
+        let dithered = tm.apply(8, 8, &buffer);
+        for (a, b) in plain.pixels().zip(dithered.pixels()) {
+            assert!((i32::from(a[0]) - i32::from(b[0])).abs() <= 1);
+        }
+    }
+}